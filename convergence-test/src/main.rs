@@ -3,10 +3,14 @@
 // practically understand the convergence properties.
 //
 
+use std::sync::Arc;
+
 use clap::{Parser, Subcommand, ValueEnum};
 
 use tray_racer_lib::vec4::*;
-use tray_racer_lib::{CanvasConfig, EnvMap, Tracer};
+use tray_racer_lib::{
+    Antialias, CanvasConfig, EnvMap, Filter, Handedness, Integrator, ToneMap, Tracer,
+};
 
 const RESOLUTION: usize = 64;
 const MIN_SIZE: f64 = 0.001;
@@ -33,6 +37,14 @@ enum Value {
     PathLen,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum IntegratorArg {
+    /// Step along the previous direction and project onto the surface.
+    Projection,
+    /// 4th-order Runge-Kutta on the geodesic equation.
+    Rk4,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Proxy {
     /// Curvature per unit length.
@@ -49,6 +61,9 @@ enum Proxy {
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// How the tracer advances rays.
+    #[arg(long, value_enum, default_value_t = IntegratorArg::Projection)]
+    integrator: IntegratorArg,
     #[command(subcommand)]
     command: Command,
 }
@@ -76,20 +91,27 @@ enum Command {
 
 fn main() {
     let args = Args::parse();
+    let tracer = default_tracer(args.integrator);
 
     match args.command {
-        Command::Path { output_mode, value } => path_stats(output_mode, value),
-        Command::Step { step_size, proxies } => step_stats(step_size, &proxies),
+        Command::Path { output_mode, value } => path_stats(&tracer, output_mode, value),
+        Command::Step { step_size, proxies } => step_stats(&tracer, step_size, &proxies),
     }
 }
 
-fn default_tracer() -> Tracer {
+fn default_tracer(integrator: IntegratorArg) -> Tracer {
     Tracer {
-        env_map_pos: EnvMap::new(),
-        env_map_neg: EnvMap::new(),
-        w_scale: 0.25,
-        radius: 0.25,
-        infinity: 4.0,
+        integrator: match integrator {
+            IntegratorArg::Projection => Integrator::Projection,
+            IntegratorArg::Rk4 => Integrator::Rk4,
+        },
+        ..Tracer::new(
+            Arc::new(EnvMap::new()),
+            Arc::new(EnvMap::new()),
+            0.25,
+            0.25,
+            4.0,
+        )
     }
 }
 
@@ -99,11 +121,17 @@ fn default_canvas_conf() -> CanvasConfig {
         height: RESOLUTION,
         aspect: 1.0,
         fov_degrees: 90.0,
+        fov_v_degrees: None,
+        antialias: Antialias::None,
+        filter: Filter::Box(1.0),
+        handedness: Handedness::Left,
+        pixel_offset: (0.0, 0.0),
+        tone_map: ToneMap::None,
+        gamma: 1.0,
     }
 }
 
-fn path_stats(output_mode: ResultFormat, value: Value) {
-    let tracer = default_tracer();
+fn path_stats(tracer: &Tracer, output_mode: ResultFormat, value: Value) {
     let conf = default_canvas_conf();
     // Find the result direction vectors for various step sizes (grouped by path).
     let mut results = (0..RESOLUTION.pow(2))
@@ -151,7 +179,7 @@ fn path_stats(output_mode: ResultFormat, value: Value) {
             // Find difference against subsequent entries.
             path_results[1..]
                 .iter()
-                .map(|x| (x.norm().sub(base)).len())
+                .map(|x| x.norm().distance(&base))
                 .collect::<Vec<_>>()
         }
 
@@ -190,10 +218,9 @@ fn display(results: &[Vec<f64>]) {
     }
 }
 
-fn step_stats(step_size: f64, proxies: &[Proxy]) {
-    assert!(0.001 <= step_size && step_size <= 0.1);
+fn step_stats(tracer: &Tracer, step_size: f64, proxies: &[Proxy]) {
+    assert!((0.001..=0.1).contains(&step_size));
 
-    let tracer = default_tracer();
     let conf = default_canvas_conf();
     let results = tracer.render_step_stats(&conf, step_size);
 
@@ -3,13 +3,24 @@
 // my curved-spaces code and removing as much as I easily could.
 //
 
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
 
 use anyhow::*;
 use clap::Parser;
 use glow::{Context, *};
 
-use tray_racer_lib::{CanvasConfig, EnvMap, Tracer};
+use tray_racer_lib::postprocess::unsharp_mask;
+use tray_racer_lib::vec4::*;
+use tray_racer_lib::{
+    camera_frame, camera_ray, fit_aspect, letterbox, Antialias, AxisPosition, CanvasConfig,
+    ColourGrade, EnvMap, EnvMapAlphaPolicy, Face, Filter, GeometryResult, Handedness, RenderBuffer,
+    RenderControl, ToneMap, Tracer,
+};
 
 ////////////////////////////////////////////////////////////////////////
 // Command-line args
@@ -28,6 +39,10 @@ struct Args {
     /// Directory containing negative-w env maps
     #[arg(long, default_value_t = DEFAULT_ENV_MAP_NEG.to_string())]
     env_map_neg: String,
+    /// Upload renders to alternate textures, so that an upload doesn't
+    /// wait for the GPU to finish drawing the previous one
+    #[arg(long)]
+    double_buffer: bool,
 }
 
 ////////////////////////////////////////////////////////////////////////
@@ -35,6 +50,23 @@ struct Args {
 // single `draw_elements` call.
 //
 
+/// Types that can be viewed as raw bytes.
+///
+/// # Safety
+///
+/// Implementors must have no padding and no invalid bit patterns.
+unsafe trait Plain: Copy {}
+
+unsafe impl Plain for f32 {}
+unsafe impl Plain for u32 {}
+
+// View a slice of plain values as bytes, for uploading to GL.
+fn as_bytes<T: Plain>(data: &[T]) -> &[u8] {
+    // Safe as `Plain` types have no padding, the length covers
+    // exactly the source slice, and the lifetime is tied to it.
+    unsafe { core::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) }
+}
+
 pub struct Shape {
     vao: VertexArray,
     vbo: Buffer,
@@ -74,19 +106,15 @@ impl Shape {
 
     fn rebuild(&mut self, gl: &Context, vertices: &[f32], indices: &[u32]) {
         unsafe {
-            let vertices_u8: &[u8] = core::slice::from_raw_parts(
-                vertices.as_ptr() as *const u8,
-                std::mem::size_of_val(vertices),
-            );
             gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.vbo));
-            gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, vertices_u8, glow::STATIC_DRAW);
+            gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, as_bytes(vertices), glow::STATIC_DRAW);
 
-            let indices_u8: &[u8] = core::slice::from_raw_parts(
-                indices.as_ptr() as *const u8,
-                std::mem::size_of_val(indices),
-            );
             gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(self.ibo));
-            gl.buffer_data_u8_slice(glow::ELEMENT_ARRAY_BUFFER, indices_u8, glow::STATIC_DRAW);
+            gl.buffer_data_u8_slice(
+                glow::ELEMENT_ARRAY_BUFFER,
+                as_bytes(indices),
+                glow::STATIC_DRAW,
+            );
 
             self.num_elts = indices.len() as i32;
         }
@@ -112,6 +140,103 @@ impl Shape {
     }
 }
 
+////////////////////////////////////////////////////////////////////////
+// Shader programs: A linked program, with its uniforms set by name
+// from typed values.
+//
+
+// Values that can be set as a uniform of the GLSL type with the same
+// shape. Doubles are narrowed to floats.
+trait UniformValue {
+    unsafe fn set(&self, gl: &Context, location: &UniformLocation);
+}
+
+impl UniformValue for f32 {
+    unsafe fn set(&self, gl: &Context, location: &UniformLocation) {
+        gl.uniform_1_f32(Some(location), *self);
+    }
+}
+
+impl UniformValue for f64 {
+    unsafe fn set(&self, gl: &Context, location: &UniformLocation) {
+        gl.uniform_1_f32(Some(location), *self as f32);
+    }
+}
+
+// Also used for samplers, set to their texture unit.
+impl UniformValue for i32 {
+    unsafe fn set(&self, gl: &Context, location: &UniformLocation) {
+        gl.uniform_1_i32(Some(location), *self);
+    }
+}
+
+impl UniformValue for [f32; 2] {
+    unsafe fn set(&self, gl: &Context, location: &UniformLocation) {
+        gl.uniform_2_f32_slice(Some(location), self);
+    }
+}
+
+impl UniformValue for [f32; 3] {
+    unsafe fn set(&self, gl: &Context, location: &UniformLocation) {
+        gl.uniform_3_f32_slice(Some(location), self);
+    }
+}
+
+impl UniformValue for [f32; 4] {
+    unsafe fn set(&self, gl: &Context, location: &UniformLocation) {
+        gl.uniform_4_f32_slice(Some(location), self);
+    }
+}
+
+impl UniformValue for Vec4 {
+    unsafe fn set(&self, gl: &Context, location: &UniformLocation) {
+        [self.x, self.y, self.z, self.w]
+            .map(|c| c as f32)
+            .set(gl, location);
+    }
+}
+
+struct ShaderProgram {
+    program: Program,
+    // Names of uniforms we've failed to find, so that we only warn
+    // about each once.
+    missing: HashSet<String>,
+}
+
+impl ShaderProgram {
+    fn new(program: Program) -> ShaderProgram {
+        ShaderProgram {
+            program,
+            missing: HashSet::new(),
+        }
+    }
+
+    unsafe fn use_program(&self, gl: &Context) {
+        gl.use_program(Some(self.program));
+    }
+
+    // Set a uniform of the program, which must be in use. Setting a
+    // uniform the program doesn't have, including one the shader
+    // compiler removed as unused, does nothing, with a warning the
+    // first time.
+    unsafe fn set(&mut self, gl: &Context, name: &str, value: impl UniformValue) {
+        match gl.get_uniform_location(self.program, name) {
+            Some(location) => value.set(gl, &location),
+            None => {
+                if self.missing.insert(name.to_string()) {
+                    log::warn!("No active uniform '{}' in program, not setting it", name);
+                }
+            }
+        }
+    }
+
+    fn close(&self, gl: &Context) {
+        unsafe {
+            gl.delete_program(self.program);
+        }
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////
 // winit: Shared between wasm32 and glutin_winit.
 //
@@ -142,6 +267,9 @@ impl Platform {
 
         let mut egui_glow =
             egui_glow::winit::EguiGlow::new(&event_loop, self.gl.clone(), None, None);
+        drawable.focus.textures = drawable
+            .textures
+            .map(|tex| Some(egui_glow.painter.register_native_texture(tex)));
 
         let event_loop_proxy = egui::mutex::Mutex::new(event_loop.create_proxy());
         egui_glow
@@ -156,6 +284,8 @@ impl Platform {
         let mut repaint_delay = std::time::Duration::MAX;
         // Used to detect dragging on the image to pan.
         let mut left_button_down = false;
+        // Shift-clicks inspect a ray, rather than dragging the view.
+        let mut shift_down = false;
 
         let event_fn =
             move |event,
@@ -220,6 +350,10 @@ impl Platform {
                             self.resize(physical_size);
                         }
 
+                        if let WindowEvent::ModifiersChanged(modifiers) = &event {
+                            shift_down = modifiers.state().shift_key();
+                        }
+
                         // We're interacting if the mouse button is
                         // down, whether it's in an egui element or
                         // not.
@@ -229,7 +363,7 @@ impl Platform {
                             ..
                         } = &event
                         {
-                            drawable.fast_draw = *state == ElementState::Pressed;
+                            drawable.fast_draw = *state == ElementState::Pressed && !shift_down;
                             if !drawable.fast_draw {
                                 needs_retex = true;
                             }
@@ -248,14 +382,18 @@ impl Platform {
                                 // allow egui to consume it first.
                                 WindowEvent::MouseInput { state, button, .. } => {
                                     if button == MouseButton::Left {
-                                        left_button_down = state == ElementState::Pressed;
+                                        left_button_down =
+                                            state == ElementState::Pressed && !shift_down;
                                     }
                                 }
                                 // We will make use of keyboard
                                 // auto-repeat for movement, rather
                                 // than doing our own key-held
                                 // logic. As we're using WASD keys,
-                                // we'll use the PhysicalKey.
+                                // we'll use the PhysicalKey. Arrow
+                                // keys only get here if no egui
+                                // widget (such as a slider) has
+                                // focus.
                                 WindowEvent::KeyboardInput { event, .. } => {
                                     use winit::keyboard::*;
                                     if let KeyEvent {
@@ -265,6 +403,22 @@ impl Platform {
                                     } = event
                                     {
                                         match k {
+                                            KeyCode::ArrowLeft => {
+                                                drawable.rotate_view(-KEY_TURN_STEP, 0.0);
+                                                needs_retex = true;
+                                            }
+                                            KeyCode::ArrowRight => {
+                                                drawable.rotate_view(KEY_TURN_STEP, 0.0);
+                                                needs_retex = true;
+                                            }
+                                            KeyCode::ArrowUp => {
+                                                drawable.rotate_view(0.0, KEY_TURN_STEP);
+                                                needs_retex = true;
+                                            }
+                                            KeyCode::ArrowDown => {
+                                                drawable.rotate_view(0.0, -KEY_TURN_STEP);
+                                                needs_retex = true;
+                                            }
                                             KeyCode::KeyW => {}
                                             KeyCode::KeyS => {}
                                             KeyCode::KeyA => {}
@@ -290,16 +444,7 @@ impl Platform {
                                 let x = delta.0 * 360.0 / size.width as f64;
                                 let y = delta.1 * 180.0 / size.height as f64;
 
-                                let turn = &mut drawable.turn;
-                                let tilt = &mut drawable.tilt;
-                                *turn -= x;
-                                if *turn > 180.0 {
-                                    *turn -= 360.0;
-                                } else if *turn < -180.0 {
-                                    *turn += 360.0;
-                                }
-                                *tilt = (*tilt + y).min(90.0).max(-90.0);
-
+                                drawable.rotate_view(-x, y);
                                 drawable.rebuild_tex(&self.gl)
                             }
                         }
@@ -330,11 +475,85 @@ impl Platform {
     }
 }
 
+// A GL version we can ask for a context for, along with the shader
+// version line that goes with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct GlVersion {
+    gles: bool,
+    major: u8,
+    minor: u8,
+    shader_version: &'static str,
+}
+
+// Versions to try, in order of preference.
+const GL_VERSIONS: &[GlVersion] = &[
+    GlVersion {
+        gles: false,
+        major: 4,
+        minor: 1,
+        shader_version: "#version 410",
+    },
+    GlVersion {
+        gles: false,
+        major: 3,
+        minor: 3,
+        shader_version: "#version 330",
+    },
+    GlVersion {
+        gles: true,
+        major: 3,
+        minor: 0,
+        shader_version: "#version 300 es",
+    },
+];
+
+impl GlVersion {
+    fn context_api(&self) -> glutin::context::ContextApi {
+        use glutin::context::{ContextApi, Version};
+        let version = Some(Version::new(self.major, self.minor));
+        if self.gles {
+            ContextApi::Gles(version)
+        } else {
+            ContextApi::OpenGl(version)
+        }
+    }
+}
+
+impl std::fmt::Display for GlVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let api = if self.gles { "OpenGL ES" } else { "OpenGL" };
+        write!(f, "{} {}.{}", api, self.major, self.minor)
+    }
+}
+
+// Try creating something for each version in turn, returning the
+// first success along with the version used. Separated out from the
+// GL calls so that the fallback logic is independent of them.
+fn first_supported<T, E: std::fmt::Display>(
+    versions: &[GlVersion],
+    mut create: impl FnMut(&GlVersion) -> Result<T, E>,
+) -> Result<(T, GlVersion)> {
+    let mut failures = Vec::new();
+    for version in versions.iter() {
+        match create(version) {
+            Result::Ok(t) => return Ok((t, *version)),
+            Err(e) => {
+                log::warn!("Couldn't create {} context: {}", version, e);
+                failures.push(format!("  {}: {}", version, e));
+            }
+        }
+    }
+    Err(anyhow!(
+        "Couldn't create a usable GL context. Tried:\n{}",
+        failures.join("\n")
+    ))
+}
+
 impl Platform {
     fn new(width: u32, height: u32, name: &str) -> Result<Platform> {
         use glutin::{
             config::{ConfigTemplateBuilder, GlConfig},
-            context::{ContextApi, ContextAttributesBuilder, NotCurrentGlContext},
+            context::{ContextAttributesBuilder, NotCurrentGlContext},
             display::{GetGlDisplay, GlDisplay},
             surface::{GlSurface, SwapInterval},
         };
@@ -369,21 +588,19 @@ impl Platform {
         let window = window.ok_or_else(|| anyhow!("Couldn't get window"))?;
 
         let gl_display = gl_config.display();
-        let context_attributes = ContextAttributesBuilder::new()
-            .with_context_api(ContextApi::OpenGl(Some(glutin::context::Version {
-                major: 4,
-                minor: 10,
-            })))
-            .build(raw_window_handle);
-
-        let (gl, gl_surface, gl_context) = unsafe {
-            let not_current_gl_context =
-                gl_display.create_context(&gl_config, &context_attributes)?;
+
+        let (gl, gl_surface, gl_context, gl_version) = unsafe {
+            let (not_current_gl_context, gl_version) = first_supported(GL_VERSIONS, |version| {
+                let context_attributes = ContextAttributesBuilder::new()
+                    .with_context_api(version.context_api())
+                    .build(raw_window_handle);
+                gl_display.create_context(&gl_config, &context_attributes)
+            })?;
             let attrs = window.build_surface_attributes(Default::default());
             let gl_surface = gl_display.create_window_surface(&gl_config, &attrs)?;
             let gl_context = not_current_gl_context.make_current(&gl_surface)?;
             let gl = glow::Context::from_loader_function_cstr(|s| gl_display.get_proc_address(s));
-            (gl, gl_surface, gl_context)
+            (gl, gl_surface, gl_context, gl_version)
         };
 
         gl_surface
@@ -391,7 +608,7 @@ impl Platform {
 
         Ok(Platform {
             gl: std::sync::Arc::new(gl),
-            shader_version: "#version 410",
+            shader_version: gl_version.shader_version,
             window,
             event_loop: Some(event_loop),
 
@@ -449,7 +666,8 @@ fn main() -> Result<()> {
         p.shader_version,
         Path::new(&args.env_map_pos),
         Path::new(&args.env_map_neg),
-    );
+        args.double_buffer,
+    )?;
 
     unsafe {
         p.gl.clear_color(0.1, 0.2, 0.3, 1.0);
@@ -463,134 +681,1290 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+// The user-adjustable parameters of a Tracer, separated from the
+// environment maps so that we can keep multiple sets around.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TracerParams {
+    w_scale: f64,
+    radius: f64,
+    infinity: f64,
+}
+
+impl TracerParams {
+    fn get(tracer: &Tracer) -> TracerParams {
+        TracerParams {
+            w_scale: tracer.w_scale,
+            radius: tracer.radius,
+            infinity: tracer.infinity,
+        }
+    }
+
+    fn apply(&self, tracer: &mut Tracer) {
+        tracer.w_scale = self.w_scale;
+        tracer.radius = self.radius;
+        tracer.infinity = self.infinity;
+    }
+}
+
+// Named parameter sets, as a starting point for exploration.
+const PRESETS: &[(&str, TracerParams)] = &[
+    (
+        "Subtle lens",
+        TracerParams {
+            w_scale: 0.5,
+            radius: 0.05,
+            infinity: 4.0,
+        },
+    ),
+    (
+        "Classic wormhole",
+        TracerParams {
+            w_scale: 0.25,
+            radius: 0.1,
+            infinity: 4.0,
+        },
+    ),
+    (
+        "Extreme throat",
+        TracerParams {
+            w_scale: 0.1,
+            radius: 0.5,
+            infinity: 6.0,
+        },
+    ),
+    (
+        "Negative radius",
+        TracerParams {
+            w_scale: 0.25,
+            radius: -0.3,
+            infinity: 4.0,
+        },
+    ),
+];
+
+// State for comparing two parameter sets, with state A shown left of
+// a vertical wipe line, and state B to the right.
+struct Wipe {
+    enabled: bool,
+    // Position of the wipe, as a fraction of the width.
+    position: f64,
+    // Which state the sliders are editing. The tracer holds the
+    // parameters being edited, and `other` the remaining state.
+    editing_b: bool,
+    other: TracerParams,
+}
+
+// Everything that determines a traced render, before it's composited,
+// sharpened and letterboxed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RenderKey {
+    params: TracerParams,
+    grade: ColourGrade,
+    tilt: f64,
+    turn: f64,
+    pan: f64,
+    conf: CanvasConfig,
+    pos_map_only: bool,
+}
+
+// Renders from the last job, so that the next only traces what has
+// changed. With the A/B wipe, dragging one state's sliders leaves the
+// other state's render to be reused.
+#[derive(Default)]
+struct RenderCache {
+    entries: Vec<(RenderKey, RenderBuffer)>,
+}
+
+// Combine two renders of the same size, taking pixels left of the
+// wipe position from `a`, and the rest from `b`.
+fn composite_wipe(a: &RenderBuffer, b: &RenderBuffer, position: f64) -> RenderBuffer {
+    let split = ((position * a.width as f64).round() as usize).min(a.width);
+    let mut out = RenderBuffer::new(a.width, a.height);
+    for y in 0..a.height {
+        for x in 0..a.width {
+            let source = if x < split { a } else { b };
+            out.set(x, y, source.get(x, y));
+        }
+    }
+    out
+}
+
+// Draw a crosshair over the centre of the screen, which is the centre
+// of the render.
+fn draw_crosshair(ctx: &egui::Context) {
+    const SIZE: f32 = 10.0;
+    let painter = ctx.layer_painter(egui::LayerId::new(
+        egui::Order::Background,
+        egui::Id::new("crosshair"),
+    ));
+    let centre = ctx.screen_rect().center();
+    let stroke = egui::Stroke::new(1.5, egui::Color32::WHITE);
+    painter.hline(centre.x - SIZE..=centre.x + SIZE, centre.y, stroke);
+    painter.vline(centre.x, centre.y - SIZE..=centre.y + SIZE, stroke);
+}
+
+// Draw a projected path over the view, breaking it where points are
+// behind the camera.
+fn draw_path(ctx: &egui::Context, points: &[Option<(f64, f64)>]) {
+    let painter = ctx.layer_painter(egui::LayerId::new(
+        egui::Order::Background,
+        egui::Id::new("inspected_path"),
+    ));
+    let screen = ctx.screen_rect();
+    let to_screen =
+        |(x, y): (f64, f64)| screen.min + egui::vec2(x as f32, y as f32) * screen.size();
+    let stroke = egui::Stroke::new(1.5, egui::Color32::YELLOW);
+    for pair in points.windows(2) {
+        if let [Some(a), Some(b)] = pair {
+            painter.line_segment([to_screen(*a), to_screen(*b)], stroke);
+        }
+    }
+}
+
+// Draw markers where the world axes appear, as dots for the positive
+// directions and rings for the negative, coloured as by the CLI.
+fn draw_axes(ctx: &egui::Context, markers: &[AxisPosition]) {
+    const RADIUS: f32 = 4.0;
+    const RING_WIDTH: f32 = 1.5;
+    let painter = ctx.layer_painter(egui::LayerId::new(
+        egui::Order::Background,
+        egui::Id::new("axes"),
+    ));
+    let screen = ctx.screen_rect();
+    let colours = [
+        egui::Color32::RED,
+        egui::Color32::GREEN,
+        egui::Color32::BLUE,
+        egui::Color32::YELLOW,
+    ];
+    for marker in markers {
+        let (x, y) = marker.pos;
+        let centre = screen.min + egui::vec2(x as f32, y as f32) * screen.size();
+        let colour = colours[marker.axis];
+        if marker.positive {
+            painter.circle_filled(centre, RADIUS, colour);
+        } else {
+            let stroke = egui::Stroke::new(RING_WIDTH, colour);
+            painter.circle_stroke(centre, RADIUS - 0.5 * RING_WIDTH, stroke);
+        }
+    }
+}
+
+// Add a slider. Once focused, egui steps it with the arrow keys, and
+// we also step it by `step` with +/-. Focus makes egui consume the key
+// events, so they don't move the camera. Returns true if the value
+// changed.
+fn slider<T: egui::emath::Numeric>(
+    ui: &mut egui::Ui,
+    value: &mut T,
+    range: RangeInclusive<T>,
+    text: &str,
+    step: f64,
+) -> bool {
+    let response = ui.add(egui::Slider::new(value, range.clone()).text(text));
+    let mut changed = response.changed();
+    if response.has_focus() {
+        let presses = ui.input(|i| {
+            (i.num_presses(egui::Key::Plus) + i.num_presses(egui::Key::Equals)) as f64
+                - i.num_presses(egui::Key::Minus) as f64
+        });
+        if presses != 0.0 {
+            let (lo, hi) = (range.start().to_f64(), range.end().to_f64());
+            let new_value = (value.to_f64() + presses * step).clamp(lo, hi);
+            changed |= new_value != value.to_f64();
+            *value = T::from_f64(new_value);
+        }
+    }
+    changed
+}
+
+////////////////////////////////////////////////////////////////////////
+// Background rendering: Full-resolution renders run on a worker
+// thread, so that the UI stays responsive and can show progress. If
+// the user starts interacting again, the render is cancelled.
+//
+
+// A snapshot of everything that determines the texture, so that it
+// can be rendered away from the Drawable.
+struct RenderJob {
+    params: TracerParams,
+    grade: ColourGrade,
+    tilt: f64,
+    turn: f64,
+    pan: f64,
+    conf: CanvasConfig,
+    // If wiping, the other state's parameters, whether the edited
+    // state is B, and the wipe position.
+    wipe: Option<(TracerParams, bool, f64)>,
+    // The texture size, which is larger than the render if
+    // letterboxed, and the colour of the bars.
+    out_size: (usize, usize),
+    bar_colour: [u8; 4],
+    // Unsharp mask amount and radius, if sharpening.
+    sharpen: Option<(f64, f64)>,
+    // Sample the pos env map on both sides of the throat.
+    pos_map_only: bool,
+}
+
+impl RenderJob {
+    // The canvas pixel coordinates under a window position, given as
+    // fractions of the window's size from its top left, or None if
+    // it's over the letterbox bars. The texture's rows run bottom to
+    // top.
+    fn window_to_pixel(&self, (fx, fy): (f64, f64)) -> Option<(f64, f64)> {
+        let (out_w, out_h) = self.out_size;
+        let (w, h) = (self.conf.width, self.conf.height);
+        // Pixel centres are at integer coordinates.
+        let px = fx * out_w as f64 - ((out_w - w) / 2) as f64 - 0.5;
+        let py = (1.0 - fy) * out_h as f64 - ((out_h - h) / 2) as f64 - 0.5;
+        let inside = |p: f64, size: usize| (-0.5..size as f64 - 0.5).contains(&p);
+        (inside(px, w) && inside(py, h)).then_some((px, py))
+    }
+
+    // The inverse of `window_to_pixel`, for any pixel coordinates.
+    fn pixel_to_window(&self, (px, py): (f64, f64)) -> (f64, f64) {
+        let (out_w, out_h) = self.out_size;
+        let (w, h) = (self.conf.width, self.conf.height);
+        let fx = (px + 0.5 + ((out_w - w) / 2) as f64) / out_w as f64;
+        let fy = 1.0 - (py + 0.5 + ((out_h - h) / 2) as f64) / out_h as f64;
+        (fx, fy)
+    }
+
+    // Where the points appear in the window, as fractions of its size,
+    // projecting straight onto the view as if space were flat. None
+    // for points too close to or behind the camera.
+    fn project_points(&self, points: &[Point4]) -> Vec<Option<(f64, f64)>> {
+        const MIN_DEPTH: f64 = 1.0e-3;
+        let conf = &self.conf;
+        let frame = camera_frame(conf, self.tilt, self.turn, self.pan);
+        let (w, h) = (conf.width as f64, conf.height as f64);
+        // Half the view's width and height at unit distance, as for
+        // GPU tracing.
+        let (scale_x, scale_y) = conf.view_scale();
+        points
+            .iter()
+            .map(|p| {
+                let rel = p.sub(frame.origin);
+                let [x, y, z] = frame.axes.map(|axis| rel.dot(axis));
+                (z >= MIN_DEPTH).then(|| {
+                    let px = 0.5 * (1.0 - x / (z * scale_x)) * w - 0.5;
+                    let py = 0.5 * (1.0 - y / (z * scale_y)) * h - 0.5;
+                    self.pixel_to_window((px, py))
+                })
+            })
+            .collect()
+    }
+
+    // Number of rows rendered in total, for progress reporting.
+    fn total_rows(&self) -> usize {
+        let passes = if self.wipe.is_some() { 2 } else { 1 };
+        passes * self.conf.height
+    }
+
+    fn render_key(&self, params: TracerParams) -> RenderKey {
+        RenderKey {
+            params,
+            grade: self.grade,
+            tilt: self.tilt,
+            turn: self.turn,
+            pan: self.pan,
+            conf: self.conf,
+            pos_map_only: self.pos_map_only,
+        }
+    }
+
+    // Render the texture data with the given tracer, whose parameters
+    // and grade are set from the job, reusing renders in the cache
+    // where possible. `progress` is called with the rows done so far.
+    // Returns None if cancelled.
+    fn run(
+        &self,
+        tracer: &mut Tracer,
+        cache: &mut RenderCache,
+        progress: &(dyn Fn(usize) + Sync),
+        cancel: &AtomicBool,
+    ) -> Option<Vec<u8>> {
+        // Only keep the renders this job could use.
+        let keys = [
+            Some(self.render_key(self.params)),
+            self.wipe
+                .map(|(other_params, ..)| self.render_key(other_params)),
+        ];
+        cache.entries.retain(|(key, _)| keys.contains(&Some(*key)));
+
+        // Only touch the env maps if the grade has changed, as they're
+        // copied if still shared with the UI's tracer.
+        for env_map in [&mut tracer.env_map_pos, &mut tracer.env_map_neg] {
+            if env_map.grade() != self.grade {
+                Arc::make_mut(env_map).set_grade(self.grade);
+            }
+        }
+        tracer.pos_map_only = self.pos_map_only;
+        let mut render = |tracer: &mut Tracer, params: TracerParams, pass: usize| {
+            let offset = pass * self.conf.height;
+            let key = self.render_key(params);
+            if let Some((_, buffer)) = cache.entries.iter().find(|(k, _)| *k == key) {
+                progress(offset + self.conf.height);
+                return Some(buffer.clone());
+            }
+            let control = RenderControl {
+                progress: &|done, _| progress(offset + done),
+                cancel,
+            };
+            params.apply(tracer);
+            let buffer = tracer.render_buffer_controlled(
+                &self.conf, self.tilt, self.turn, self.pan, None, &control,
+            )?;
+            cache.entries.push((key, buffer.clone()));
+            Some(buffer)
+        };
+
+        let current = render(tracer, self.params, 0)?;
+        let buffer = match self.wipe {
+            Some((other_params, editing_b, position)) => {
+                // Render the other state, swapping its parameters
+                // into the tracer.
+                let other = render(tracer, other_params, 1);
+                self.params.apply(tracer);
+                let other = other?;
+
+                let (a, b) = if editing_b {
+                    (other, current)
+                } else {
+                    (current, other)
+                };
+                composite_wipe(&a, &b, position)
+            }
+            None => current,
+        };
+        // As in the CLI, sharpening works on the samples, before tone
+        // mapping.
+        let buffer = match self.sharpen {
+            Some((amount, radius)) => unsharp_mask(&buffer, amount, radius),
+            None => buffer,
+        };
+        let pixels = buffer.finalize(self.conf.tone_map, self.conf.gamma);
+
+        let (out_w, out_h) = self.out_size;
+        Some(letterbox(
+            &pixels,
+            self.conf.width,
+            self.conf.height,
+            out_w,
+            out_h,
+            self.bar_colour,
+        ))
+    }
+}
+
+// The state of the full-resolution render.
+enum RenderState {
+    // Showing a fast render, or nothing yet.
+    Idle,
+    // A render is in flight on the worker.
+    Rendering {
+        id: u64,
+        out_size: (usize, usize),
+        total_rows: usize,
+        progress: Arc<AtomicUsize>,
+        cancel: Arc<AtomicBool>,
+    },
+    // Showing a completed full-resolution render.
+    Done,
+}
+
+// A job as sent to the worker.
+struct WorkerJob {
+    id: u64,
+    job: RenderJob,
+    progress: Arc<AtomicUsize>,
+    cancel: Arc<AtomicBool>,
+}
+
+struct BackgroundRender {
+    state: RenderState,
+    next_id: u64,
+    jobs: Sender<WorkerJob>,
+    // Finished jobs' ids, and pixels if not cancelled.
+    results: Receiver<(u64, Option<Vec<u8>>)>,
+}
+
+impl BackgroundRender {
+    // Start the worker, with its own copy of the tracer. This shares
+    // the env maps until either side regrades them, and lets the UI
+    // keep editing its tracer while the worker renders. The worker
+    // exits when this is dropped.
+    fn new(mut tracer: Tracer) -> BackgroundRender {
+        let (jobs, job_receiver) = mpsc::channel::<WorkerJob>();
+        let (result_sender, results) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut cache = RenderCache::default();
+            for work in job_receiver {
+                let record = |done| {
+                    work.progress.fetch_max(done, Ordering::Relaxed);
+                };
+                let pixels = work.job.run(&mut tracer, &mut cache, &record, &work.cancel);
+                if result_sender.send((work.id, pixels)).is_err() {
+                    break;
+                }
+            }
+        });
+        BackgroundRender {
+            state: RenderState::Idle,
+            next_id: 0,
+            jobs,
+            results,
+        }
+    }
+
+    // Start rendering the job, cancelling any render in flight.
+    fn start(&mut self, job: RenderJob) {
+        self.cancel();
+        let id = self.next_id;
+        self.next_id += 1;
+        let progress = Arc::new(AtomicUsize::new(0));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (out_size, total_rows) = (job.out_size, job.total_rows());
+        self.jobs
+            .send(WorkerJob {
+                id,
+                job,
+                progress: progress.clone(),
+                cancel: cancel.clone(),
+            })
+            .expect("Render worker has exited");
+        self.state = RenderState::Rendering {
+            id,
+            out_size,
+            total_rows,
+            progress,
+            cancel,
+        };
+    }
+
+    // Cancel any render in flight, as a fast render is replacing it.
+    fn cancel(&mut self) {
+        if let RenderState::Rendering { cancel, .. } = &self.state {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        self.state = RenderState::Idle;
+    }
+
+    // Fraction of the render in flight that's complete, if there is
+    // one.
+    fn progress(&self) -> Option<f32> {
+        match &self.state {
+            RenderState::Rendering {
+                total_rows,
+                progress,
+                ..
+            } => Some(progress.load(Ordering::Relaxed) as f32 / *total_rows as f32),
+            _ => None,
+        }
+    }
+
+    // Collect finished renders, returning the texture size and data
+    // if the current render has completed. Results from cancelled
+    // renders are dropped.
+    fn poll(&mut self) -> Option<((usize, usize), Vec<u8>)> {
+        let mut finished = None;
+        while let std::result::Result::Ok((id, pixels)) = self.results.try_recv() {
+            if let (
+                RenderState::Rendering {
+                    id: current,
+                    out_size,
+                    ..
+                },
+                Some(pixels),
+            ) = (&self.state, pixels)
+            {
+                if id == *current {
+                    finished = Some((*out_size, pixels));
+                    self.state = RenderState::Done;
+                }
+            }
+        }
+        finished
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// GPU tracing: Trace each fragment's ray in the fragment shader, so
+// that interaction needs no CPU render or texture upload. The shader
+// is a single-precision port of the adaptive tracer, and the CPU
+// tracer remains the reference for quality output. Env maps are
+// uploaded as texture arrays, with a layer per face, so that the
+// shader can select faces exactly as EnvMap does.
+//
+
+struct GpuTracer {
+    program: ShaderProgram,
+    // Positive- and negative-w env maps.
+    env_textures: [Texture; 2],
+}
+
+// The faces of an env map, concatenated in the order of Face::ALL,
+// along with their size. The faces must all be the same size, and
+// the map must be an 8-bit cube map, as the shader only samples
+// those.
+fn env_map_layers(env_map: &EnvMap) -> Result<((u32, u32), Vec<u8>)> {
+    let faces = Face::ALL
+        .iter()
+        .map(|face| env_map.face_image(*face))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| anyhow!("GPU tracing needs 8-bit cube map env maps"))?;
+    let size = faces[0].dimensions();
+    for (face, image) in Face::ALL.iter().zip(faces.iter()) {
+        let face_size = image.dimensions();
+        ensure!(
+            face_size == size,
+            "Env map face {} is {}x{}, but {} is {}x{}",
+            face.name(),
+            face_size.0,
+            face_size.1,
+            Face::ALL[0].name(),
+            size.0,
+            size.1
+        );
+    }
+    let data = faces
+        .iter()
+        .flat_map(|image| image.as_raw().iter().copied())
+        .collect();
+    Ok((size, data))
+}
+
+impl GpuTracer {
+    fn new(gl: &Context, shader_version: &str, tracer: &Tracer) -> Result<GpuTracer> {
+        let layers = [
+            env_map_layers(&tracer.env_map_pos)?,
+            env_map_layers(&tracer.env_map_neg)?,
+        ];
+        unsafe {
+            let program = build_program(gl, shader_version, TRACE_SRC)?;
+            let env_textures = layers.map(|((w, h), data)| {
+                let texture = gl.create_texture().unwrap();
+                gl.bind_texture(glow::TEXTURE_2D_ARRAY, Some(texture));
+                gl.tex_image_3d(
+                    glow::TEXTURE_2D_ARRAY,
+                    0,
+                    glow::RGBA8 as i32,
+                    w as i32,
+                    h as i32,
+                    Face::ALL.len() as i32,
+                    0,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    Some(&data),
+                );
+                // The shader fetches texels directly, but the texture
+                // is only complete with a non-mipmap filter.
+                for param in [glow::TEXTURE_MIN_FILTER, glow::TEXTURE_MAG_FILTER] {
+                    gl.tex_parameter_i32(glow::TEXTURE_2D_ARRAY, param, glow::NEAREST as i32);
+                }
+                texture
+            });
+            Ok(GpuTracer {
+                program: ShaderProgram::new(program),
+                env_textures,
+            })
+        }
+    }
+
+    // Trace the view described by the job over the shape. The env
+    // maps' rotations come from the tracer, as they're not part of
+    // the job.
+    fn draw(&mut self, gl: &Context, shape: &Shape, job: &RenderJob, tracer: &Tracer) {
+        let conf = &job.conf;
+        let frame = camera_frame(conf, job.tilt, job.turn, job.pan);
+        let (scale_x, scale_y) = conf.view_scale();
+        let view_scale = [scale_x as f32, scale_y as f32];
+        // The traced region is centred, as by `letterbox`.
+        let (out_w, out_h) = job.out_size;
+        let left = ((out_w - conf.width) / 2) as f32 / out_w as f32;
+        let first_row = ((out_h - conf.height) / 2) as f32 / out_h as f32;
+        let trace_w = conf.width as f32 / out_w as f32;
+        let trace_h = conf.height as f32 / out_h as f32;
+        let grade = &job.grade;
+
+        let program = &mut self.program;
+        unsafe {
+            program.use_program(gl);
+            program.set(gl, "origin", frame.origin);
+            program.set(gl, "axis_x", frame.axes[0]);
+            program.set(gl, "axis_y", frame.axes[1]);
+            program.set(gl, "axis_z", frame.axes[2]);
+            program.set(gl, "view_scale", view_scale);
+            program.set(
+                gl,
+                "trace_rect",
+                [left, first_row, left + trace_w, first_row + trace_h],
+            );
+            program.set(
+                gl,
+                "bar_colour",
+                job.bar_colour.map(|c| c as f32 / u8::MAX as f32),
+            );
+
+            program.set(gl, "w_scale", job.params.w_scale);
+            program.set(gl, "radius", job.params.radius);
+            program.set(gl, "infinity", job.params.infinity);
+
+            let [pos_texture, neg_texture] = self.env_textures;
+            let neg = if job.pos_map_only {
+                (&tracer.env_map_pos, pos_texture)
+            } else {
+                (&tracer.env_map_neg, neg_texture)
+            };
+            let env_maps = [
+                ("env_pos", (&tracer.env_map_pos, pos_texture)),
+                ("env_neg", neg),
+            ];
+            for (unit, (name, (env_map, texture))) in env_maps.into_iter().enumerate() {
+                gl.active_texture(glow::TEXTURE0 + unit as u32);
+                gl.bind_texture(glow::TEXTURE_2D_ARRAY, Some(texture));
+                program.set(gl, name, unit as i32);
+                let (cos, sin) = env_map.rotation();
+                program.set(gl, &format!("{}_rotation", name), [cos as f32, sin as f32]);
+                // Matches the shader's ALPHA_ constants.
+                let alpha = match env_map.alpha_policy() {
+                    EnvMapAlphaPolicy::Opaque => 0,
+                    EnvMapAlphaPolicy::Premultiply => 1,
+                    EnvMapAlphaPolicy::Passthrough => 2,
+                };
+                program.set(gl, &format!("{}_alpha", name), alpha);
+            }
+            gl.active_texture(glow::TEXTURE0);
+
+            program.set(
+                gl,
+                "grade",
+                [grade.brightness, grade.contrast, grade.saturation],
+            );
+
+            shape.draw(gl, glow::TRIANGLE_STRIP);
+        }
+    }
+
+    fn close(&self, gl: &Context) {
+        self.program.close(gl);
+        unsafe {
+            for texture in self.env_textures {
+                gl.delete_texture(texture);
+            }
+        }
+    }
+}
+
 struct Drawable {
     tracer: Tracer,
-    program: Program,
+    program: ShaderProgram,
     tilt: f64,
     turn: f64,
     pan: f64,
     shape: Shape,
-    tex: Texture,
+    // Render textures. The front one is drawn, and if double
+    // buffering, uploads go to the other one, which then becomes the
+    // front.
+    textures: [Texture; 2],
+    front: usize,
+    double_buffer: bool,
     fast_draw: bool,
     fov: f64,
     upscaling: usize,
+    wipe: Wipe,
+    crosshair: bool,
+    // Where the world axes appear, as fractions of the window's size,
+    // if shown.
+    show_axes: bool,
+    axis_markers: Vec<AxisPosition>,
+    focus: FocusAssist,
+    // Colour grading applied to both env maps.
+    grade: ColourGrade,
+    letterbox: Letterbox,
+    sharpen: Sharpen,
+    // Fast renders, kept to save re-tracing.
+    render_cache: RenderCache,
+    // Full-resolution renders, done in the background.
+    background: BackgroundRender,
+    // The GPU tracer, if it could be set up, and whether it's in use
+    // instead of the CPU tracer.
+    gpu: Option<GpuTracer>,
+    gpu_tracing: bool,
+    inspection: Option<RayInspection>,
+    // Sample the pos env map on both sides of the throat, to check
+    // its alignment.
+    pos_map_only: bool,
+}
+
+// Preview of a render at a fixed aspect ratio, letterboxed into the
+// window.
+struct Letterbox {
+    enabled: bool,
+    // Width / height of the traced image.
+    aspect: f64,
+    // Colour of the bars.
+    colour: [u8; 3],
+}
+
+// Unsharp masking of the displayed render.
+struct Sharpen {
+    // Multiple of the detail lost by blurring added back, with 0
+    // disabling sharpening.
+    amount: f64,
+    // Standard deviation of the blur, in texture pixels.
+    radius: f64,
+}
+
+// A single ray, traced from a shift-click to show its details.
+struct RayInspection {
+    // The clicked point, as fractions of the window's size.
+    window_pos: (f64, f64),
+    dir: Dir4,
+    geom: GeometryResult,
+    path: Vec<Point4>,
+    show_path: bool,
+}
+
+// A magnified inset of the centre of the render, like a camera's focus
+// assist, for judging detail without a full-resolution export.
+struct FocusAssist {
+    enabled: bool,
+    // Magnification relative to the main view.
+    zoom: f32,
+    // The render textures, registered with egui.
+    textures: [Option<egui::TextureId>; 2],
+}
+
+// Fraction of the window's width and height covered by the focus
+// assist inset.
+const FOCUS_INSET_FRACTION: f32 = 0.25;
+
+// The on-screen size of the focus assist inset for the given window
+// size, and the texture coordinates of the centre region it shows.
+// The texture's rows run bottom to top, while egui's run top to
+// bottom, so the region is flipped vertically.
+fn focus_crop(window: egui::Vec2, zoom: f32) -> (egui::Vec2, egui::Rect) {
+    let size = window * FOCUS_INSET_FRACTION;
+    let half = 0.5 * FOCUS_INSET_FRACTION / zoom;
+    let uv = egui::Rect::from_min_max(
+        egui::pos2(0.5 - half, 0.5 + half),
+        egui::pos2(0.5 + half, 0.5 - half),
+    );
+    (size, uv)
 }
 
 const VERT_SRC: &str = include_str!("shader/vertex.glsl");
 const FRAG_SRC: &str = include_str!("shader/fragment.glsl");
+const TRACE_SRC: &str = include_str!("shader/trace.glsl");
+
+// Compile and link a program from the vertex shader and the given
+// fragment shader.
+unsafe fn build_program(gl: &Context, shader_version: &str, frag_src: &str) -> Result<Program> {
+    let program = gl.create_program().map_err(|e| anyhow!(e))?;
+
+    let shader_sources = [
+        (glow::VERTEX_SHADER, VERT_SRC),
+        (glow::FRAGMENT_SHADER, frag_src),
+    ];
+
+    let mut shaders = Vec::with_capacity(shader_sources.len());
+
+    for (shader_type, shader_source) in shader_sources.iter() {
+        let shader = gl.create_shader(*shader_type).map_err(|e| anyhow!(e))?;
+        gl.shader_source(shader, &format!("{}\n{}", shader_version, shader_source));
+        gl.compile_shader(shader);
+        if !gl.get_shader_compile_status(shader) {
+            bail!("{}", gl.get_shader_info_log(shader));
+        }
+        gl.attach_shader(program, shader);
+        shaders.push(shader);
+    }
+
+    gl.link_program(program);
+    if !gl.get_program_link_status(program) {
+        bail!("{}", gl.get_program_info_log(program));
+    }
+
+    for shader in shaders {
+        gl.detach_shader(program, shader);
+        gl.delete_shader(shader);
+    }
+
+    Ok(program)
+}
 
 // The tracing resolution used during interactive updates.
 const FAST_RES: usize = 128;
 
+// Degrees turned or tilted per arrow key press.
+const KEY_TURN_STEP: f64 = 2.0;
+
 impl Drawable {
     fn new(
         gl: &Context,
         shader_version: &str,
         env_map_path_pos: &Path,
         env_map_path_neg: &Path,
-    ) -> Drawable {
-        let env_map_pos = EnvMap::from(env_map_path_pos).unwrap();
-        let env_map_neg = EnvMap::from(env_map_path_neg).unwrap();
+        double_buffer: bool,
+    ) -> Result<Drawable> {
+        let env_map_pos = EnvMap::from(env_map_path_pos)?;
+        let env_map_neg = EnvMap::from(env_map_path_neg)?;
 
         unsafe {
-            let program = gl.create_program().expect("Cannot create program");
-
-            let shader_sources = [
-                (glow::VERTEX_SHADER, VERT_SRC),
-                (glow::FRAGMENT_SHADER, FRAG_SRC),
-            ];
-
-            let mut shaders = Vec::with_capacity(shader_sources.len());
-
-            for (shader_type, shader_source) in shader_sources.iter() {
-                let shader = gl
-                    .create_shader(*shader_type)
-                    .expect("Cannot create shader");
-                gl.shader_source(shader, &format!("{}\n{}", shader_version, shader_source));
-                gl.compile_shader(shader);
-                if !gl.get_shader_compile_status(shader) {
-                    panic!("{}", gl.get_shader_info_log(shader));
-                }
-                gl.attach_shader(program, shader);
-                shaders.push(shader);
-            }
-
-            gl.link_program(program);
-            if !gl.get_program_link_status(program) {
-                panic!("{}", gl.get_program_info_log(program));
-            }
-
-            for shader in shaders {
-                gl.detach_shader(program, shader);
-                gl.delete_shader(shader);
-            }
+            let program = ShaderProgram::new(build_program(gl, shader_version, FRAG_SRC).unwrap());
 
             let mut shape = Shape::new(gl);
 
             shape.rebuild(gl, &[0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 0.0, 1.0], &[0, 1, 3, 2]);
 
-            let tex = gl.create_texture().unwrap();
+            let textures = std::array::from_fn(|_| gl.create_texture().unwrap());
+
+            let tracer = Tracer::new(Arc::new(env_map_pos), Arc::new(env_map_neg), 0.25, 0.1, 4.0);
+            let gpu = GpuTracer::new(gl, shader_version, &tracer)
+                .map_err(|e| log::warn!("GPU tracing unavailable: {}", e))
+                .ok();
+            let wipe = Wipe {
+                enabled: false,
+                position: 0.5,
+                editing_b: false,
+                other: TracerParams::get(&tracer),
+            };
 
-            let drawable = Drawable {
-                tracer: Tracer {
-                    env_map_pos,
-                    env_map_neg,
-                    w_scale: 0.25,
-                    radius: 0.1,
-                    infinity: 4.0,
-                },
+            let mut drawable = Drawable {
                 program,
                 tilt: 0.0,
                 turn: 0.0,
                 pan: 0.0,
                 shape,
-                tex,
+                textures,
+                front: 0,
+                double_buffer,
                 fov: 90.0,
                 fast_draw: false,
                 upscaling: 2,
+                wipe,
+                crosshair: true,
+                show_axes: false,
+                axis_markers: Vec::new(),
+                focus: FocusAssist {
+                    enabled: false,
+                    zoom: 3.0,
+                    textures: [None; 2],
+                },
+                grade: ColourGrade::default(),
+                letterbox: Letterbox {
+                    enabled: false,
+                    aspect: 2.39,
+                    colour: [0, 0, 0],
+                },
+                sharpen: Sharpen {
+                    amount: 0.0,
+                    radius: 1.0,
+                },
+                render_cache: RenderCache::default(),
+                background: BackgroundRender::new(tracer.clone()),
+                gpu,
+                gpu_tracing: false,
+                inspection: None,
+                pos_map_only: false,
+                tracer,
             };
             drawable.rebuild_tex(gl);
-            drawable
+            Ok(drawable)
         }
     }
 
     fn ui(&mut self, ctx: &egui::Context, gl: &Context) {
+        if let Some((size, pixels)) = self.background.poll() {
+            self.upload_tex(gl, size, &pixels);
+        }
+
         egui::Window::new("Controls").show(ctx, |ui| {
+            if let Some(fraction) = self.background.progress() {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.add(egui::ProgressBar::new(fraction).show_percentage());
+                });
+                // Keep polling until the render completes.
+                ctx.request_repaint();
+            }
             // TODO
             // if ui.button("Quit").clicked() {}
             let mut need_retex = false;
+            need_retex |= slider(ui, &mut self.fov, 20.0..=160.0, "Field of view", 1.0);
+            need_retex |= slider(ui, &mut self.upscaling, 0..=4, "Upscaling", 1.0);
+            // The shader traces a single parameter set, straight to
+            // the window.
             need_retex |= ui
-                .add(egui::Slider::new(&mut self.fov, 20.0..=160.0).text("Field of view"))
-                .changed();
-            need_retex |= ui
-                .add(egui::Slider::new(&mut self.upscaling, 0..=4).text("Upscaling"))
-                .changed();
-            need_retex |= ui
-                .add(egui::Slider::new(&mut self.tilt, -90.0..=90.0).text("Tilt"))
-                .changed();
-            need_retex |= ui
-                .add(egui::Slider::new(&mut self.turn, -180.0..=180.0).text("Turn"))
+                .add_enabled(
+                    self.gpu.is_some() && !self.wipe.enabled,
+                    egui::Checkbox::new(&mut self.gpu_tracing, "GPU tracing"),
+                )
+                .on_disabled_hover_text("Unavailable, or in use by the A/B wipe")
                 .changed();
+            need_retex |= slider(ui, &mut self.tilt, -90.0..=90.0, "Tilt", 1.0);
+            need_retex |= slider(ui, &mut self.turn, -180.0..=180.0, "Turn", 1.0);
+            need_retex |= slider(ui, &mut self.pan, -180.0..=180.0, "Pan", 1.0);
+            ui.checkbox(&mut self.crosshair, "Crosshair");
+            if self.crosshair {
+                self.readout(ui);
+            }
             need_retex |= ui
-                .add(egui::Slider::new(&mut self.pan, -180.0..=180.0).text("Pan"))
+                .checkbox(&mut self.show_axes, "Show axes")
+                .on_hover_text("Found at low resolution, so small images may be missed")
                 .changed();
+            ui.add_enabled(
+                !self.gpu_tracing,
+                egui::Checkbox::new(&mut self.focus.enabled, "Focus assist"),
+            );
+            if self.focus.enabled {
+                slider(ui, &mut self.focus.zoom, 2.0..=4.0, "Zoom", 0.5);
+            }
             need_retex |= ui
-                .add(egui::Slider::new(&mut self.tracer.radius, -1.0..=1.0).text("Wormhole radius"))
+                .checkbox(&mut self.letterbox.enabled, "Letterbox")
                 .changed();
+            if self.letterbox.enabled {
+                need_retex |= slider(ui, &mut self.letterbox.aspect, 0.5..=3.0, "Aspect", 0.01);
+                ui.horizontal(|ui| {
+                    ui.label("Bar colour");
+                    need_retex |= ui
+                        .color_edit_button_srgb(&mut self.letterbox.colour)
+                        .changed();
+                });
+            }
+            need_retex |= slider(ui, &mut self.sharpen.amount, 0.0..=2.0, "Sharpen", 0.05);
+            if self.sharpen.amount > 0.0 {
+                need_retex |= slider(
+                    ui,
+                    &mut self.sharpen.radius,
+                    0.5..=4.0,
+                    "Sharpen radius",
+                    0.1,
+                );
+            }
+            ui.separator();
             need_retex |= ui
-                .add(egui::Slider::new(&mut self.tracer.w_scale, 0.1..=1.0).text("Smoothness"))
+                .add_enabled(
+                    !self.gpu_tracing,
+                    egui::Checkbox::new(&mut self.wipe.enabled, "A/B wipe"),
+                )
                 .changed();
+            if self.wipe.enabled {
+                ui.horizontal(|ui| {
+                    ui.label("Editing:");
+                    let old_editing_b = self.wipe.editing_b;
+                    ui.radio_value(&mut self.wipe.editing_b, false, "A");
+                    ui.radio_value(&mut self.wipe.editing_b, true, "B");
+                    if self.wipe.editing_b != old_editing_b {
+                        // Swap the edited parameters into the tracer.
+                        let current = TracerParams::get(&self.tracer);
+                        self.wipe.other.apply(&mut self.tracer);
+                        self.wipe.other = current;
+                    }
+                });
+                need_retex |= slider(
+                    ui,
+                    &mut self.wipe.position,
+                    0.0..=1.0,
+                    "Wipe position",
+                    0.01,
+                );
+            }
+            need_retex |= self.preset_combo(ui);
+            need_retex |= slider(
+                ui,
+                &mut self.tracer.radius,
+                -1.0..=1.0,
+                "Wormhole radius",
+                0.01,
+            );
+            need_retex |= slider(ui, &mut self.tracer.w_scale, -1.0..=1.0, "Smoothness", 0.01);
+            need_retex |= slider(ui, &mut self.tracer.infinity, 1.0..=10.0, "Infinity", 0.1);
+            ui.separator();
+            let mut regrade = false;
+            regrade |= slider(
+                ui,
+                &mut self.grade.brightness,
+                0.0..=4.0,
+                "Env brightness",
+                0.05,
+            );
+            regrade |= slider(
+                ui,
+                &mut self.grade.contrast,
+                0.25..=4.0,
+                "Env contrast",
+                0.05,
+            );
+            regrade |= slider(
+                ui,
+                &mut self.grade.saturation,
+                0.0..=2.0,
+                "Env saturation",
+                0.05,
+            );
             need_retex |= ui
-                .add(egui::Slider::new(&mut self.tracer.infinity, 1.0..=10.0).text("Infinity"))
+                .checkbox(&mut self.pos_map_only, "Use pos map for both sides")
                 .changed();
+            if regrade {
+                Arc::make_mut(&mut self.tracer.env_map_pos).set_grade(self.grade);
+                Arc::make_mut(&mut self.tracer.env_map_neg).set_grade(self.grade);
+                need_retex = true;
+            }
 
             if need_retex {
                 self.rebuild_tex(gl);
             }
         });
+
+        if self.wipe.enabled {
+            self.wipe_handle(ctx, gl);
+        }
+        if self.crosshair {
+            draw_crosshair(ctx);
+        }
+        if self.show_axes {
+            draw_axes(ctx, &self.axis_markers);
+        }
+        if self.focus.enabled && !self.gpu_tracing {
+            self.focus_inset(ctx);
+        }
+
+        let click = ctx.input(|i| {
+            (i.pointer.primary_clicked() && i.modifiers.shift)
+                .then(|| i.pointer.interact_pos())
+                .flatten()
+        });
+        if let Some(pos) = click {
+            if !ctx.is_pointer_over_area() {
+                let screen = ctx.screen_rect();
+                let rel = (pos - screen.min) / screen.size();
+                self.inspect((rel.x as f64, rel.y as f64));
+            }
+        }
+        self.inspector(ctx);
+    }
+
+    // Trace the ray under a window position, given as fractions of the
+    // window's size, for the inspector. With the A/B wipe, this is the
+    // state being edited.
+    fn inspect(&mut self, window_pos: (f64, f64)) {
+        let job = self.render_job();
+        let Some((px, py)) = job.window_to_pixel(window_pos) else {
+            return;
+        };
+        let (origin, dir) = camera_ray(&job.conf, job.tilt, job.turn, job.pan, px, py);
+        let show_path = self.inspection.as_ref().is_none_or(|i| i.show_path);
+        self.inspection = Some(RayInspection {
+            window_pos,
+            dir,
+            geom: self.tracer.trace_geometry(origin, dir, None),
+            path: self.tracer.trace_path(origin, dir, None),
+            show_path,
+        });
+    }
+
+    // Show the inspected ray's details, and its path over the view.
+    fn inspector(&mut self, ctx: &egui::Context) {
+        let job = self.render_job();
+        let Some(inspection) = &mut self.inspection else {
+            return;
+        };
+        let fmt = |d: Dir4| {
+            let d = d.norm();
+            format!("({:.3}, {:.3}, {:.3}, {:.3})", d.x, d.y, d.z, d.w)
+        };
+        let side = |positive_w| if positive_w { "+w" } else { "-w" };
+        let geom = &inspection.geom;
+        let mut open = true;
+        egui::Window::new("Ray inspector")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let (x, y) = inspection.window_pos;
+                ui.label(format!("At ({:.3}, {:.3}) in the window", x, y));
+                ui.label(format!("Starts along {}", fmt(inspection.dir)));
+                ui.label(format!("Path length {:.3}", geom.path_len));
+                ui.label(format!("{} steps", geom.steps));
+                ui.label(format!("Closest to the axis {:.4}", geom.min_dist));
+                ui.label(format!("Crosses the throat {} times", geom.crossings));
+                ui.label(format!(
+                    "Escapes along {} on {}",
+                    fmt(geom.final_dir),
+                    side(geom.positive_w())
+                ));
+                ui.checkbox(&mut inspection.show_path, "Show path")
+                    .on_hover_text("Projected as if space were flat");
+            });
+        if inspection.show_path {
+            draw_path(ctx, &job.project_points(&inspection.path));
+        }
+        if !open {
+            self.inspection = None;
+        }
+    }
+
+    // Show the centre of the render, magnified, in the bottom-right
+    // corner of the window.
+    fn focus_inset(&self, ctx: &egui::Context) {
+        let Some(texture) = self.focus.textures[self.front] else {
+            return;
+        };
+        let (size, uv) = focus_crop(ctx.screen_rect().size(), self.focus.zoom);
+        egui::Area::new(egui::Id::new("focus_assist"))
+            .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-8.0, -8.0))
+            .interactable(false)
+            .show(ctx, |ui| {
+                let response = ui.add(egui::Image::new((texture, size)).uv(uv));
+                ui.painter().rect_stroke(
+                    response.rect,
+                    0.0,
+                    egui::Stroke::new(1.0, egui::Color32::WHITE),
+                );
+            });
+    }
+
+    // Show the camera pose, and where the centre ray starts off
+    // looking and where it ends up.
+    fn readout(&self, ui: &mut egui::Ui) {
+        let fmt = |d: Dir4| {
+            let d = d.norm();
+            format!("({:.3}, {:.3}, {:.3})", d.x, d.y, d.z)
+        };
+        let (origin, dir) = self.centre_ray();
+        let geom = self.tracer.trace_geometry(origin, dir, None);
+        let side = |positive_w| if positive_w { "+w" } else { "-w" };
+        ui.label(format!(
+            "Tilt {:.1}, turn {:.1}, pan {:.1}",
+            self.tilt, self.turn, self.pan
+        ));
+        ui.label(format!("Camera on {}", side(geom.start_positive_w)));
+        ui.label(format!("Looking along {}", fmt(dir)));
+        ui.label(format!(
+            "Escapes along {} on {}",
+            fmt(geom.final_dir),
+            side(geom.positive_w())
+        ));
+    }
+
+    // Turn and tilt the camera, in degrees, wrapping the turn and
+    // clamping the tilt.
+    fn rotate_view(&mut self, turn: f64, tilt: f64) {
+        self.turn += turn;
+        if self.turn > 180.0 {
+            self.turn -= 360.0;
+        } else if self.turn < -180.0 {
+            self.turn += 360.0;
+        }
+        self.tilt = (self.tilt + tilt).min(90.0).max(-90.0);
+    }
+
+    // Select from the presets, returning true if the parameters
+    // changed.
+    fn preset_combo(&mut self, ui: &mut egui::Ui) -> bool {
+        let current = TracerParams::get(&self.tracer);
+        let selected = PRESETS
+            .iter()
+            .find(|(_, params)| *params == current)
+            .map_or("Custom", |(name, _)| name);
+        let mut changed = false;
+        egui::ComboBox::from_label("Preset")
+            .selected_text(selected)
+            .show_ui(ui, |ui| {
+                for (name, params) in PRESETS.iter() {
+                    if ui.selectable_label(*name == selected, *name).clicked() {
+                        params.apply(&mut self.tracer);
+                        changed = *params != current;
+                    }
+                }
+            });
+        changed
+    }
+
+    // Draw the wipe line, and allow it to be dragged.
+    fn wipe_handle(&mut self, ctx: &egui::Context, gl: &Context) {
+        const HANDLE_WIDTH: f32 = 8.0;
+        let screen = ctx.screen_rect();
+        let x = screen.left() + self.wipe.position as f32 * screen.width();
+        let dragged = egui::Area::new(egui::Id::new("wipe_handle"))
+            .fixed_pos(egui::pos2(x - HANDLE_WIDTH * 0.5, screen.top()))
+            .order(egui::Order::Background)
+            .show(ctx, |ui| {
+                let (rect, response) = ui.allocate_exact_size(
+                    egui::vec2(HANDLE_WIDTH, screen.height()),
+                    egui::Sense::drag(),
+                );
+                ui.painter().vline(
+                    rect.center().x,
+                    rect.y_range(),
+                    egui::Stroke::new(2.0, egui::Color32::WHITE),
+                );
+                response.drag_delta().x
+            })
+            .inner;
+        if dragged != 0.0 {
+            let position = self.wipe.position + (dragged / screen.width()) as f64;
+            self.wipe.position = position.clamp(0.0, 1.0);
+            self.rebuild_tex(gl);
+        }
+    }
+
+    fn canvas_config(&self, width: usize, height: usize) -> CanvasConfig {
+        CanvasConfig {
+            width,
+            height,
+            aspect: 1.0,
+            fov_degrees: self.fov,
+            fov_v_degrees: None,
+            antialias: Antialias::None,
+            filter: Filter::Box(1.0),
+            handedness: Handedness::Left,
+            pixel_offset: (0.0, 0.0),
+            tone_map: ToneMap::None,
+            gamma: 1.0,
+        }
     }
 
-    fn rebuild_tex(&self, gl: &Context) {
+    // Where the world axes appear in the job's view, as fractions of
+    // the window's size. The image is searched at the fast draw
+    // resolution, which is enough to seed the refinement.
+    fn find_axes(&self, job: &RenderJob) -> Vec<AxisPosition> {
+        let (w, h) = (job.conf.width, job.conf.height);
+        let scan_w = w.min(FAST_RES);
+        let scan_h = (scan_w * h / w).max(1);
+        let scan = self.canvas_config(scan_w, scan_h);
+        let camera = camera_frame(&scan, job.tilt, job.turn, job.pan);
+        self.tracer
+            .axis_positions(&scan, &camera)
+            .into_iter()
+            .map(|marker| {
+                // Scale from the scan's pixels to the job's.
+                let (px, py) = marker.pos;
+                let px = (px + 0.5) * w as f64 / scan_w as f64 - 0.5;
+                let py = (py + 0.5) * h as f64 / scan_h as f64 - 0.5;
+                AxisPosition {
+                    pos: job.pixel_to_window((px, py)),
+                    ..marker
+                }
+            })
+            .collect()
+    }
+
+    // The ray through the centre of the view. A single pixel canvas
+    // has its pixel centred.
+    fn centre_ray(&self) -> (Point4, Dir4) {
+        camera_ray(
+            &self.canvas_config(1, 1),
+            self.tilt,
+            self.turn,
+            self.pan,
+            0.0,
+            0.0,
+        )
+    }
+
+    // Snapshot the state needed to render the texture.
+    fn render_job(&self) -> RenderJob {
         // TODO: Pull this from the context or whatever.
         let (base_w, base_h) = (1024, 768);
         let (w, h) = if self.fast_draw {
@@ -599,21 +1973,71 @@ impl Drawable {
             (base_w >> self.upscaling, base_h >> self.upscaling)
         };
 
-        let tex_data = self.tracer.render(
-            &CanvasConfig {
-                width: w,
-                height: h,
-                aspect: 1.0,
-                fov_degrees: self.fov,
-            },
-            self.tilt,
-            self.turn,
-            self.pan,
-            None,
-        );
+        // Trace the letterboxed region only, and add the bars after.
+        let out_size = (w, h);
+        let (w, h) = if self.letterbox.enabled {
+            fit_aspect(w, h, self.letterbox.aspect)
+        } else {
+            (w, h)
+        };
+        let [r, g, b] = self.letterbox.colour;
+
+        RenderJob {
+            params: TracerParams::get(&self.tracer),
+            grade: self.grade,
+            tilt: self.tilt,
+            turn: self.turn,
+            pan: self.pan,
+            conf: self.canvas_config(w, h),
+            wipe: self.wipe.enabled.then_some((
+                self.wipe.other,
+                self.wipe.editing_b,
+                self.wipe.position,
+            )),
+            out_size,
+            bar_colour: [r, g, b, u8::MAX],
+            sharpen: (self.sharpen.amount > 0.0)
+                .then_some((self.sharpen.amount, self.sharpen.radius)),
+            pos_map_only: self.pos_map_only,
+        }
+    }
+
+    // Fast renders, while interacting, are done immediately, replacing
+    // any full-resolution render in flight. Full-resolution renders
+    // are started in the background, and uploaded when `ui` polls.
+    // GPU tracing draws from the current state each frame, so has no
+    // texture to build.
+    fn rebuild_tex(&mut self, gl: &Context) {
+        let job = self.render_job();
+        self.axis_markers = if self.show_axes {
+            self.find_axes(&job)
+        } else {
+            Vec::new()
+        };
+        if self.gpu_tracing {
+            self.background.cancel();
+        } else if self.fast_draw {
+            self.background.cancel();
+            let pixels = job
+                .run(
+                    &mut self.tracer,
+                    &mut self.render_cache,
+                    &|_| {},
+                    &AtomicBool::new(false),
+                )
+                .expect("Render cancelled without a cancel request");
+            self.upload_tex(gl, job.out_size, &pixels);
+        } else {
+            self.background.start(job);
+        }
+    }
 
+    fn upload_tex(&mut self, gl: &Context, (w, h): (usize, usize), tex_data: &[u8]) {
+        if self.double_buffer {
+            self.front = 1 - self.front;
+        }
         unsafe {
-            gl.bind_texture(glow::TEXTURE_2D, Some(self.tex));
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.textures[self.front]));
 
             gl.tex_image_2d(
                 glow::TEXTURE_2D,
@@ -624,7 +2048,7 @@ impl Drawable {
                 0,
                 glow::RGBA,
                 glow::UNSIGNED_BYTE,
-                Some(&tex_data),
+                Some(tex_data),
             );
             gl.generate_mipmap(glow::TEXTURE_2D);
         }
@@ -633,16 +2057,200 @@ impl Drawable {
     fn draw(&mut self, gl: &Context, width: u32, height: u32) {
         unsafe {
             gl.viewport(0, 0, width as i32, height as i32);
-            gl.use_program(Some(self.program));
-            gl.bind_texture(glow::TEXTURE_2D, Some(self.tex));
-            self.shape.draw(gl, glow::TRIANGLE_STRIP);
+        }
+        let job = self.render_job();
+        match &mut self.gpu {
+            Some(gpu) if self.gpu_tracing => {
+                gpu.draw(gl, &self.shape, &job, &self.tracer);
+            }
+            _ => unsafe {
+                let program = &mut self.program;
+                program.use_program(gl);
+                gl.bind_texture(glow::TEXTURE_2D, Some(self.textures[self.front]));
+                self.shape.draw(gl, glow::TRIANGLE_STRIP);
+            },
         }
     }
 
-    fn close(&self, gl: &Context) {
+    fn close(&mut self, gl: &Context) {
+        self.background.cancel();
+        self.program.close(gl);
         unsafe {
-            gl.delete_program(self.program);
+            for tex in self.textures {
+                gl.delete_texture(tex);
+            }
+        }
+        if let Some(gpu) = &self.gpu {
+            gpu.close(gl);
         }
         self.shape.close(gl);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: TracerParams = TracerParams {
+        w_scale: 0.25,
+        radius: 0.1,
+        infinity: 4.0,
+    };
+    const B: TracerParams = TracerParams {
+        w_scale: 0.1,
+        radius: 0.3,
+        infinity: 6.0,
+    };
+
+    // A tracer with a different gradient on each face of each env map.
+    fn test_tracer() -> Tracer {
+        let env_map = |flip: u32| {
+            let faces = std::array::from_fn(|face| {
+                image::RgbaImage::from_fn(8, 8, |x, y| {
+                    let (x, y) = (x ^ flip, y ^ flip);
+                    image::Rgba([(x * 32) as u8, (y * 32) as u8, (face * 40) as u8, 255])
+                })
+            });
+            Arc::new(EnvMap::from_images(faces).unwrap())
+        };
+        Tracer::new(env_map(0), env_map(7), A.w_scale, A.radius, A.infinity)
+    }
+
+    // A job editing state A, wiped against state B.
+    fn test_job(params: TracerParams, position: f64) -> RenderJob {
+        let conf = CanvasConfig {
+            width: 20,
+            height: 12,
+            aspect: 1.0,
+            fov_degrees: 90.0,
+            fov_v_degrees: None,
+            antialias: Antialias::None,
+            filter: Filter::Box(1.0),
+            handedness: Handedness::Left,
+            pixel_offset: (0.0, 0.0),
+            tone_map: ToneMap::None,
+            gamma: 1.0,
+        };
+        RenderJob {
+            params,
+            grade: ColourGrade::default(),
+            tilt: 0.0,
+            turn: 20.0,
+            pan: 0.0,
+            conf,
+            wipe: Some((B, false, position)),
+            out_size: (conf.width, conf.height),
+            bar_colour: [0, 0, 0, 255],
+            sharpen: None,
+            pos_map_only: false,
+        }
+    }
+
+    fn render(job: &RenderJob, params: TracerParams) -> Vec<u8> {
+        let mut tracer = test_tracer();
+        params.apply(&mut tracer);
+        tracer
+            .render(&job.conf, job.tilt, job.turn, job.pan, None, None)
+            .complete()
+            .unwrap()
+    }
+
+    #[test]
+    fn composite_wipe_splits_at_position() {
+        let (width, height) = (10, 3);
+        let a = RenderBuffer::from_grey(width, height, &vec![0.25; width * height]);
+        let b = RenderBuffer::from_grey(width, height, &vec![0.75; width * height]);
+        for (position, split) in [(0.0, 0), (0.34, 3), (0.5, 5), (1.0, 10)] {
+            let out = composite_wipe(&a, &b, position);
+            for y in 0..height {
+                for x in 0..width {
+                    let source = if x < split { &a } else { &b };
+                    assert_eq!(out.get(x, y), source.get(x, y), "{position} at ({x}, {y})");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wipe_job_takes_a_left_and_b_right() {
+        let job = test_job(A, 0.5);
+        let (a, b) = (render(&job, A), render(&job, B));
+        let mut cache = RenderCache::default();
+        let pixels = job
+            .run(
+                &mut test_tracer(),
+                &mut cache,
+                &|_| {},
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+
+        let width = job.conf.width;
+        for (idx, pixel) in pixels.chunks(4).enumerate() {
+            let source = if idx % width < width / 2 { &a } else { &b };
+            assert_eq!(pixel, &source[idx * 4..idx * 4 + 4], "pixel {idx}");
+        }
+        // The two sides differ, so the test can tell them apart.
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn wipe_job_reuses_unchanged_side() {
+        let mut cache = RenderCache::default();
+        let mut tracer = test_tracer();
+        let cancel = AtomicBool::new(false);
+        test_job(A, 0.5)
+            .run(&mut tracer, &mut cache, &|_| {}, &cancel)
+            .unwrap();
+
+        // Mark the cached render of B, so that it shows if reused.
+        let job = test_job(A, 0.5);
+        let marker = [1.0, 0.0, 1.0, 1.0];
+        let (_, cached_b) = cache
+            .entries
+            .iter_mut()
+            .find(|(key, _)| *key == job.render_key(B))
+            .unwrap();
+        for y in 0..cached_b.height {
+            for x in 0..cached_b.width {
+                cached_b.set(x, y, marker);
+            }
+        }
+
+        // Edit A. Only A is traced again, with its progress reported
+        // row by row, while B's pass completes at once.
+        let edited = TracerParams { radius: 0.2, ..A };
+        let job = test_job(edited, 0.5);
+        let progress = std::sync::Mutex::new(Vec::new());
+        let pixels = job
+            .run(
+                &mut tracer,
+                &mut cache,
+                &|done| progress.lock().unwrap().push(done),
+                &cancel,
+            )
+            .unwrap();
+        let height = job.conf.height;
+        let mut expected_progress = (1..=height).collect::<Vec<_>>();
+        expected_progress.push(2 * height);
+        assert_eq!(progress.into_inner().unwrap(), expected_progress);
+
+        let a = render(&job, edited);
+        let width = job.conf.width;
+        for (idx, pixel) in pixels.chunks(4).enumerate() {
+            if idx % width < width / 2 {
+                assert_eq!(pixel, &a[idx * 4..idx * 4 + 4], "pixel {idx}");
+            } else {
+                assert_eq!(pixel, [255, 0, 255, 255], "pixel {idx}");
+            }
+        }
+
+        // The stale render of A has been dropped.
+        let keys = cache
+            .entries
+            .iter()
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, [job.render_key(B), job.render_key(edited)]);
+    }
+}
@@ -0,0 +1,274 @@
+// Fragment shader that traces each fragment's ray on the GPU. A port
+// of the adaptive tracer in tray-racer-lib's renderer.rs, which is the
+// reference. This is single precision, so derivatives are analytic
+// rather than finite differences, and the solver's tolerance is
+// looser.
+
+precision highp float;
+precision highp sampler2DArray;
+
+in vec2 uv;
+out vec4 color;
+
+// Camera position and the world directions of its axes.
+uniform vec4 origin;
+uniform vec4 axis_x;
+uniform vec4 axis_y;
+uniform vec4 axis_z;
+// Half the width and height of the view, at unit distance.
+uniform vec2 view_scale;
+// The traced region, as minimum and maximum texture coordinates. The
+// rest is filled with bar_colour, for letterboxing.
+uniform vec4 trace_rect;
+uniform vec4 bar_colour;
+
+// Wormhole parameters, as in Tracer.
+uniform float w_scale;
+uniform float radius;
+uniform float infinity;
+
+// Env maps, with a layer per face, in the order of Face::ALL.
+uniform sampler2DArray env_pos;
+uniform sampler2DArray env_neg;
+// Cos and sin of each env map's rotation around the y axis.
+uniform vec2 env_pos_rotation;
+uniform vec2 env_neg_rotation;
+// Each env map's EnvMapAlphaPolicy, as one of the ALPHA_ constants.
+uniform int env_pos_alpha;
+uniform int env_neg_alpha;
+// Brightness, contrast and saturation, as in ColourGrade.
+uniform vec3 grade;
+
+// Layers of the env map faces.
+const int POS_X = 0;
+const int NEG_X = 1;
+const int POS_Y = 2;
+const int NEG_Y = 3;
+const int POS_Z = 4;
+const int NEG_Z = 5;
+
+// Alpha policies, in the order of EnvMapAlphaPolicy.
+const int ALPHA_OPAQUE = 0;
+const int ALPHA_PREMULTIPLY = 1;
+const int ALPHA_PASSTHROUGH = 2;
+
+// Colour of rays that can't be started on the surface, like the CPU
+// tracer's PANIC_COLOUR.
+const vec4 FAIL_COLOUR = vec4(1.0, 0.0, 1.0, 1.0);
+
+const float EPSILON = 1.0e-7;
+const float MIN_W_SCALE = 0.02;
+const float TARGET_NORM_DIFF = 1.0e-4;
+const float BASE_ADAPTIVE_STEP = 0.01;
+const float MAX_ADAPTIVE_STEP = 0.1;
+const float MID_GREY = 0.18;
+// Limit on how much the step size grows per step.
+const float MAX_STEP_GROWTH = 2.0;
+
+// Newton-Raphson convergence tolerance, relative to the squared
+// distance from the axis. EPSILON is below single-precision rounding
+// of `dist` away from the origin.
+const float TOLERANCE = 1.0e-6;
+
+// Limit on the steps per ray, so that a stuck ray can't hang the GPU.
+const int MAX_STEPS = 10000;
+
+////////////////////////////////////////////////////////////////////////
+// Surface
+//
+
+float effective_w_scale() {
+    float mag = abs(w_scale);
+    return mag < 2.0 * MIN_W_SCALE ? MIN_W_SCALE + mag * mag / (4.0 * MIN_W_SCALE) : mag;
+}
+
+float dist(vec4 p) {
+    float w = p.w / effective_w_scale();
+    return dot(p.xyz, p.xyz) - w * w - radius;
+}
+
+// The gradient of `dist`. Not normalised.
+vec4 normal_at(vec4 p) {
+    float s = effective_w_scale();
+    return vec4(2.0 * p.xyz, -2.0 * p.w / (s * s));
+}
+
+// Newton-Raphson solver on dist(point + lambda direction). Returns
+// whether it converged, with lambda set to the last estimate either
+// way.
+bool intersect_line(vec4 point, vec4 direction, int max_iters, out float lambda) {
+    lambda = 0.0;
+    for (int i = 0; i < max_iters; i++) {
+        vec4 guess = point + direction * lambda;
+        float guess_val = dist(guess);
+        if (abs(guess_val) < TOLERANCE * max(1.0, dot(guess.xyz, guess.xyz))) {
+            return true;
+        }
+        lambda -= guess_val / dot(normal_at(guess), direction);
+    }
+    return false;
+}
+
+// Intersect the surface with a line in the w-axis from the point, on
+// the side the point's w is on.
+bool project_vertical(vec4 point, out vec4 result) {
+    const vec4 VERTICAL = vec4(0.0, 0.0, 0.0, 1.0);
+    bool negative = point.w < 0.0;
+    float lambda;
+    if (intersect_line(point, VERTICAL, 10, lambda)) {
+        result = point + VERTICAL * lambda;
+        if ((result.w < 0.0) == negative) {
+            return true;
+        }
+    }
+    // Retry from further out on the point's side, where the solver
+    // is better behaved.
+    point.w = negative ? -1.0 : 1.0;
+    bool found = intersect_line(point, VERTICAL, 10, lambda);
+    result = point + VERTICAL * lambda;
+    return found;
+}
+
+////////////////////////////////////////////////////////////////////////
+// Tracer
+//
+
+// Step from p along the unit direction delta, constrained to the
+// surface in the direction of the unit normal norm. Updates norm to
+// the normal at the new point, and step_size to the size for the next
+// step.
+vec4 step_adaptive(vec4 p, vec4 delta, inout vec4 norm, inout float step_size) {
+    // Where the CPU tracer would give up on the ray, retry with smaller
+    // steps, as its fixed-step `step` does. Near the throat, rounding
+    // can otherwise stop the solver converging.
+    vec4 base = p + delta * step_size;
+    float projection;
+    bool converged = intersect_line(base, norm, 3, projection);
+    for (int i = 0; i < 8 && !converged; i++) {
+        step_size *= 0.5;
+        base = p + delta * step_size;
+        converged = intersect_line(base, norm, 3, projection);
+    }
+    vec4 new_p = base + norm * projection;
+
+    // Now, calculate the next step size. The CPU tracer measures the
+    // offset between projecting along the old and new normals by
+    // subtracting the points, which cancels catastrophically in single
+    // precision, so we use the equivalent product. Small steps can
+    // still round the offset to zero, so growth is limited.
+    vec4 new_norm = normalize(normal_at(new_p));
+    float norm_diff = max(length(norm - new_norm) * abs(projection) / step_size, 1.0e-20);
+    step_size = min(step_size * min(TARGET_NORM_DIFF / norm_diff, MAX_STEP_GROWTH), MAX_ADAPTIVE_STEP);
+
+    norm = new_norm;
+    return new_p;
+}
+
+// Trace a ray, returning the direction of its final step, or a zero
+// vector if it couldn't be started.
+vec4 trace(vec4 p, vec4 dir) {
+    vec4 old_p;
+    vec4 start;
+    vec4 back;
+    if (!project_vertical(p, start) ||
+        !project_vertical(start - normalize(dir) * BASE_ADAPTIVE_STEP, back)) {
+        return vec4(0.0);
+    }
+    p = start;
+    old_p = back;
+
+    float step_size = BASE_ADAPTIVE_STEP;
+    vec4 norm = normalize(normal_at(p));
+    for (int i = 0; i < MAX_STEPS && length(p) < infinity; i++) {
+        vec4 delta = normalize(p - old_p);
+        old_p = p;
+        p = step_adaptive(p, delta, norm, step_size);
+    }
+    return p - old_p;
+}
+
+////////////////////////////////////////////////////////////////////////
+// Environment map
+//
+
+// The layer and texture coordinates for face coordinates normalised to
+// have the largest direction in z, as EnvMap::face_coords.
+vec3 face_coords(float x, float y, float z, int neg_face, int pos_face, bool vertical) {
+    int face = z > 0.0 ? neg_face : pos_face;
+    vec2 coords = vertical ? vec2(x / abs(z), y / z) : vec2(x / z, y / abs(z));
+    return vec3(float(face), 0.5 * (coords + 1.0));
+}
+
+// As EnvMap::face_uv.
+vec3 face_uv(vec4 dir, vec2 rotation) {
+    vec3 d = vec3(
+        dir.x * rotation.x - dir.z * rotation.y,
+        dir.y,
+        dir.x * rotation.y + dir.z * rotation.x);
+    vec3 a = abs(d);
+    if (a.z >= a.x && a.z >= a.y) {
+        return face_coords(d.x, d.y, d.z, NEG_X, POS_X, false);
+    } else if (a.x >= a.y) {
+        return face_coords(d.z, d.y, -d.x, NEG_Z, POS_Z, false);
+    } else {
+        return face_coords(-d.z, -d.x, d.y, NEG_Y, POS_Y, true);
+    }
+}
+
+vec3 srgb_to_linear(vec3 c) {
+    return mix(pow((c + 0.055) / 1.055, vec3(2.4)), c / 12.92, lessThanEqual(c, vec3(0.04045)));
+}
+
+vec3 linear_to_srgb(vec3 c) {
+    c = clamp(c, 0.0, 1.0);
+    return mix(1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, c * 12.92, lessThanEqual(c, vec3(0.0031308)));
+}
+
+// As ColourGrade::apply, in linear space.
+vec3 apply_grade(vec3 rgb) {
+    rgb = MID_GREY * pow(rgb * grade.x / MID_GREY, vec3(grade.y));
+    // Rec. 709 luminance.
+    float luma = dot(rgb, vec3(0.2126, 0.7152, 0.0722));
+    return luma + (rgb - luma) * grade.z;
+}
+
+// Nearest-texel lookup, as EnvMap::colour.
+vec4 env_colour(sampler2DArray env, vec2 rotation, int alpha, vec4 dir) {
+    vec3 face = face_uv(dir, rotation);
+    ivec2 size = textureSize(env, 0).xy;
+    ivec2 texel = min(ivec2(face.yz * vec2(size)), size - 1);
+    vec4 colour = texelFetch(env, ivec3(texel, int(face.x)), 0);
+    if (grade != vec3(1.0) || alpha == ALPHA_PREMULTIPLY) {
+        vec3 rgb = apply_grade(srgb_to_linear(colour.rgb));
+        if (alpha == ALPHA_PREMULTIPLY) {
+            rgb *= colour.a;
+        }
+        colour.rgb = linear_to_srgb(rgb);
+    }
+    if (alpha == ALPHA_OPAQUE) {
+        colour.a = 1.0;
+    }
+    return colour;
+}
+
+void main() {
+    vec2 local = (uv - trace_rect.xy) / (trace_rect.zw - trace_rect.xy);
+    if (any(lessThan(local, vec2(0.0))) || any(greaterThanEqual(local, vec2(1.0)))) {
+        color = bar_colour;
+        return;
+    }
+
+    // Matches the CPU render's pixels as drawn from the texture, whose
+    // first row is at the bottom of the window.
+    vec2 view = view_scale * (1.0 - 2.0 * local);
+    vec4 dir = axis_x * view.x + axis_y * view.y + axis_z;
+
+    vec4 final_dir = trace(origin, dir);
+    if (final_dir == vec4(0.0)) {
+        color = FAIL_COLOUR;
+    } else if (final_dir.w > 0.0) {
+        color = env_colour(env_pos, env_pos_rotation, env_pos_alpha, final_dir);
+    } else {
+        color = env_colour(env_neg, env_neg_rotation, env_neg_alpha, final_dir);
+    }
+}
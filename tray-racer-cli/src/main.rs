@@ -3,14 +3,26 @@
 // file.
 //
 
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
 use std::path::Path;
+use std::sync::Arc;
 
 use anyhow::*;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use image::imageops::flip_vertical_in_place;
-use image::RgbaImage;
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
 
-use tray_racer_lib::{CanvasConfig, EnvMap, Tracer};
+use tray_racer_lib::expr::Expr;
+use tray_racer_lib::postprocess::unsharp_mask;
+use tray_racer_lib::vec4::*;
+use tray_racer_lib::{
+    camera_frame, camera_frame_at, camera_ray, fit_aspect, letterbox, normalize_depth, Antialias,
+    CameraFrame, CanvasConfig, ColourGrade, CubeLayout, Dither, EnvMap, Face, Filter, Handedness,
+    Integrator, OriginJitter, RenderBuffer, SamplingMode, Throat, ToneMap, Tracer,
+    DEFAULT_THROAT_BLEND, DIST_EXPR_VARS,
+};
 
 ////////////////////////////////////////////////////////////////////////
 // Command-line args
@@ -19,25 +31,164 @@ use tray_racer_lib::{CanvasConfig, EnvMap, Tracer};
 const DEFAULT_ENV_MAP_POS: &str = "skyboxes/beach-skyboxes/HeartInTheSand";
 const DEFAULT_ENV_MAP_NEG: &str = "skyboxes/night-skyboxes/PondNight";
 
+// Fixed step size used as the reference for the step-diff mode.
+const REFERENCE_STEP: f64 = 0.001;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Mode {
+    /// Trace rays through the wormhole.
+    Colour,
+    /// Show the height (w coordinate) of the surface as a grey-scale
+    /// image, viewed from above. Red marks the throat.
+    HeightMap,
+    /// Render with the adaptive tracer and with a fine fixed step (the
+    /// step size, if given), and show the per-channel difference.
+    StepDiff,
+    /// Show the env map face and texture coordinates each ray looks
+    /// up: u in red, v in green, and the face in blue, in steps of 51
+    /// in the order posx, negx, posy, negy, posz, negz.
+    EnvmapUv,
+    /// Show the path length of each ray as a grey level, with white at
+    /// twice the infinity radius.
+    Depth,
+    /// Show the total Newton-Raphson iterations taken by each ray's
+    /// steps as a grey level, with white at the most taken by any ray.
+    NewtonCost,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum FilterArg {
+    /// Average the supersamples equally.
+    Box,
+    /// Weight supersamples falling linearly away from the pixel centre.
+    Tent,
+    /// Weight supersamples by a Gaussian, with a standard deviation of
+    /// a quarter of the filter width.
+    Gaussian,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum AntialiasMode {
+    /// One ray per pixel.
+    None,
+    /// Supersample pixels at sharp changes in path length.
+    DepthEdge,
+    /// Supersample pixels at changes in the number of throat
+    /// crossings.
+    CrossingEdge,
+    /// Supersample every pixel on a jittered grid.
+    Supersample,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum HandednessArg {
+    /// +x on the left of the image (the original convention).
+    Left,
+    /// +x on the right of the image.
+    Right,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum ToneMapArg {
+    /// Clip colours to the output range.
+    None,
+    /// Compress highlights with c / (1 + c).
+    Reinhard,
+    /// Compress highlights with an approximation to the ACES filmic
+    /// curve.
+    Aces,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum SamplingArg {
+    /// Use the nearest texel, which is fastest.
+    Nearest,
+    /// Blend the four nearest texels, smoothing magnified maps.
+    Bilinear,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum IntegratorArg {
+    /// Step along the previous step's direction and project back onto
+    /// the surface.
+    Projection,
+    /// Integrate the geodesic equation with 4th-order Runge-Kutta.
+    Rk4,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum DitherArg {
+    /// Round to the nearest level.
+    None,
+    /// Ordered dithering with an 8x8 Bayer matrix.
+    Ordered,
+    /// Blue-noise dithering, without the Bayer matrix's cross-hatching.
+    #[value(name = "bluenoise")]
+    BlueNoise,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum FaceArg {
+    Posx,
+    Negx,
+    Posy,
+    Negy,
+    Posz,
+    Negz,
+}
+
 /// Program to allow you to view distorted space
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[derive(Parser, Clone, Debug)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    disable_help_flag = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Print help. There's no short form, as -h is the height
+    #[arg(long, global = true, action = clap::ArgAction::Help)]
+    help: Option<bool>,
     /// Directory containing positive-w env maps
     #[arg(long, default_value_t = DEFAULT_ENV_MAP_POS.to_string())]
     env_map_pos: String,
     /// Directory containing negative-w env maps
     #[arg(long, default_value_t = DEFAULT_ENV_MAP_NEG.to_string())]
     env_map_neg: String,
+    /// Make any env map faces that are missing by mirroring the
+    /// opposite face, for skyboxes that only have half the faces
+    #[arg(long)]
+    mirror_env_maps: bool,
+    /// How the env maps' faces are arranged. For single-image layouts,
+    /// the env map paths are image files rather than directories
+    #[arg(long, value_enum, default_value_t = CubemapLayout::Files)]
+    env_map_layout: CubemapLayout,
+    /// Load each env map from a single equirectangular panorama image,
+    /// twice as wide as it is tall, rather than cube faces
+    #[arg(long)]
+    equirect_env_maps: bool,
+    /// How the environment maps are sampled
+    #[arg(long, value_enum, default_value_t = SamplingArg::Nearest)]
+    env_map_filter: SamplingArg,
     /// File to write the output to
-    #[arg(short, long)]
-    output: String,
+    #[arg(short, long, required = true)]
+    output: Option<String>,
     /// Output image width
     #[arg(short, long, default_value_t = 1024)]
     width: usize,
     /// Output image height
     #[arg(short, long, default_value_t = 768)]
     height: usize,
+    /// Aspect ratio (width / height) to trace at. The traced image is
+    /// letterboxed to fit the output size. Not supported when tiling
+    #[arg(long)]
+    aspect: Option<f64>,
+    /// Colour of the letterbox bars, as 'r,g,b' in 0-255
+    #[arg(long, value_delimiter = ',', default_value = "0,0,0")]
+    letterbox_colour: Vec<u8>,
     /// Camera 'pitch', in degrees
     #[arg(long, default_value_t = 0.0)]
     tilt: f64,
@@ -50,19 +201,367 @@ struct Args {
     /// Horizontal camera field of view, in degrees
     #[arg(long, default_value_t = 90.0)]
     fov: f64,
+    /// Vertical camera field of view, in degrees. By default, it
+    /// follows from the horizontal one and the image's aspect ratio
+    #[arg(long)]
+    fov_v: Option<f64>,
     /// Wormhole radius
     #[arg(long, default_value_t = 0.1)]
     radius: f64,
     /// How smooth the curve between sides of the wormhole are - width
-    /// of the wormhole in the fourth dimension
+    /// of the wormhole in the fourth dimension. Negative values render
+    /// as their magnitude, and very small ones are floored smoothly
     #[arg(long, default_value_t = 0.25)]
     smoothness: f64,
+    /// Another throat, as 'x,y,z,radius,smoothness'. May be given
+    /// more than once
+    #[arg(long = "throat", value_delimiter = ',', allow_negative_numbers = true)]
+    throats: Vec<f64>,
+    /// How smoothly the surfaces of multiple throats are blended where
+    /// they meet
+    #[arg(long, default_value_t = DEFAULT_THROAT_BLEND)]
+    throat_blend: f64,
     /// The 4-distance at which we assume no further curvature occurs
     #[arg(long, default_value_t = 4.0)]
     infinity: f64,
+    /// Cutaway plane, as a point and normal 'px,py,pz,nx,ny,nz'. Rays
+    /// crossing behind the plane are transparent
+    #[arg(long, value_delimiter = ',', allow_negative_numbers = true)]
+    clip_plane: Option<Vec<f64>>,
+    /// Render rays that panic in magenta, rather than aborting
+    #[arg(long)]
+    catch_panics: bool,
+    /// Stop rays after they have crossed the wormhole's throat this
+    /// many times
+    #[arg(long)]
+    max_crossings: Option<usize>,
+    /// Evaluate the surface function with compensated arithmetic, for
+    /// accuracy near the surface
+    #[arg(long)]
+    compensated_dist: bool,
+    /// Surface function to trace instead of the wormhole's, as an
+    /// expression in x, y, z and w that is zero on the surface, e.g.
+    /// "x^2 + y^2 + z^2 - (w/0.25)^2 - 0.25". The camera starts on
+    /// the surface above its position along w, and the smoothness and
+    /// radius are ignored
+    #[arg(long)]
+    dist_expr: Option<String>,
+    /// Render a black hole with this Schwarzschild radius instead of
+    /// the wormhole. Rays falling through its horizon are black
+    #[arg(long, conflicts_with_all = ["dist_expr", "throats"])]
+    black_hole: Option<f64>,
     /// Path-tracing step size
     #[arg(short, long)]
     step_size: Option<f64>,
+    /// Trace with a fixed step size expected to keep the error in
+    /// rays' final directions within this, found by probing a few
+    /// rays, rather than giving the step size
+    #[arg(long, conflicts_with = "step_size")]
+    target_error: Option<f64>,
+    /// How rays are advanced by each fixed step. Needs a step size or
+    /// target error, as the adaptive tracer always projects
+    #[arg(long, value_enum, default_value_t = IntegratorArg::Projection)]
+    integrator: IntegratorArg,
+    /// Seed the adaptive tracer's step sizes from a coarse pre-pass,
+    /// for faster convergence. Colour mode only, with the adaptive
+    /// tracer and without anti-aliasing, tiling, jitter or camera paths
+    #[arg(long)]
+    step_prepass: bool,
+    /// What to render
+    #[arg(long, value_enum, default_value_t = Mode::Colour)]
+    mode: Mode,
+    /// Anti-aliasing approach
+    #[arg(long, value_enum, default_value_t = AntialiasMode::None)]
+    antialias: AntialiasMode,
+    /// Supersampling grid size, for N x N samples per pixel
+    #[arg(long, default_value_t = 2)]
+    aa_samples: usize,
+    /// Reconstruction filter used to combine anti-aliasing supersamples
+    #[arg(long, value_enum, default_value_t = FilterArg::Box)]
+    filter: FilterArg,
+    /// Width of the reconstruction filter, in pixels
+    #[arg(long, default_value_t = 1.0)]
+    filter_width: f64,
+    /// Camera handedness. The camera looks along +z with +y towards the
+    /// bottom of the image, and +x to the left if left-handed
+    #[arg(long, value_enum, default_value_t = HandednessArg::Left)]
+    handedness: HandednessArg,
+    /// Horizontal offset of the sample positions, in pixels
+    #[arg(long, default_value_t = 0.0)]
+    offset_x: f64,
+    /// Vertical offset of the sample positions, in pixels
+    #[arg(long, default_value_t = 0.0)]
+    offset_y: f64,
+    /// Tone-mapping operator applied before writing the image
+    #[arg(long, value_enum, default_value_t = ToneMapArg::None)]
+    tone_map: ToneMapArg,
+    /// Gamma correction applied after tone mapping
+    #[arg(long, default_value_t = 1.0)]
+    gamma: f64,
+    /// Dithering applied when converting to 8-bit output, to avoid
+    /// banding in smooth gradients. Not supported when tiling
+    #[arg(long, value_enum, default_value_t = DitherArg::None)]
+    dither: DitherArg,
+    /// Sharpen the render with an unsharp mask of this amount, adding
+    /// back this multiple of the detail lost by blurring. Not
+    /// supported when tiling
+    #[arg(long)]
+    sharpen: Option<f64>,
+    /// Standard deviation of the unsharp mask's blur, in pixels
+    #[arg(long, default_value_t = 1.0)]
+    sharpen_radius: f64,
+    /// In depth mode, rescale so that the nearest pixel is black and
+    /// the farthest white. The range is recorded in the metadata
+    #[arg(long)]
+    depth_normalize: bool,
+    /// Bits per channel of the output image (8 or 16)
+    #[arg(long, default_value_t = 8)]
+    bit_depth: usize,
+    /// Render and write the image in strips of this many rows, to
+    /// bound memory use. Writes PNG, and only supports the colour mode
+    /// without anti-aliasing
+    #[arg(long)]
+    tile_size: Option<usize>,
+    /// Record the render parameters as text chunks in the output,
+    /// which is written as PNG
+    #[arg(long)]
+    embed_metadata: bool,
+    /// Number of frames to render. With more than one, '{}' in the
+    /// output name is replaced by the frame number
+    #[arg(long, default_value_t = 1)]
+    frames: usize,
+    /// Only render the frames from start to end inclusive, given as
+    /// 'start:end' counting from 0, with the same parameters as in a
+    /// full run. For re-rendering part of an animation
+    #[arg(long)]
+    frame_range: Option<String>,
+    /// Don't print each frame's render time and the estimated time
+    /// remaining when rendering multiple frames
+    #[arg(long)]
+    quiet: bool,
+    /// Draw a progress bar on stderr as rows of the image complete.
+    /// Only supported for plain colour renders, which may be tiled
+    #[arg(long)]
+    progress: bool,
+    /// Rotation of the environment maps around the vertical axis on
+    /// the first frame, in degrees
+    #[arg(long, default_value_t = 0.0)]
+    env_rot_start: f64,
+    /// Rotation of the environment maps on the last frame, in degrees
+    #[arg(long, default_value_t = 0.0)]
+    env_rot_end: f64,
+    /// Multiplier for the environment maps' linear brightness
+    #[arg(long, default_value_t = 1.0)]
+    env_brightness: f32,
+    /// Contrast of the environment maps, as a power around mid-grey
+    #[arg(long, default_value_t = 1.0)]
+    env_contrast: f32,
+    /// Saturation of the environment maps, with 0 giving grey-scale
+    #[arg(long, default_value_t = 1.0)]
+    env_saturation: f32,
+    /// Sample only this face of the environment maps, repeated in
+    /// every direction, to find which face an artefact comes from
+    #[arg(long, value_enum)]
+    debug_face: Option<FaceArg>,
+    /// Randomly move the camera origin within this radius and average
+    /// the renders, for a motion-blur look. Colour mode only, without
+    /// anti-aliasing
+    #[arg(long)]
+    origin_jitter: Option<f64>,
+    /// Preview the lensed region quickly, only tracing rays whose
+    /// straight-line path passes within this distance of the throat,
+    /// and looking the rest up directly in the env map. The output
+    /// differs from a full trace, as untraced rays miss the slight
+    /// bending far from the throat. Colour mode only, without
+    /// anti-aliasing
+    #[arg(long)]
+    disk_only: Option<f64>,
+    /// Number of renders averaged for origin jitter
+    #[arg(long, default_value_t = 16)]
+    jitter_samples: usize,
+    /// Seed for the random origin jitter
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Pan on the last frame, in degrees. Defaults to --pan
+    #[arg(long)]
+    pan_end: Option<f64>,
+    /// Tilt of each frame, as an expression of the frame time t, which
+    /// runs from 0 on the first frame to 1 on the last
+    #[arg(long)]
+    tilt_expr: Option<String>,
+    /// Turn of each frame, as an expression of t
+    #[arg(long)]
+    turn_expr: Option<String>,
+    /// Pan of each frame, as an expression of t, e.g. "360*t - 180"
+    #[arg(long, conflicts_with_all = ["pan_end", "constant_apparent_pan"])]
+    pan_expr: Option<String>,
+    /// Field of view of each frame, as an expression of t
+    #[arg(long)]
+    fov_expr: Option<String>,
+    /// Wormhole radius of each frame, as an expression of t, e.g.
+    /// "0.1 + 0.4*sin(2*pi*t)"
+    #[arg(long)]
+    radius_expr: Option<String>,
+    /// Smoothness of each frame, as an expression of t
+    #[arg(long)]
+    smoothness_expr: Option<String>,
+    /// Space the pans over the frames so that the centre of the view
+    /// appears to move at a constant rate, allowing for lensing,
+    /// rather than linearly
+    #[arg(long)]
+    constant_apparent_pan: bool,
+    /// Carry the camera orientation from frame to frame by parallel
+    /// transport, rather than setting it from the tilt, turn and pan
+    /// each frame. Colour mode only, without tiling or origin jitter
+    #[arg(long)]
+    transport: bool,
+    /// CSV file of camera poses to render, one frame per row, with
+    /// columns x,y,z,w,tilt,turn,roll,fov and an optional header row.
+    /// The position is projected onto the surface along w, so the sign
+    /// of w picks the side of the wormhole, with 0 counting as
+    /// positive. Replaces the other camera arguments. Colour mode
+    /// only, without tiling or origin jitter
+    #[arg(long)]
+    trajectory: Option<String>,
+    /// Also write the final direction of each pixel's ray, not
+    /// normalised, to this NumPy .npy file, as float64 with shape
+    /// (height, width, 4) and rows top-down like the image. Clipped
+    /// rays are NaN. Single frames only
+    #[arg(long)]
+    direction_field: Option<String>,
+    /// Mark where the world x, y and z axis directions appear in the
+    /// image, allowing for lensing, and where the w axis points at the
+    /// camera, with dots for the positive directions and rings for the
+    /// negative. Not supported when tiling
+    #[arg(long)]
+    show_axes: bool,
+    /// Colour of the axis markers, as 'r,g,b' in 0-255. By default, x,
+    /// y, z and w are red, green, blue and yellow
+    #[arg(long, value_delimiter = ',', requires = "show_axes")]
+    axes_colour: Option<Vec<u8>>,
+}
+
+/// Alternatives to rendering an image. The scene and camera are set
+/// by the main arguments.
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Trace a sparse grid of rays, and write their paths out as
+    /// polylines in an OBJ file.
+    ExportRays {
+        /// File to write the paths to
+        #[arg(long)]
+        out: String,
+        /// Number of rays across the field of view
+        #[arg(long, default_value_t = 16)]
+        rays_x: usize,
+        /// Number of rays down the field of view
+        #[arg(long, default_value_t = 12)]
+        rays_y: usize,
+        /// Write the w coordinate as a 4th vertex component, rather
+        /// than dropping it
+        #[arg(long)]
+        keep_w: bool,
+    },
+    /// Render the full view from the camera position as the six faces
+    /// of a cube map, in the same layout as the env map directories.
+    /// Tilt and turn are ignored.
+    RenderCubemap {
+        /// Directory to write the faces to, or the image file for
+        /// single-image layouts
+        #[arg(long, alias = "out-dir")]
+        out: String,
+        /// Width and height of each face
+        #[arg(long, default_value_t = 512)]
+        size: usize,
+        /// How to arrange the faces
+        #[arg(long, value_enum, default_value_t = CubemapLayout::Files)]
+        layout: CubemapLayout,
+    },
+    /// Bisect on a wormhole parameter until a measured feature of the
+    /// image hits a target value, and print the parameter.
+    Solve {
+        /// Feature to measure
+        #[arg(long, value_enum)]
+        target: SolveTarget,
+        /// Target value of the feature
+        #[arg(long)]
+        value: f64,
+        /// Parameter to vary
+        #[arg(long, value_enum)]
+        vary: SolveParam,
+        /// Acceptable distance from the target value
+        #[arg(long, default_value_t = 0.5)]
+        tolerance: f64,
+    },
+    /// Check numerical invariants of the solver for the given scene,
+    /// printing the measured residual of each.
+    Selftest,
+    /// Report which of the env map face files a directory has, with
+    /// their formats and sizes, and whether they load, without
+    /// rendering.
+    ListFaces {
+        /// Env map directory to check
+        dir: String,
+    },
+    /// Time renders of a small canvas, and report the rays traced per
+    /// second, optionally sweeping a wormhole parameter to show how
+    /// the cost scales.
+    Bench {
+        /// Width of the canvas, which has a 4:3 aspect ratio
+        #[arg(long, default_value_t = 128)]
+        size: usize,
+        /// Number of timed renders for each parameter value
+        #[arg(long, default_value_t = 5)]
+        runs: usize,
+        /// Parameter to sweep, over the range the solver searches
+        #[arg(long, value_enum)]
+        sweep: Option<SolveParam>,
+        /// Number of parameter values in the sweep
+        #[arg(long, default_value_t = 5)]
+        sweep_steps: usize,
+    },
+    /// Alpha-composite one RGBA image over another of the same size,
+    /// such as a clipped render over a background. No scene is
+    /// rendered.
+    Composite {
+        /// Foreground image
+        #[arg(long)]
+        over: String,
+        /// Background image
+        #[arg(long)]
+        under: String,
+        /// File to write the composited image to
+        #[arg(long)]
+        out: String,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CubemapLayout {
+    /// One file per face, in a directory.
+    Files,
+    /// A single 4 x 3 face horizontal cross.
+    Cross,
+    /// A single 3 x 4 face vertical cross, with the back face upside
+    /// down below the down face.
+    VerticalCross,
+    /// A single 6 x 1 face strip: right, left, up, down, front, back.
+    Strip,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum SolveTarget {
+    /// Radius in pixels of the Einstein ring, looking straight at the
+    /// wormhole.
+    RingRadius,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum SolveParam {
+    /// Wormhole radius.
+    Radius,
+    /// Wormhole smoothness.
+    Smoothness,
 }
 
 ////////////////////////////////////////////////////////////////////////
@@ -70,62 +569,2042 @@ struct Args {
 //
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    env_logger::init();
+    let mut args = Args::parse();
+    // Only probe for a step size if the command traces with it.
+    let uses_step_size = !matches!(
+        args.command,
+        Some(Command::Selftest | Command::ListFaces { .. } | Command::Composite { .. })
+    );
+    if let (Some(target_error), true) = (args.target_error, uses_step_size) {
+        args.step_size = Some(suggest_step_size(&args, target_error)?);
+    }
 
-    let env_map_pos = EnvMap::from(Path::new(&args.env_map_pos))?;
-    let env_map_neg = EnvMap::from(Path::new(&args.env_map_neg))?;
+    match &args.command {
+        None => render_image(&args),
+        Some(Command::ExportRays {
+            out,
+            rays_x,
+            rays_y,
+            keep_w,
+        }) => export_rays(&args, out, *rays_x, *rays_y, *keep_w),
+        Some(Command::RenderCubemap { out, size, layout }) => {
+            render_cubemap(&args, out, *size, *layout)
+        }
+        Some(Command::Solve {
+            target,
+            value,
+            vary,
+            tolerance,
+        }) => solve(&args, *target, *value, *vary, *tolerance),
+        Some(Command::Selftest) => selftest(&args),
+        Some(Command::ListFaces { dir }) => list_faces(Path::new(dir)),
+        Some(Command::Bench {
+            size,
+            runs,
+            sweep,
+            sweep_steps,
+        }) => bench(&args, *size, *runs, *sweep, *sweep_steps),
+        Some(Command::Composite { over, under, out }) => composite(over, under, out),
+    }
+}
+
+// Build the tracer from the args. The environment maps can be skipped
+// for modes that don't need them.
+fn build_tracer(args: &Args, load_env_maps: bool) -> Result<Tracer> {
+    ensure!(
+        !args.mirror_env_maps || args.env_map_layout == CubemapLayout::Files,
+        "Env maps can only be mirrored when loaded from separate files"
+    );
+    ensure!(
+        !args.equirect_env_maps
+            || (!args.mirror_env_maps && args.env_map_layout == CubemapLayout::Files),
+        "Equirectangular env maps can't be mirrored or given a cube layout"
+    );
+    ensure!(
+        args.integrator == IntegratorArg::Projection
+            || args.step_size.is_some()
+            || args.target_error.is_some(),
+        "The RK4 integrator needs a fixed step size or target error"
+    );
+    let (mut env_map_pos, mut env_map_neg) = if load_env_maps {
+        let load = |path: &Path| match cube_layout(args.env_map_layout) {
+            Some(layout) => EnvMap::from_layout(path, layout),
+            None if args.equirect_env_maps => EnvMap::from_equirect(path),
+            None if args.mirror_env_maps => Ok(EnvMap::from_mirrored(path)?),
+            None => Ok(EnvMap::from(path)?),
+        };
+        (
+            load(Path::new(&args.env_map_pos))?,
+            load(Path::new(&args.env_map_neg))?,
+        )
+    } else {
+        (EnvMap::new(), EnvMap::new())
+    };
+    let grade = ColourGrade {
+        brightness: args.env_brightness,
+        contrast: args.env_contrast,
+        saturation: args.env_saturation,
+    };
+    ensure_in_range("--env-brightness", grade.brightness, 0.0..=16.0)?;
+    ensure_in_range("--env-contrast", grade.contrast, 0.1..=10.0)?;
+    ensure_in_range("--env-saturation", grade.saturation, 0.0..=4.0)?;
+    env_map_pos.set_grade(grade);
+    env_map_neg.set_grade(grade);
+    let debug_face = args.debug_face.map(|face| match face {
+        FaceArg::Posx => Face::PosX,
+        FaceArg::Negx => Face::NegX,
+        FaceArg::Posy => Face::PosY,
+        FaceArg::Negy => Face::NegY,
+        FaceArg::Posz => Face::PosZ,
+        FaceArg::Negz => Face::NegZ,
+    });
+    env_map_pos.set_debug_face(debug_face);
+    env_map_neg.set_debug_face(debug_face);
+    let filter = match args.env_map_filter {
+        SamplingArg::Nearest => SamplingMode::Nearest,
+        SamplingArg::Bilinear => SamplingMode::Bilinear,
+    };
+    env_map_pos.set_filter(filter);
+    env_map_neg.set_filter(filter);
     let w_scale = args.smoothness;
-    assert!(0.1 <= w_scale && w_scale <= 1.0);
+    ensure_in_range("--smoothness", w_scale, -1.0..=1.0)?;
     let radius = args.radius;
-    assert!(-1.0 <= radius && radius <= 1.0);
+    ensure_in_range("--radius", radius, -1.0..=1.0)?;
     let infinity = args.infinity;
-    assert!(1.0 <= infinity && infinity <= 10.0);
+    ensure_in_range("--infinity", infinity, 1.0..=10.0)?;
+
+    if let Some(v) = &args.clip_plane {
+        ensure!(v.len() == 6, "Clip plane needs 6 values, got {}", v.len());
+    }
+    ensure!(
+        args.throats.len().is_multiple_of(5),
+        "Each throat needs 5 values, got {} in total",
+        args.throats.len()
+    );
+    let extra_throats = args
+        .throats
+        .chunks(5)
+        .map(|v| {
+            ensure_in_range("--throat smoothness", v[4], -1.0..=1.0)?;
+            ensure_in_range("--throat radius", v[3], -1.0..=1.0)?;
+            Ok(Throat {
+                centre: Point4 {
+                    x: v[0],
+                    y: v[1],
+                    z: v[2],
+                    w: 0.0,
+                },
+                w_scale: v[4],
+                radius: v[3],
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(
+        args.throat_blend >= 0.0,
+        "--throat-blend must not be negative"
+    );
+    if let Some(r_s) = args.black_hole {
+        ensure_in_range("--black-hole", r_s, 0.01..=1.0)?;
+    }
+
+    let clip_plane = args.clip_plane.as_ref().map(|v| {
+        let point = Point4 {
+            x: v[0],
+            y: v[1],
+            z: v[2],
+            w: 0.0,
+        };
+        let normal = Dir4 {
+            x: v[3],
+            y: v[4],
+            z: v[5],
+            w: 0.0,
+        };
+        (point, normal)
+    });
+
+    Ok(Tracer {
+        clip_plane,
+        catch_panics: args.catch_panics,
+        max_crossings: args.max_crossings,
+        compensated_dist: args.compensated_dist,
+        dist_expr: args
+            .dist_expr
+            .as_ref()
+            .map(|src| Expr::parse(src, &DIST_EXPR_VARS))
+            .transpose()?,
+        integrator: match args.integrator {
+            IntegratorArg::Projection => Integrator::Projection,
+            IntegratorArg::Rk4 => Integrator::Rk4,
+        },
+        extra_throats,
+        throat_blend: args.throat_blend,
+        black_hole: args.black_hole,
+        ..Tracer::new(
+            Arc::new(env_map_pos),
+            Arc::new(env_map_neg),
+            w_scale,
+            radius,
+            infinity,
+        )
+    })
+}
+
+// Check that an argument is within its allowed range.
+fn ensure_in_range<T: PartialOrd + std::fmt::Debug>(
+    name: &str,
+    value: T,
+    range: RangeInclusive<T>,
+) -> Result<()> {
+    ensure!(
+        range.contains(&value),
+        "{} is {:?}, outside {:?}",
+        name,
+        value,
+        range
+    );
+    Ok(())
+}
 
-    let tracer = Tracer {
-        env_map_pos,
-        env_map_neg,
-        w_scale,
-        radius,
-        infinity,
+fn check_camera_args(args: &Args) -> Result<()> {
+    ensure_in_range("--fov", args.fov, 20.0..=160.0)?;
+    if let Some(fov_v) = args.fov_v {
+        ensure_in_range("--fov-v", fov_v, 20.0..=160.0)?;
+    }
+    ensure_in_range("--tilt", args.tilt, -90.0..=90.0)?;
+    ensure_in_range("--turn", args.turn, -180.0..=180.0)?;
+    ensure_in_range("--pan", args.pan, -180.0..=360.0)?;
+    if let Some(pan) = args.pan_end {
+        ensure_in_range("--pan-end", pan, -180.0..=360.0)?;
+    }
+    if let Some(step_size) = args.step_size {
+        ensure_in_range("--step-size", step_size, 0.001..=0.1)?;
+    }
+    Ok(())
+}
+
+fn filter(args: &Args) -> Result<Filter> {
+    let width = args.filter_width;
+    // Narrower filters would mostly fall back to the nearest
+    // supersample.
+    ensure_in_range("--filter-width", width, 0.5..=4.0)?;
+    Ok(match args.filter {
+        FilterArg::Box => Filter::Box(width),
+        FilterArg::Tent => Filter::Tent(width),
+        FilterArg::Gaussian => Filter::Gaussian(width),
+    })
+}
+
+fn handedness(args: &Args) -> Handedness {
+    match args.handedness {
+        HandednessArg::Left => Handedness::Left,
+        HandednessArg::Right => Handedness::Right,
+    }
+}
+
+// Probe for a fixed step size expected to meet the target error, for
+// the scene and camera in the args.
+fn suggest_step_size(args: &Args, target_error: f64) -> Result<f64> {
+    ensure_in_range("--target-error", target_error, 1.0e-6..=1.0)?;
+    let tracer = build_tracer(args, false)?;
+    let conf = CanvasConfig {
+        width: args.width,
+        height: args.height,
+        aspect: 1.0,
+        fov_degrees: args.fov,
+        fov_v_degrees: args.fov_v,
+        antialias: Antialias::None,
+        filter: Filter::Box(1.0),
+        handedness: handedness(args),
+        pixel_offset: (0.0, 0.0),
+        tone_map: ToneMap::None,
+        gamma: 1.0,
     };
+    let camera = camera_frame(&conf, args.tilt, args.turn, args.pan);
+    let step_size = tracer.suggest_step_size(&conf, &camera, target_error);
+    log::info!(
+        "Step size {} suggested for target error {}",
+        step_size,
+        target_error
+    );
+    Ok(step_size)
+}
+
+fn render_image(args: &Args) -> Result<()> {
+    let mut tracer = build_tracer(args, true)?;
 
     let width = args.width;
-    assert!(16 <= width && width <= 16384);
+    ensure_in_range("--width", width, 16..=16384)?;
     let height = args.height;
-    assert!(16 <= height && height <= 16384);
-
-    let fov_degrees = args.fov;
-    assert!(20.0 <= fov_degrees && fov_degrees <= 160.0);
-    let tilt = args.tilt;
-    assert!(-90.0 <= tilt && tilt <= 90.0);
-    let turn = args.turn;
-    assert!(-180.0 <= turn && turn <= 180.0);
-    let pan = args.pan;
-    assert!(-180.0 <= pan && pan <= 360.0);
-    let step_size = args.step_size;
-    if let Some(step_size) = step_size {
-        assert!(0.001 <= step_size && step_size <= 0.1);
-    }
-
-    let raw_image = tracer.render(
-        &CanvasConfig {
+    ensure_in_range("--height", height, 16..=16384)?;
+    check_camera_args(args)?;
+    ensure!(
+        args.letterbox_colour.len() == 3,
+        "Letterbox colour must have 3 components"
+    );
+    ensure!(
+        args.axes_colour.as_ref().is_none_or(|c| c.len() == 3),
+        "Axes colour must have 3 components"
+    );
+    ensure!(
+        !args.show_axes || args.tile_size.is_none(),
+        "The axes overlay isn't supported with tiled output"
+    );
+    // Trace at the requested aspect ratio, letterboxing on output.
+    let (width, height) = match args.aspect {
+        Some(aspect) => {
+            ensure_in_range("--aspect", aspect, 0.1..=10.0)?;
+            ensure!(
+                args.tile_size.is_none(),
+                "Letterboxing isn't supported with tiled output"
+            );
+            fit_aspect(width, height, aspect)
+        }
+        None => (width, height),
+    };
+
+    let conf = CanvasConfig {
+        width,
+        height,
+        // When writing out an image, we'll always assume square pixels.
+        aspect: 1.0,
+        fov_degrees: args.fov,
+        fov_v_degrees: args.fov_v,
+        antialias: match args.antialias {
+            AntialiasMode::None => Antialias::None,
+            AntialiasMode::DepthEdge => Antialias::DepthEdge,
+            AntialiasMode::CrossingEdge => Antialias::CrossingEdge,
+            AntialiasMode::Supersample => Antialias::Supersample(args.aa_samples),
+        },
+        filter: filter(args)?,
+        handedness: handedness(args),
+        pixel_offset: (args.offset_x, args.offset_y),
+        tone_map: tone_map(args),
+        gamma: args.gamma as f32,
+    };
+    ensure_in_range("--aa-samples", args.aa_samples, 1..=16)?;
+    ensure_in_range("--gamma", args.gamma, 0.1..=10.0)?;
+    ensure!(
+        args.bit_depth == 8 || args.bit_depth == 16,
+        "--bit-depth must be 8 or 16"
+    );
+    ensure!(
+        args.dither == DitherArg::None || (args.bit_depth == 8 && args.tile_size.is_none()),
+        "Dithering is only supported for untiled 8-bit output"
+    );
+    if let Some(amount) = args.sharpen {
+        ensure_in_range("--sharpen", amount, 0.0..=10.0)?;
+        ensure_in_range("--sharpen-radius", args.sharpen_radius, 0.1..=20.0)?;
+        ensure!(
+            args.tile_size.is_none(),
+            "Sharpening isn't supported with tiled output"
+        );
+    }
+    if let Some(radius) = args.origin_jitter {
+        // Keep the camera well clear of the wormhole.
+        ensure_in_range("--origin-jitter", radius, 0.0..=0.25)?;
+        ensure_in_range("--jitter-samples", args.jitter_samples, 1..=1024)?;
+        ensure!(
+            args.antialias == AntialiasMode::None && args.tile_size.is_none(),
+            "Origin jitter doesn't support anti-aliasing or tiled output"
+        );
+    }
+
+    if let Some(trace_radius) = args.disk_only {
+        ensure_in_range("--disk-only", trace_radius, 0.0..=10.0)?;
+        ensure!(
+            args.antialias == AntialiasMode::None
+                && args.tile_size.is_none()
+                && args.origin_jitter.is_none()
+                && !args.transport
+                && args.trajectory.is_none(),
+            "Disk-only rendering doesn't support anti-aliasing, tiling, jitter or camera paths"
+        );
+    }
+
+    if args.step_prepass {
+        ensure!(
+            args.mode == Mode::Colour
+                && args.step_size.is_none()
+                && args.antialias == AntialiasMode::None
+                && args.tile_size.is_none()
+                && args.origin_jitter.is_none()
+                && args.disk_only.is_none()
+                && !args.transport
+                && args.trajectory.is_none(),
+            "The step pre-pass only supports plain adaptive colour renders"
+        );
+    }
+
+    if args.progress {
+        ensure!(
+            args.mode == Mode::Colour
+                && args.origin_jitter.is_none()
+                && args.disk_only.is_none()
+                && !args.step_prepass
+                && !args.transport
+                && args.trajectory.is_none(),
+            "Progress is only shown for plain colour renders"
+        );
+    }
+
+    let trajectory = match &args.trajectory {
+        Some(path) => Some(read_trajectory(path)?),
+        None => None,
+    };
+    if let Some(poses) = &trajectory {
+        for (idx, pose) in poses.iter().enumerate() {
+            let Some(positive_w) = tracer.camera_side(pose.origin) else {
+                bail!(
+                    "Trajectory pose {} is inside the wormhole's throat",
+                    idx + 1
+                );
+            };
+            if !positive_w {
+                log::info!(
+                    "Trajectory pose {}: camera is on the far (-w) side",
+                    idx + 1
+                );
+            }
+        }
+    }
+
+    let frames = trajectory.as_ref().map_or(args.frames, |poses| poses.len());
+    ensure_in_range("Frame count", frames, 1..=100000)?;
+    // Required by clap unless there's a subcommand.
+    let output = args.output.as_ref().unwrap();
+    if frames > 1 && !output.contains("{}") {
+        bail!("Output name must contain '{{}}' to render multiple frames");
+    }
+    let frame_range = frame_range(args, frames)?;
+    ensure!(
+        !args.transport || trajectory.is_none(),
+        "Parallel transport can't be used with a trajectory"
+    );
+    ensure!(
+        !(args.transport || trajectory.is_some())
+            || (args.mode == Mode::Colour
+                && args.tile_size.is_none()
+                && args.origin_jitter.is_none()),
+        "Camera paths only support untiled colour renders without origin jitter"
+    );
+
+    if let Some(path) = &args.direction_field {
+        ensure!(
+            frames == 1 && trajectory.is_none(),
+            "The direction field can only be written for a single frame"
+        );
+        let camera = camera_frame(&conf, args.tilt, args.turn, args.pan);
+        let dirs = tracer.render_directions(&conf, &camera, args.step_size);
+        write_direction_field(path, conf.width, conf.height, &dirs)?;
+    }
+
+    let exprs = FrameExprs::parse(args)?;
+    ensure!(
+        exprs.is_empty() || trajectory.is_none(),
+        "Frame expressions can't be used with a trajectory"
+    );
+    exprs.check(frames)?;
+
+    let pan_end = args.pan_end.unwrap_or(args.pan);
+    let apparent_pans = if args.constant_apparent_pan {
+        ensure!(
+            trajectory.is_none(),
+            "Constant apparent pan can't be used with a trajectory"
+        );
+        Some(tracer.constant_apparent_pans(
+            &conf,
+            args.tilt,
+            args.turn,
+            (args.pan, pan_end),
+            frames,
+            args.step_size,
+        ))
+    } else {
+        None
+    };
+
+    let mut transported = camera_frame(&conf, args.tilt, args.turn, args.pan);
+    let start = std::time::Instant::now();
+    // Frames outside the range are still stepped through, so that
+    // state carried between frames, like the transported camera,
+    // matches a full run.
+    for frame in 0..frames {
+        let frame_start = std::time::Instant::now();
+        // Parameters are interpolated from start to end over the
+        // sequence, hitting both ends exactly.
+        let t = frame_time(frame, frames);
+        let lerp = |start: f64, end: f64| start + t * (end - start);
+
+        let env_rot = lerp(args.env_rot_start, args.env_rot_end);
+        Arc::make_mut(&mut tracer.env_map_pos).set_rotation(env_rot);
+        Arc::make_mut(&mut tracer.env_map_neg).set_rotation(env_rot);
+
+        let pan = match &apparent_pans {
+            Some(pans) => pans[frame],
+            None => lerp(args.pan, pan_end),
+        };
+        let mut frame_args = Args {
+            pan,
+            ..args.clone()
+        };
+        exprs.apply(t, &mut frame_args, &mut tracer)?;
+        let pan = frame_args.pan;
+        let camera = if let Some(poses) = &trajectory {
+            let pose = &poses[frame];
+            frame_args.fov = pose.fov;
+            Some(camera_frame_at(
+                &conf,
+                pose.origin,
+                pose.tilt,
+                pose.turn,
+                pose.roll,
+            ))
+        } else if args.transport {
+            // When transporting, the camera moves on from the previous
+            // frame's, only using the pan to find its new position.
+            let origin = camera_frame(&conf, args.tilt, args.turn, pan).origin;
+            transported = tracer
+                .transport_frame(&transported, origin)
+                .context("Couldn't transport the camera across the surface")?;
+            Some(transported)
+        } else {
+            None
+        };
+        if !frame_range.contains(&frame) {
+            continue;
+        }
+        let conf = CanvasConfig {
+            fov_degrees: frame_args.fov,
+            ..conf
+        };
+
+        let digits = (frames - 1).to_string().len();
+        let path = output.replace("{}", &format!("{:0digits$}", frame));
+        render_frame(&frame_args, &tracer, &conf, &path, camera.as_ref())?;
+        if frames > 1 && !args.quiet {
+            let eta = estimate_remaining(
+                start.elapsed(),
+                frame + 1 - frame_range.start(),
+                frame_range.end() + 1 - frame_range.start(),
+            );
+            eprintln!(
+                "Frame {}/{}: {:.2}s, ETA {:.1}s",
+                frame + 1,
+                frames,
+                frame_start.elapsed().as_secs_f64(),
+                eta.as_secs_f64()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// The frames to render, from the frame range argument if given, or
+// else all of them.
+fn frame_range(args: &Args, frames: usize) -> Result<RangeInclusive<usize>> {
+    let Some(range) = &args.frame_range else {
+        return Ok(0..=frames - 1);
+    };
+    let parse = |s: &str| {
+        s.trim()
+            .parse::<usize>()
+            .map_err(|_| anyhow!("Bad frame number '{}' in frame range '{}'", s, range))
+    };
+    let Some((start, end)) = range.split_once(':') else {
+        bail!("Frame range '{}' should be 'start:end'", range);
+    };
+    let (start, end) = (parse(start)?, parse(end)?);
+    ensure!(
+        start <= end && end < frames,
+        "Frame range {}:{} isn't within the {} frames",
+        start,
+        end,
+        frames
+    );
+    Ok(start..=end)
+}
+
+// Estimated time to render the rest of the frames, from the mean time
+// of those done so far.
+fn estimate_remaining(
+    elapsed: std::time::Duration,
+    done: usize,
+    total: usize,
+) -> std::time::Duration {
+    assert!((1..=total).contains(&done));
+    elapsed.mul_f64((total - done) as f64 / done as f64)
+}
+
+// Time through the sequence of the frame, from 0 on the first frame
+// to 1 on the last.
+fn frame_time(frame: usize, frames: usize) -> f64 {
+    if frames > 1 {
+        frame as f64 / (frames - 1) as f64
+    } else {
+        0.0
+    }
+}
+
+// A frame parameter given as an expression of the frame time t, with
+// the range its values must stay in.
+struct FrameExpr {
+    name: &'static str,
+    expr: Expr,
+    range: RangeInclusive<f64>,
+}
+
+impl FrameExpr {
+    fn parse(
+        name: &'static str,
+        src: &Option<String>,
+        range: RangeInclusive<f64>,
+    ) -> Result<Option<FrameExpr>> {
+        let Some(src) = src else {
+            return Ok(None);
+        };
+        let expr = Expr::parse(src, &["t"]).with_context(|| format!("Bad --{}", name))?;
+        Ok(Some(FrameExpr { name, expr, range }))
+    }
+
+    fn eval(&self, t: f64) -> Result<f64> {
+        let value = self.expr.eval(&[t]);
+        ensure!(
+            self.range.contains(&value),
+            "--{} gives {} at t = {}, outside {:?}",
+            self.name,
+            value,
+            t,
+            self.range
+        );
+        Ok(value)
+    }
+}
+
+// The frame parameters given as expressions.
+struct FrameExprs {
+    tilt: Option<FrameExpr>,
+    turn: Option<FrameExpr>,
+    pan: Option<FrameExpr>,
+    fov: Option<FrameExpr>,
+    radius: Option<FrameExpr>,
+    smoothness: Option<FrameExpr>,
+}
+
+impl FrameExprs {
+    // Ranges match those checked for the fixed arguments.
+    fn parse(args: &Args) -> Result<FrameExprs> {
+        Ok(FrameExprs {
+            tilt: FrameExpr::parse("tilt-expr", &args.tilt_expr, -90.0..=90.0)?,
+            turn: FrameExpr::parse("turn-expr", &args.turn_expr, -180.0..=180.0)?,
+            pan: FrameExpr::parse("pan-expr", &args.pan_expr, -180.0..=360.0)?,
+            fov: FrameExpr::parse("fov-expr", &args.fov_expr, 20.0..=160.0)?,
+            radius: FrameExpr::parse("radius-expr", &args.radius_expr, -1.0..=1.0)?,
+            smoothness: FrameExpr::parse("smoothness-expr", &args.smoothness_expr, -1.0..=1.0)?,
+        })
+    }
+
+    fn all(&self) -> [&Option<FrameExpr>; 6] {
+        [
+            &self.tilt,
+            &self.turn,
+            &self.pan,
+            &self.fov,
+            &self.radius,
+            &self.smoothness,
+        ]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.all().iter().all(|expr| expr.is_none())
+    }
+
+    // Check the values stay in range over all the frames, before
+    // spending time rendering any.
+    fn check(&self, frames: usize) -> Result<()> {
+        for frame in 0..frames {
+            for expr in self.all().iter().copied().flatten() {
+                expr.eval(frame_time(frame, frames))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Set the parameters that have expressions to their values at
+    // frame time t.
+    fn apply(&self, t: f64, args: &mut Args, tracer: &mut Tracer) -> Result<()> {
+        let set = |expr: &Option<FrameExpr>, value: &mut f64| -> Result<()> {
+            if let Some(expr) = expr {
+                *value = expr.eval(t)?;
+            }
+            Ok(())
+        };
+        set(&self.tilt, &mut args.tilt)?;
+        set(&self.turn, &mut args.turn)?;
+        set(&self.pan, &mut args.pan)?;
+        set(&self.fov, &mut args.fov)?;
+        set(&self.radius, &mut tracer.radius)?;
+        set(&self.smoothness, &mut tracer.w_scale)?;
+        Ok(())
+    }
+}
+
+// A camera pose, read from a trajectory file.
+struct Pose {
+    origin: Point4,
+    tilt: f64,
+    turn: f64,
+    roll: f64,
+    fov: f64,
+}
+
+const TRAJECTORY_COLUMNS: [&str; 8] = ["x", "y", "z", "w", "tilt", "turn", "roll", "fov"];
+
+// Read a CSV file of camera poses. Blank lines and lines starting
+// with '#' are skipped, and the first row may be a header naming the
+// columns.
+fn read_trajectory(path: &str) -> Result<Vec<Pose>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("Couldn't read '{}'", path))?;
+
+    let mut poses = Vec::new();
+    let mut first_row = true;
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at = || format!("{}:{}", path, idx + 1);
+        let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+
+        let is_header = first_row && fields[0].parse::<f64>().is_err();
+        first_row = false;
+        if is_header {
+            ensure!(
+                fields
+                    .iter()
+                    .map(|f| f.to_lowercase())
+                    .eq(TRAJECTORY_COLUMNS),
+                "{}: Expected header '{}'",
+                at(),
+                TRAJECTORY_COLUMNS.join(",")
+            );
+            continue;
+        }
+
+        ensure!(
+            fields.len() == TRAJECTORY_COLUMNS.len(),
+            "{}: Expected {} values, found {}",
+            at(),
+            TRAJECTORY_COLUMNS.len(),
+            fields.len()
+        );
+        let values = fields
+            .iter()
+            .map(|field| match field.parse::<f64>() {
+                Result::Ok(value) if value.is_finite() => Ok(value),
+                _ => bail!("{}: Invalid number '{}'", at(), field),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let [x, y, z, w, tilt, turn, roll, fov] = values[..] else {
+            unreachable!()
+        };
+
+        ensure!(
+            (-90.0..=90.0).contains(&tilt),
+            "{}: Tilt out of range",
+            at()
+        );
+        ensure!(
+            (-180.0..=180.0).contains(&turn),
+            "{}: Turn out of range",
+            at()
+        );
+        ensure!(
+            (-180.0..=180.0).contains(&roll),
+            "{}: Roll out of range",
+            at()
+        );
+        ensure!((20.0..=160.0).contains(&fov), "{}: FOV out of range", at());
+        poses.push(Pose {
+            origin: Point4 { x, y, z, w },
+            tilt,
+            turn,
+            roll,
+            fov,
+        });
+    }
+
+    ensure!(!poses.is_empty(), "{}: No poses found", path);
+    Ok(poses)
+}
+
+fn tone_map(args: &Args) -> ToneMap {
+    match args.tone_map {
+        ToneMapArg::None => ToneMap::None,
+        ToneMapArg::Reinhard => ToneMap::Reinhard,
+        ToneMapArg::Aces => ToneMap::Aces,
+    }
+}
+
+fn dither(args: &Args) -> Dither {
+    match args.dither {
+        DitherArg::None => Dither::None,
+        DitherArg::Ordered => Dither::Ordered,
+        DitherArg::BlueNoise => Dither::BlueNoise,
+    }
+}
+
+fn render_frame(
+    args: &Args,
+    tracer: &Tracer,
+    conf: &CanvasConfig,
+    path: &str,
+    camera: Option<&CameraFrame>,
+) -> Result<()> {
+    if let Some(tile_size) = args.tile_size {
+        return render_frame_tiled(args, tracer, conf, path, tile_size);
+    }
+
+    let (width, height) = (conf.width, conf.height);
+    // Metadata describing this frame, beyond the arguments.
+    let mut frame_metadata = Vec::new();
+    let buffer = match args.mode {
+        Mode::Colour => match (camera, args.origin_jitter) {
+            (Some(camera), _) => tracer.render_from_frame(conf, camera, args.step_size),
+            (None, Some(radius)) => tracer.render_jittered(
+                conf,
+                args.tilt,
+                args.turn,
+                args.pan,
+                args.step_size,
+                &OriginJitter {
+                    radius,
+                    samples: args.jitter_samples,
+                    seed: args.seed,
+                },
+            ),
+            (None, None) => match args.disk_only {
+                Some(trace_radius) => RenderBuffer::from_pixels(
+                    width,
+                    height,
+                    &tracer.render_disk_only(
+                        conf,
+                        args.tilt,
+                        args.turn,
+                        args.pan,
+                        args.step_size,
+                        trace_radius,
+                    ),
+                ),
+                None if args.step_prepass => {
+                    tracer.render_seeded(conf, args.tilt, args.turn, args.pan)
+                }
+                None if args.progress => tracer.render_buffer_with_progress(
+                    conf,
+                    args.tilt,
+                    args.turn,
+                    args.pan,
+                    args.step_size,
+                    &draw_progress,
+                ),
+                None => tracer.render_buffer(conf, args.tilt, args.turn, args.pan, args.step_size),
+            },
+        },
+        Mode::HeightMap => {
+            RenderBuffer::from_pixels(width, height, &tracer.render_height_map(conf))
+        }
+        Mode::EnvmapUv => RenderBuffer::from_pixels(
             width,
             height,
-            // When writing out an image, we'll always assume square pixels.
-            aspect: 1.0,
-            fov_degrees,
-        },
-        tilt,
-        turn,
-        pan,
-        step_size,
+            &tracer.render_envmap_uv(conf, args.tilt, args.turn, args.pan, args.step_size),
+        ),
+        Mode::Depth => {
+            let depths = tracer.render_depth(conf, args.tilt, args.turn, args.pan, args.step_size);
+            let depths = if args.depth_normalize {
+                let (depths, min, max) = normalize_depth(&depths);
+                log::info!("Depth range {} to {}", min, max);
+                frame_metadata.push(("depth-min", min.to_string()));
+                frame_metadata.push(("depth-max", max.to_string()));
+                depths
+            } else {
+                let scale = 2.0 * tracer.infinity;
+                depths.iter().map(|d| d / scale).collect()
+            };
+            RenderBuffer::from_grey(width, height, &depths)
+        }
+        Mode::NewtonCost => {
+            let costs =
+                tracer.render_newton_cost(conf, args.tilt, args.turn, args.pan, args.step_size);
+            let max_iters = costs.iter().map(|(iters, _)| *iters).max().unwrap_or(0);
+            let (total_iters, total_steps) = costs
+                .iter()
+                .fold((0, 0), |(i, s), (iters, steps)| (i + iters, s + steps));
+            log::info!(
+                "Newton iterations: up to {} per ray, {:.3} per step on average",
+                max_iters,
+                total_iters as f64 / total_steps.max(1) as f64
+            );
+            frame_metadata.push(("newton-max", max_iters.to_string()));
+            let values = costs
+                .iter()
+                .map(|(iters, _)| *iters as f64 / max_iters.max(1) as f64)
+                .collect::<Vec<_>>();
+            RenderBuffer::from_grey(width, height, &values)
+        }
+        Mode::StepDiff => {
+            let render = |step_size| {
+                tracer
+                    .render_buffer(conf, args.tilt, args.turn, args.pan, step_size)
+                    .finalize(ToneMap::None, 1.0)
+            };
+            let adaptive = render(None);
+            let fixed = render(Some(args.step_size.unwrap_or(REFERENCE_STEP)));
+            RenderBuffer::from_pixels(width, height, &difference(&adaptive, &fixed))
+        }
+    };
+    let mut buffer = match args.sharpen {
+        Some(amount) => unsharp_mask(&buffer, amount, args.sharpen_radius),
+        None => buffer,
+    };
+    if args.show_axes {
+        let camera = camera
+            .copied()
+            .unwrap_or_else(|| camera_frame(conf, args.tilt, args.turn, args.pan));
+        draw_axes(&mut buffer, &axis_markers(args, tracer, conf, &camera));
+    }
+    let (tone_map, gamma) = (conf.tone_map, conf.gamma);
+
+    // The output is the requested size, which the render may only
+    // partly fill.
+    let [r, g, b] = [0, 1, 2].map(|idx| args.letterbox_colour[idx]);
+    let fill = [r, g, b, u8::MAX];
+    let (out_width, out_height) = (args.width, args.height);
+
+    if args.bit_depth == 16 {
+        // Scale so that 255 maps to 65535.
+        let raw_image = letterbox(
+            &buffer.finalize_u16(tone_map, gamma),
+            width,
+            height,
+            out_width,
+            out_height,
+            fill.map(|c| c as u16 * 257),
+        );
+        let mut image =
+            ImageBuffer::<Rgba<u16>, _>::from_raw(out_width as u32, out_height as u32, raw_image)
+                .ok_or(anyhow!("Couldn't create image"))?;
+        // OpenGL uses inverted vertical axis.
+        flip_vertical_in_place(&mut image);
+        if args.embed_metadata {
+            // PNG stores 16-bit samples big-endian.
+            let bytes = image
+                .iter()
+                .flat_map(|c| c.to_be_bytes())
+                .collect::<Vec<_>>();
+            png_encoder(args, path, out_width, out_height, &frame_metadata)?
+                .write_header()?
+                .write_image_data(&bytes)?;
+        } else {
+            image.save(path)?;
+        }
+    } else {
+        let raw_image = letterbox(
+            &buffer.finalize_dithered(tone_map, gamma, dither(args)),
+            width,
+            height,
+            out_width,
+            out_height,
+            fill,
+        );
+        let mut image = RgbaImage::from_raw(out_width as u32, out_height as u32, raw_image)
+            .ok_or(anyhow!("Couldn't create image"))?;
+        flip_vertical_in_place(&mut image);
+        if args.embed_metadata {
+            png_encoder(args, path, out_width, out_height, &frame_metadata)?
+                .write_header()?
+                .write_image_data(&image)?;
+        } else {
+            image.save(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Set up a PNG encoder for the output format, with the render
+// parameters and any extra metadata as text chunks if requested.
+fn png_encoder(
+    args: &Args,
+    path: &str,
+    width: usize,
+    height: usize,
+    extra_metadata: &[(&'static str, String)],
+) -> Result<png::Encoder<'static, BufWriter<File>>> {
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        width as u32,
+        height as u32,
     );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(if args.bit_depth == 16 {
+        png::BitDepth::Sixteen
+    } else {
+        png::BitDepth::Eight
+    });
+    if args.embed_metadata {
+        for (key, value) in metadata(args)
+            .into_iter()
+            .chain(extra_metadata.iter().cloned())
+        {
+            encoder.add_text_chunk(key.to_string(), value)?;
+        }
+    }
+    Ok(encoder)
+}
+
+// An optional expression argument as metadata.
+fn expr_metadata(expr: &Option<String>) -> String {
+    expr.clone().unwrap_or("none".to_string())
+}
+
+// The parameters that determine the rendered image, keyed by their
+// command-line names.
+fn metadata(args: &Args) -> Vec<(&'static str, String)> {
+    let step_size = args
+        .step_size
+        .map_or("adaptive".to_string(), |s| s.to_string());
+    let clip_plane = args.clip_plane.as_ref().map_or("none".to_string(), |v| {
+        v.iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+    vec![
+        ("Software", "tray-racer-cli".to_string()),
+        ("env-map-pos", args.env_map_pos.clone()),
+        ("env-map-neg", args.env_map_neg.clone()),
+        ("mirror-env-maps", args.mirror_env_maps.to_string()),
+        ("env-map-layout", format!("{:?}", args.env_map_layout)),
+        ("equirect-env-maps", args.equirect_env_maps.to_string()),
+        ("env-map-filter", format!("{:?}", args.env_map_filter)),
+        ("width", args.width.to_string()),
+        ("height", args.height.to_string()),
+        (
+            "aspect",
+            args.aspect.map_or("none".to_string(), |a| a.to_string()),
+        ),
+        (
+            "letterbox-colour",
+            args.letterbox_colour
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        ("tilt", args.tilt.to_string()),
+        ("turn", args.turn.to_string()),
+        ("pan", args.pan.to_string()),
+        ("fov", args.fov.to_string()),
+        (
+            "fov-v",
+            args.fov_v.map_or("none".to_string(), |f| f.to_string()),
+        ),
+        ("radius", args.radius.to_string()),
+        ("smoothness", args.smoothness.to_string()),
+        (
+            "throats",
+            if args.throats.is_empty() {
+                "none".to_string()
+            } else {
+                args.throats
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            },
+        ),
+        ("throat-blend", args.throat_blend.to_string()),
+        ("infinity", args.infinity.to_string()),
+        ("clip-plane", clip_plane),
+        ("compensated-dist", args.compensated_dist.to_string()),
+        ("dist-expr", expr_metadata(&args.dist_expr)),
+        (
+            "black-hole",
+            args.black_hole
+                .map_or("none".to_string(), |r| r.to_string()),
+        ),
+        ("step-size", step_size),
+        ("integrator", format!("{:?}", args.integrator)),
+        (
+            "target-error",
+            args.target_error
+                .map_or("none".to_string(), |e| e.to_string()),
+        ),
+        ("step-prepass", args.step_prepass.to_string()),
+        ("mode", format!("{:?}", args.mode)),
+        ("antialias", format!("{:?}", args.antialias)),
+        ("aa-samples", args.aa_samples.to_string()),
+        ("filter", format!("{:?}", args.filter)),
+        ("filter-width", args.filter_width.to_string()),
+        ("handedness", format!("{:?}", args.handedness)),
+        ("offset-x", args.offset_x.to_string()),
+        ("offset-y", args.offset_y.to_string()),
+        ("tone-map", format!("{:?}", args.tone_map)),
+        ("gamma", args.gamma.to_string()),
+        ("dither", format!("{:?}", args.dither)),
+        (
+            "sharpen",
+            args.sharpen.map_or("none".to_string(), |a| a.to_string()),
+        ),
+        ("sharpen-radius", args.sharpen_radius.to_string()),
+        ("depth-normalize", args.depth_normalize.to_string()),
+        ("frames", args.frames.to_string()),
+        ("tilt-expr", expr_metadata(&args.tilt_expr)),
+        ("turn-expr", expr_metadata(&args.turn_expr)),
+        ("pan-expr", expr_metadata(&args.pan_expr)),
+        ("fov-expr", expr_metadata(&args.fov_expr)),
+        ("radius-expr", expr_metadata(&args.radius_expr)),
+        ("smoothness-expr", expr_metadata(&args.smoothness_expr)),
+        ("env-rot-start", args.env_rot_start.to_string()),
+        ("env-rot-end", args.env_rot_end.to_string()),
+        (
+            "constant-apparent-pan",
+            args.constant_apparent_pan.to_string(),
+        ),
+        ("show-axes", args.show_axes.to_string()),
+        (
+            "axes-colour",
+            args.axes_colour
+                .as_ref()
+                .map_or("default".to_string(), |c| {
+                    c.iter()
+                        .map(|c| c.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                }),
+        ),
+        (
+            "debug-face",
+            args.debug_face
+                .map_or("none".to_string(), |f| format!("{:?}", f)),
+        ),
+    ]
+}
+
+// Render the image a strip at a time, streaming each strip into a PNG
+// encoder, so that only one strip of the image is held in memory.
+fn render_frame_tiled(
+    args: &Args,
+    tracer: &Tracer,
+    conf: &CanvasConfig,
+    path: &str,
+    tile_size: usize,
+) -> Result<()> {
+    ensure!(tile_size >= 1, "Tile size must be at least 1");
+    ensure!(
+        args.mode == Mode::Colour && args.antialias == AntialiasMode::None,
+        "Tiled rendering only supports the colour mode without anti-aliasing"
+    );
+
+    let (width, height) = (conf.width, conf.height);
+    let mut writer = png_encoder(args, path, width, height, &[])?.write_header()?;
+    let mut stream = writer.stream_writer()?;
+
+    let (tone_map, gamma) = (conf.tone_map, conf.gamma);
+    // OpenGL uses inverted vertical axis, so we render strips from the
+    // bottom up, and write each strip's rows in reverse.
+    let starts = (0..height).step_by(tile_size).collect::<Vec<_>>();
+    for start in starts.into_iter().rev() {
+        let end = (start + tile_size).min(height);
+        let pixels = tracer.render_region(
+            conf,
+            args.tilt,
+            args.turn,
+            args.pan,
+            args.step_size,
+            start..end,
+        );
+        let buffer = RenderBuffer::from_pixels(width, end - start, &pixels);
+        let bytes = if args.bit_depth == 16 {
+            // PNG stores 16-bit samples big-endian.
+            buffer
+                .finalize_u16(tone_map, gamma)
+                .iter()
+                .flat_map(|c| c.to_be_bytes())
+                .collect()
+        } else {
+            buffer.finalize(tone_map, gamma)
+        };
+        for row in bytes.chunks(bytes.len() / (end - start)).rev() {
+            stream.write_all(row)?;
+        }
+        if args.progress {
+            for done in height - end + 1..=height - start {
+                draw_progress(done, height);
+            }
+        }
+    }
+    stream.finish()?;
+
+    Ok(())
+}
+
+// Draw a progress bar on stderr, redrawn only when the percentage
+// changes, and finished with a newline once all rows are done.
+fn draw_progress(done: usize, total: usize) {
+    const BAR_WIDTH: usize = 50;
+    let percent = done * 100 / total;
+    if done != total && percent == (done - 1) * 100 / total {
+        return;
+    }
+    let filled = done * BAR_WIDTH / total;
+    eprint!(
+        "\r[{}{}] {:3}%",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        percent
+    );
+    if done == total {
+        eprintln!();
+    }
+}
+
+// Absolute per-channel difference of two RGBA images, with opaque
+// alpha.
+fn difference(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.chunks(4)
+        .zip(b.chunks(4))
+        .flat_map(|(pa, pb)| {
+            [
+                pa[0].abs_diff(pb[0]),
+                pa[1].abs_diff(pb[1]),
+                pa[2].abs_diff(pb[2]),
+                u8::MAX,
+            ]
+        })
+        .collect()
+}
+
+////////////////////////////////////////////////////////////////////////
+// Ray export
+//
+
+fn export_rays(args: &Args, out: &str, rays_x: usize, rays_y: usize, keep_w: bool) -> Result<()> {
+    let tracer = build_tracer(args, false)?;
+
+    ensure_in_range("--rays-x", rays_x, 1..=1024)?;
+    ensure_in_range("--rays-y", rays_y, 1..=1024)?;
+    check_camera_args(args)?;
+
+    let conf = CanvasConfig {
+        width: rays_x,
+        height: rays_y,
+        aspect: 1.0,
+        fov_degrees: args.fov,
+        fov_v_degrees: args.fov_v,
+        antialias: Antialias::None,
+        filter: Filter::Box(1.0),
+        handedness: handedness(args),
+        pixel_offset: (args.offset_x, args.offset_y),
+        tone_map: ToneMap::None,
+        gamma: 1.0,
+    };
+    let paths = tracer.render_paths(&conf, args.tilt, args.turn, args.pan, args.step_size);
+    write_obj(out, &paths, keep_w)
+}
+
+// Write the paths as OBJ polylines.
+fn write_obj(out: &str, paths: &[Vec<Point4>], keep_w: bool) -> Result<()> {
+    let mut file = BufWriter::new(File::create(out)?);
+    writeln!(file, "# Geodesics traced by tray-racer")?;
+
+    // OBJ vertex indices are 1-based.
+    let mut base = 1;
+    for path in paths.iter() {
+        for p in path.iter() {
+            if keep_w {
+                writeln!(file, "v {} {} {} {}", p.x, p.y, p.z, p.w)?;
+            } else {
+                writeln!(file, "v {} {} {}", p.x, p.y, p.z)?;
+            }
+        }
+        write!(file, "l")?;
+        for idx in base..base + path.len() {
+            write!(file, " {}", idx)?;
+        }
+        writeln!(file)?;
+        base += path.len();
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Axes overlay: Markers where the world axis directions appear in the
+// image. The x, y and z directions are compared ignoring w, as the env
+// maps do, so rays ending on either side of the wormhole match. The w
+// axis is marked where it points at the camera, without lensing.
+//
+
+// Outer radius of the markers, and width of the rings, in pixels.
+const AXIS_MARKER_RADIUS: f64 = 4.0;
+const AXIS_RING_WIDTH: f64 = 1.5;
+
+struct AxisMarker {
+    pos: (f64, f64),
+    colour: [u8; 3],
+    positive: bool,
+}
+
+fn axis_markers(
+    args: &Args,
+    tracer: &Tracer,
+    conf: &CanvasConfig,
+    camera: &CameraFrame,
+) -> Vec<AxisMarker> {
+    let default_colours = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0]];
+    tracer
+        .axis_positions(conf, camera)
+        .into_iter()
+        .map(|p| AxisMarker {
+            pos: p.pos,
+            colour: args
+                .axes_colour
+                .as_ref()
+                .map_or(default_colours[p.axis], |c| [c[0], c[1], c[2]]),
+            positive: p.positive,
+        })
+        .collect()
+}
+
+// Draw the markers over the render, as dots for positive directions
+// and rings for negative ones.
+fn draw_axes(buffer: &mut RenderBuffer, markers: &[AxisMarker]) {
+    for marker in markers {
+        let (mx, my) = marker.pos;
+        let [r, g, b] = marker.colour.map(|c| c as f32 / u8::MAX as f32);
+        let inner = if marker.positive {
+            0.0
+        } else {
+            AXIS_MARKER_RADIUS - AXIS_RING_WIDTH
+        };
+        let x_range = (mx - AXIS_MARKER_RADIUS).ceil().max(0.0) as usize
+            ..=((mx + AXIS_MARKER_RADIUS).floor() as usize).min(buffer.width - 1);
+        let y_range = (my - AXIS_MARKER_RADIUS).ceil().max(0.0) as usize
+            ..=((my + AXIS_MARKER_RADIUS).floor() as usize).min(buffer.height - 1);
+        for y in y_range {
+            for x in x_range.clone() {
+                let dist = (x as f64 - mx).hypot(y as f64 - my);
+                if inner <= dist && dist <= AXIS_MARKER_RADIUS {
+                    buffer.set(x, y, [r, g, b, 1.0]);
+                }
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Direction field export: Ray directions written as a NumPy array.
+//
+
+// Write the directions, given bottom row first as rendered, as a
+// version 1.0 .npy file of little-endian float64s.
+fn write_direction_field(
+    out: &str,
+    width: usize,
+    height: usize,
+    dirs: &[Option<Dir4>],
+) -> Result<()> {
+    assert_eq!(dirs.len(), width * height);
+    let mut file = BufWriter::new(File::create(out)?);
+
+    // The header is a Python dict literal, padded with spaces and
+    // ending in a newline so that the data is 64-byte aligned.
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}, 4), }}",
+        height, width
+    );
+    const PREAMBLE_LEN: usize = 10;
+    let padding = 63 - (PREAMBLE_LEN + header.len()) % 64;
+    header.push_str(&" ".repeat(padding));
+    header.push('\n');
+    file.write_all(b"\x93NUMPY\x01\x00")?;
+    file.write_all(&(header.len() as u16).to_le_bytes())?;
+    file.write_all(header.as_bytes())?;
 
-    let mut image = RgbaImage::from_raw(width as u32, height as u32, raw_image)
-        .ok_or(anyhow!("Couldn't create image"))?;
     // OpenGL uses inverted vertical axis.
-    flip_vertical_in_place(&mut image);
-    image.save(args.output)?;
+    for row in dirs.chunks(width).rev() {
+        for dir in row.iter() {
+            let values = dir.map_or([f64::NAN; 4], |d| [d.x, d.y, d.z, d.w]);
+            for value in values {
+                file.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Cube map export
+//
+
+fn render_cubemap(args: &Args, out: &str, size: usize, layout: CubemapLayout) -> Result<()> {
+    let tracer = build_tracer(args, true)?;
+
+    ensure_in_range("--size", size, 16..=16384)?;
+    check_camera_args(args)?;
+
+    // Cube map images are read top-down, so no flips are needed.
+    let Some(layout) = cube_layout(layout) else {
+        let out_dir = Path::new(out);
+        std::fs::create_dir_all(out_dir)?;
+        for face in Face::ALL {
+            let raw_image = tracer.render_cube_face(face, size, args.pan, args.step_size);
+            save_cubemap_image(raw_image, size, size, &out_dir.join(face.file_name()))?;
+        }
+        return Ok(());
+    };
+    let (raw_image, width, height) =
+        tracer.render_cube_layout(layout, size, args.pan, args.step_size);
+    save_cubemap_image(raw_image, width, height, Path::new(out))
+}
+
+// The single-image layout, or None for separate files.
+fn cube_layout(layout: CubemapLayout) -> Option<CubeLayout> {
+    match layout {
+        CubemapLayout::Files => None,
+        CubemapLayout::Cross => Some(CubeLayout::Cross),
+        CubemapLayout::VerticalCross => Some(CubeLayout::VerticalCross),
+        CubemapLayout::Strip => Some(CubeLayout::Strip),
+    }
+}
+
+fn save_cubemap_image(raw_image: Vec<u8>, width: usize, height: usize, path: &Path) -> Result<()> {
+    let image = RgbaImage::from_raw(width as u32, height as u32, raw_image)
+        .ok_or(anyhow!("Couldn't create image"))?;
+    let is_jpeg = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"));
+    if is_jpeg {
+        // JPEG has no alpha channel.
+        DynamicImage::ImageRgba8(image).into_rgb8().save(path)?;
+    } else {
+        image.save(path)?;
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Env map diagnostics
+//
+
+fn list_faces(dir: &Path) -> Result<()> {
+    let mut problems = Vec::new();
+    for face in Face::ALL {
+        let Some(path) = face.find_file(dir) else {
+            println!("{}: missing", face.name());
+            problems.push(face.name());
+            continue;
+        };
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        // Report the header before fully decoding, so that a file
+        // with a bad body still gives its format and size.
+        let format = image::ImageReader::open(&path)?
+            .with_guessed_format()?
+            .format()
+            .map_or("unknown format".to_string(), |f| format!("{:?}", f));
+        let size = image::image_dimensions(&path)
+            .map_or("unknown size".to_string(), |(w, h)| format!("{}x{}", w, h));
+        let status = match image::open(&path) {
+            std::result::Result::Ok(_) => "loads".to_string(),
+            Err(err) => {
+                problems.push(face.name());
+                format!("fails to load: {}", err)
+            }
+        };
+        println!("{}: {}, {}, {}", file_name, format, size, status);
+    }
+
+    ensure!(
+        problems.is_empty(),
+        "Env map in {} has missing or unreadable faces: {}",
+        dir.display(),
+        problems.join(", ")
+    );
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Compositing
+//
+
+// Porter-Duff "over" of a pixel onto another, with straight (not
+// premultiplied) alpha, as PNG stores. Done on the encoded values, as
+// image editors do.
+fn alpha_over(fg: [u8; 4], bg: [u8; 4]) -> [u8; 4] {
+    let fg_alpha = fg[3] as f32 / 255.0;
+    let bg_alpha = bg[3] as f32 / 255.0 * (1.0 - fg_alpha);
+    let alpha = fg_alpha + bg_alpha;
+    if alpha == 0.0 {
+        return [0; 4];
+    }
+    let mut result = [0; 4];
+    for (channel, (f, b)) in result.iter_mut().zip(fg.iter().zip(bg.iter())).take(3) {
+        let value = (*f as f32 * fg_alpha + *b as f32 * bg_alpha) / alpha;
+        *channel = value.round().clamp(0.0, 255.0) as u8;
+    }
+    result[3] = (alpha * 255.0).round() as u8;
+    result
+}
 
+fn composite(over: &str, under: &str, out: &str) -> Result<()> {
+    let fg = image::open(over)?.into_rgba8();
+    let bg = image::open(under)?.into_rgba8();
+    ensure!(
+        fg.dimensions() == bg.dimensions(),
+        "Can't composite {} ({}x{}) over {} ({}x{}): sizes differ",
+        over,
+        fg.width(),
+        fg.height(),
+        under,
+        bg.width(),
+        bg.height()
+    );
+    let (width, height) = fg.dimensions();
+    let composited = RgbaImage::from_fn(width, height, |x, y| {
+        Rgba(alpha_over(fg.get_pixel(x, y).0, bg.get_pixel(x, y).0))
+    });
+    composited.save(out)?;
     Ok(())
 }
+
+////////////////////////////////////////////////////////////////////////
+// Parameter solver
+//
+
+// Range searched for each parameter, within the range the
+// command-line arguments allow, avoiding the extremes where the
+// tracer fails to extend paths.
+fn param_range(param: SolveParam) -> (f64, f64) {
+    match param {
+        SolveParam::Radius => (0.05, 0.8),
+        SolveParam::Smoothness => (0.15, 1.0),
+    }
+}
+
+fn set_param(tracer: &mut Tracer, param: SolveParam, value: f64) {
+    match param {
+        SolveParam::Radius => tracer.radius = value,
+        SolveParam::Smoothness => tracer.w_scale = value,
+    }
+}
+
+// Bisection steps before giving up on hitting the tolerance.
+const SOLVE_MAX_ITERS: usize = 50;
+
+fn solve(
+    args: &Args,
+    target: SolveTarget,
+    value: f64,
+    vary: SolveParam,
+    tolerance: f64,
+) -> Result<()> {
+    let mut tracer = build_tracer(args, false)?;
+    check_camera_args(args)?;
+
+    let conf = CanvasConfig {
+        width: args.width,
+        height: args.height,
+        aspect: 1.0,
+        fov_degrees: args.fov,
+        fov_v_degrees: args.fov_v,
+        antialias: Antialias::None,
+        filter: Filter::Box(1.0),
+        handedness: handedness(args),
+        pixel_offset: (0.0, 0.0),
+        tone_map: ToneMap::None,
+        gamma: 1.0,
+    };
+
+    if target == SolveTarget::RingRadius && value >= (args.width / 2) as f64 {
+        bail!(
+            "Target {} not achievable: ring would be larger than the image",
+            value
+        );
+    }
+
+    let (mut lo, mut hi) = param_range(vary);
+    let mut measure = |param: f64| {
+        set_param(&mut tracer, vary, param);
+        match target {
+            // No ring counts as a radius of 0. A ring off the edge of
+            // the image measures half the image's width, which is
+            // bigger than any achievable target.
+            SolveTarget::RingRadius => tracer.ring_radius(&conf, args.step_size).unwrap_or(0.0),
+        }
+    };
+
+    let (f_lo, f_hi) = (measure(lo) - value, measure(hi) - value);
+    if f_lo.signum() == f_hi.signum() {
+        bail!(
+            "Target {} not achievable: {:?} ranges from {} to {} over {:?} {}..{}",
+            value,
+            target,
+            f_lo + value,
+            f_hi + value,
+            vary,
+            lo,
+            hi
+        );
+    }
+
+    for _ in 0..SOLVE_MAX_ITERS {
+        let mid = 0.5 * (lo + hi);
+        let f_mid = measure(mid) - value;
+        if f_mid.abs() <= tolerance {
+            println!("{:?} = {} ({:?} = {})", vary, mid, target, f_mid + value);
+            return Ok(());
+        }
+        if f_mid.signum() == f_lo.signum() {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    bail!("Failed to converge within {} iterations", SOLVE_MAX_ITERS)
+}
+
+////////////////////////////////////////////////////////////////////////
+// Self-test: Numerical invariants of the solver, each measured as a
+// residual and compared against a tolerance, so that a build can be
+// checked on new hardware and drift is visible even when passing.
+//
+
+// Step used for chords when checking normals.
+const SELFTEST_CHORD: f64 = 1.0e-4;
+// Grid of rays compared between the tracers.
+const SELFTEST_RAYS: (usize, usize) = (16, 12);
+
+// Surface points to check, from a grid on either side of the
+// wormhole, skipping points over the throat.
+fn selftest_points(tracer: &Tracer) -> Vec<Point4> {
+    let coords = (0..9).map(|i| -2.0 + i as f64 * 0.5);
+    coords
+        .clone()
+        .flat_map(|x| coords.clone().map(move |z| (x, z)))
+        .flat_map(|(x, z)| [1.0, -1.0].map(|w| Point4 { x, y: 0.3, z, w }))
+        .filter_map(|p| tracer.project_vertical(p))
+        .collect()
+}
+
+// Largest |dist| of projected points, which should lie on the surface.
+fn check_projection(tracer: &Tracer) -> f64 {
+    selftest_points(tracer)
+        .iter()
+        .map(|p| tracer.dist(*p).abs())
+        .fold(0.0, f64::max)
+}
+
+// Largest cosine between the normal and short chords across the
+// surface, which should be perpendicular up to the chord's curvature.
+fn check_normals(tracer: &Tracer) -> f64 {
+    let axes = [(1.0, 0.0, 0.0), (0.0, 1.0, 0.0), (0.0, 0.0, 1.0)];
+    selftest_points(tracer)
+        .iter()
+        .flat_map(|p| {
+            let norm = tracer.normal_at(*p).norm();
+            axes.iter().filter_map(move |(x, y, z)| {
+                let offset = Dir4 {
+                    x: *x,
+                    y: *y,
+                    z: *z,
+                    w: 0.0,
+                };
+                let q = tracer.project_vertical(p.add(offset.scale(SELFTEST_CHORD)))?;
+                Some(q.sub(*p).norm().dot(norm).abs())
+            })
+        })
+        .fold(0.0, f64::max)
+}
+
+// Largest angle, in radians, between the initial and final directions
+// of rays traced through flat space, the w = 0 hyperplane.
+fn check_flat(tracer: &Tracer, conf: &CanvasConfig) -> f64 {
+    let flat = Tracer {
+        env_map_pos: tracer.env_map_pos.clone(),
+        env_map_neg: tracer.env_map_neg.clone(),
+        clip_plane: None,
+        dist_expr: Some(Expr::parse("w", &DIST_EXPR_VARS).unwrap()),
+        extra_throats: Vec::new(),
+        black_hole: None,
+        ..*tracer
+    };
+    selftest_rays(conf)
+        .iter()
+        .flat_map(|(origin, dir)| {
+            [None, Some(0.01)].map(|step_size| {
+                let geom = flat.trace_geometry(*origin, *dir, step_size);
+                geom.final_dir
+                    .norm()
+                    .dot(dir.norm())
+                    .clamp(-1.0, 1.0)
+                    .acos()
+            })
+        })
+        .fold(0.0, f64::max)
+}
+
+// Mean angle, in radians, between the final directions from the
+// adaptive and fixed-step tracers. The mean is used as rays grazing
+// the throat may legitimately diverge.
+fn check_tracers_agree(tracer: &Tracer, conf: &CanvasConfig) -> f64 {
+    let rays = selftest_rays(conf);
+    let total = rays
+        .iter()
+        .map(|(origin, dir)| {
+            let adaptive = tracer.trace_geometry(*origin, *dir, None).final_dir;
+            let fixed = tracer
+                .trace_geometry(*origin, *dir, Some(REFERENCE_STEP))
+                .final_dir;
+            adaptive.norm().dot(fixed.norm()).clamp(-1.0, 1.0).acos()
+        })
+        .sum::<f64>();
+    total / rays.len() as f64
+}
+
+// A sparse grid of rays over the camera's view.
+fn selftest_rays(conf: &CanvasConfig) -> Vec<(Point4, Dir4)> {
+    let (rays_x, rays_y) = SELFTEST_RAYS;
+    let pos = |i: usize, rays: usize, size: usize| (i as f64 + 0.5) / rays as f64 * size as f64;
+    (0..rays_y)
+        .flat_map(|y| (0..rays_x).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            camera_ray(
+                conf,
+                0.0,
+                0.0,
+                0.0,
+                pos(x, rays_x, conf.width) - 0.5,
+                pos(y, rays_y, conf.height) - 0.5,
+            )
+        })
+        .collect()
+}
+
+fn selftest(args: &Args) -> Result<()> {
+    let tracer = build_tracer(args, false)?;
+    let conf = CanvasConfig {
+        width: args.width,
+        height: args.height,
+        aspect: 1.0,
+        fov_degrees: args.fov,
+        fov_v_degrees: args.fov_v,
+        antialias: Antialias::None,
+        filter: Filter::Box(1.0),
+        handedness: handedness(args),
+        pixel_offset: (0.0, 0.0),
+        tone_map: ToneMap::None,
+        gamma: 1.0,
+    };
+
+    let checks: [(&str, f64, &dyn Fn() -> f64); 4] = [
+        ("Projected points lie on the surface", 1.0e-6, &|| {
+            check_projection(&tracer)
+        }),
+        ("Normals are perpendicular to the surface", 1.0e-3, &|| {
+            check_normals(&tracer)
+        }),
+        ("Rays go straight in flat space", 1.0e-6, &|| {
+            check_flat(&tracer, &conf)
+        }),
+        ("Adaptive and fixed-step tracers agree", 5.0e-3, &|| {
+            check_tracers_agree(&tracer, &conf)
+        }),
+    ];
+
+    let mut failures = 0;
+    for (name, tolerance, check) in checks {
+        let residual = check();
+        let passed = residual <= tolerance;
+        if !passed {
+            failures += 1;
+        }
+        println!(
+            "{} {}: residual {:.3e} (tolerance {:.0e})",
+            if passed { "PASS" } else { "FAIL" },
+            name,
+            residual,
+            tolerance
+        );
+    }
+    ensure!(failures == 0, "{} self-test check(s) failed", failures);
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Benchmark: Rays per second on the given scene, for comparing
+// hardware, builds and parameter regimes.
+//
+
+fn bench(
+    args: &Args,
+    size: usize,
+    runs: usize,
+    sweep: Option<SolveParam>,
+    sweep_steps: usize,
+) -> Result<()> {
+    let mut tracer = build_tracer(args, true)?;
+    check_camera_args(args)?;
+    ensure_in_range("--size", size, 16..=4096)?;
+    ensure_in_range("--runs", runs, 1..=1000)?;
+    ensure_in_range("--sweep-steps", sweep_steps, 2..=100)?;
+
+    let conf = CanvasConfig {
+        width: size,
+        height: size * 3 / 4,
+        aspect: 1.0,
+        fov_degrees: args.fov,
+        fov_v_degrees: args.fov_v,
+        antialias: Antialias::None,
+        filter: Filter::Box(1.0),
+        handedness: handedness(args),
+        pixel_offset: (0.0, 0.0),
+        tone_map: ToneMap::None,
+        gamma: 1.0,
+    };
+
+    let values = match sweep {
+        Some(param) => {
+            let (lo, hi) = param_range(param);
+            (0..sweep_steps)
+                .map(|idx| lo + (hi - lo) * idx as f64 / (sweep_steps - 1) as f64)
+                .collect()
+        }
+        None => vec![f64::NAN],
+    };
+    for value in values {
+        let label = match sweep {
+            Some(param) => {
+                set_param(&mut tracer, param, value);
+                format!("{:?} {:.3}: ", param, value)
+            }
+            None => String::new(),
+        };
+        let (mean, median, std_dev) = bench_rays_per_sec(&tracer, args, &conf, runs);
+        println!(
+            "{}{:.0} rays/s (median {:.0}, std dev {:.0}), {:.1} steps/ray",
+            label,
+            mean,
+            median,
+            std_dev,
+            mean_steps(&tracer, args, &conf)
+        );
+    }
+
+    Ok(())
+}
+
+// Mean, median and standard deviation of the rays per second over
+// the timed runs, after an untimed warm-up render.
+fn bench_rays_per_sec(
+    tracer: &Tracer,
+    args: &Args,
+    conf: &CanvasConfig,
+    runs: usize,
+) -> (f64, f64, f64) {
+    let render = || tracer.render(conf, args.tilt, args.turn, args.pan, args.step_size, None);
+    render();
+    let rays = (conf.width * conf.height) as f64;
+    let mut rates = (0..runs)
+        .map(|_| {
+            let start = std::time::Instant::now();
+            render();
+            rays / start.elapsed().as_secs_f64()
+        })
+        .collect::<Vec<_>>();
+    rates.sort_by(f64::total_cmp);
+
+    let n = rates.len() as f64;
+    let mean = rates.iter().sum::<f64>() / n;
+    let mid = rates.len() / 2;
+    let median = if rates.len() % 2 == 0 {
+        0.5 * (rates[mid - 1] + rates[mid])
+    } else {
+        rates[mid]
+    };
+    let variance = rates.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / n;
+    (mean, median, variance.sqrt())
+}
+
+// Mean number of steps taken per ray, as a measure of the work done
+// independent of the hardware.
+fn mean_steps(tracer: &Tracer, args: &Args, conf: &CanvasConfig) -> f64 {
+    let steps = (0..conf.height)
+        .flat_map(|y| (0..conf.width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let (origin, dir) =
+                camera_ray(conf, args.tilt, args.turn, args.pan, x as f64, y as f64);
+            tracer.trace_geometry(origin, dir, args.step_size).steps
+        })
+        .sum::<usize>();
+    steps as f64 / (conf.width * conf.height) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tracer with a different gradient on each face of each env map.
+    fn test_tracer(args: &Args) -> Tracer {
+        let env_map = |flip: u32| {
+            let faces = std::array::from_fn(|face| {
+                RgbaImage::from_fn(8, 8, |x, y| {
+                    let (x, y) = (x ^ flip, y ^ flip);
+                    Rgba([(x * 32) as u8, (y * 32) as u8, (face * 40) as u8, 255])
+                })
+            });
+            Arc::new(EnvMap::from_images(faces).unwrap())
+        };
+        Tracer::new(
+            env_map(0),
+            env_map(7),
+            args.smoothness,
+            args.radius,
+            args.infinity,
+        )
+    }
+
+    fn test_conf(args: &Args) -> CanvasConfig {
+        CanvasConfig {
+            width: args.width,
+            height: args.height,
+            aspect: 1.0,
+            fov_degrees: args.fov,
+            fov_v_degrees: args.fov_v,
+            antialias: Antialias::None,
+            filter: filter(args).unwrap(),
+            handedness: handedness(args),
+            pixel_offset: (0.0, 0.0),
+            tone_map: tone_map(args),
+            gamma: args.gamma as f32,
+        }
+    }
+
+    // A path for a test's output, unique to the test and process.
+    fn temp_path(name: &str) -> String {
+        let file = format!("tray-racer-cli-{}-{}", std::process::id(), name);
+        std::env::temp_dir()
+            .join(file)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn tiled_render_matches_untiled() {
+        for bit_depth in ["8", "16"] {
+            let args = Args::parse_from([
+                "tray-racer-cli",
+                "-o",
+                "unused.png",
+                "-w",
+                "24",
+                "-h",
+                "20",
+                "--turn=20",
+                "--tone-map",
+                "reinhard",
+                "--gamma",
+                "2.2",
+                "--bit-depth",
+                bit_depth,
+            ]);
+            let tiled_args = Args {
+                tile_size: Some(7),
+                ..args.clone()
+            };
+            let (tracer, conf) = (test_tracer(&args), test_conf(&args));
+            let untiled_path = temp_path(&format!("untiled-{bit_depth}.png"));
+            let tiled_path = temp_path(&format!("tiled-{bit_depth}.png"));
+            render_frame(&args, &tracer, &conf, &untiled_path, None).unwrap();
+            render_frame(&tiled_args, &tracer, &conf, &tiled_path, None).unwrap();
+
+            let read = |path: &str| image::open(path).unwrap().into_rgba16().into_raw();
+            let (untiled, tiled) = (read(&untiled_path), read(&tiled_path));
+            std::fs::remove_file(&untiled_path).unwrap();
+            std::fs::remove_file(&tiled_path).unwrap();
+            assert_eq!(untiled, tiled, "bit depth {bit_depth}");
+        }
+    }
+
+    // Read back the polylines written by `write_obj`, with w as 0 if
+    // it wasn't written.
+    fn read_obj(path: &str) -> Vec<Vec<Point4>> {
+        let mut vertices = Vec::new();
+        let mut paths = Vec::new();
+        for line in std::fs::read_to_string(path).unwrap().lines() {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("v") => {
+                    let mut coords = fields
+                        .map(|f| f.parse::<f64>().unwrap())
+                        .collect::<Vec<_>>();
+                    coords.resize(4, 0.0);
+                    vertices.push(Point4 {
+                        x: coords[0],
+                        y: coords[1],
+                        z: coords[2],
+                        w: coords[3],
+                    });
+                }
+                Some("l") => paths.push(
+                    fields
+                        .map(|f| vertices[f.parse::<usize>().unwrap() - 1])
+                        .collect(),
+                ),
+                _ => {}
+            }
+        }
+        paths
+    }
+
+    #[test]
+    fn obj_round_trips() {
+        let point = |x: f64, y: f64, z: f64, w: f64| Point4 { x, y, z, w };
+        let paths = vec![
+            vec![point(0.0, 0.0, -1.0, 1.0), point(0.1, -0.2, -0.9, 0.75)],
+            vec![point(1.0 / 3.0, 2.5e-7, 1.0e10, -0.125)],
+            vec![
+                point(-1.0, 0.0, 0.0, 0.5),
+                point(-0.5, 0.0, 0.0, 0.0),
+                point(0.0, 0.0, 0.0, -0.5),
+            ],
+        ];
+        for keep_w in [true, false] {
+            let path = temp_path(&format!("paths-{keep_w}.obj"));
+            write_obj(&path, &paths, keep_w).unwrap();
+            let read = read_obj(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            let expected = paths
+                .iter()
+                .map(|path| {
+                    path.iter()
+                        .map(|p| Point4 {
+                            w: if keep_w { p.w } else { 0.0 },
+                            ..*p
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(read, expected, "keep_w {keep_w}");
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//
+// expr.rs: A small evaluator for arithmetic expressions in named
+// variables, so that parameters can be given as formulae.
+//
+// Supports numbers, the operators + - * / ^ (power, right
+// associative), parentheses, the constants pi and e, and the functions
+// sin, cos, tan, asin, acos, atan, sqrt, abs, exp, ln, floor, min and
+// max. Constant sub-expressions are folded when parsing, and the
+// result is compiled to a sequence of stack operations, so that
+// evaluation is quick enough to use in the tracer's inner loop.
+//
+
+use anyhow::{bail, Result};
+
+// Deepest evaluation stack an expression may need, so that the stack
+// can live in a fixed-size array.
+const MAX_STACK: usize = 32;
+
+// A parsed expression. Variables are referred to by their index in
+// the names given to `parse`.
+#[derive(Clone, Debug)]
+pub struct Expr {
+    ops: Vec<Op>,
+}
+
+// Stack operations an expression compiles to, in postfix order.
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Const(f64),
+    Var(usize),
+    Neg,
+    Binary(BinaryOp),
+    // Power by a constant integer, which is much quicker than powf.
+    PowInt(i32),
+    Call(Func),
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Const(f64),
+    Var(usize),
+    Neg(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    Call(Func, Vec<Node>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Func {
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Sqrt,
+    Abs,
+    Exp,
+    Ln,
+    Floor,
+    Min,
+    Max,
+}
+
+const FUNCS: [(&str, Func); 13] = [
+    ("sin", Func::Sin),
+    ("cos", Func::Cos),
+    ("tan", Func::Tan),
+    ("asin", Func::Asin),
+    ("acos", Func::Acos),
+    ("atan", Func::Atan),
+    ("sqrt", Func::Sqrt),
+    ("abs", Func::Abs),
+    ("exp", Func::Exp),
+    ("ln", Func::Ln),
+    ("floor", Func::Floor),
+    ("min", Func::Min),
+    ("max", Func::Max),
+];
+
+impl BinaryOp {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+            BinaryOp::Pow => a.powf(b),
+        }
+    }
+}
+
+impl Func {
+    fn arity(self) -> usize {
+        match self {
+            Func::Min | Func::Max => 2,
+            _ => 1,
+        }
+    }
+
+    // Apply to the arguments, of which there are `arity`.
+    fn apply(self, args: &[f64]) -> f64 {
+        let a = args[0];
+        match self {
+            Func::Sin => a.sin(),
+            Func::Cos => a.cos(),
+            Func::Tan => a.tan(),
+            Func::Asin => a.asin(),
+            Func::Acos => a.acos(),
+            Func::Atan => a.atan(),
+            Func::Sqrt => a.sqrt(),
+            Func::Abs => a.abs(),
+            Func::Exp => a.exp(),
+            Func::Ln => a.ln(),
+            Func::Floor => a.floor(),
+            Func::Min => a.min(args[1]),
+            Func::Max => a.max(args[1]),
+        }
+    }
+}
+
+impl Node {
+    // Evaluate the tree directly, for constant folding.
+    fn eval(&self, values: &[f64]) -> f64 {
+        match self {
+            Node::Const(c) => *c,
+            Node::Var(idx) => values[*idx],
+            Node::Neg(a) => -a.eval(values),
+            Node::Binary(op, a, b) => op.apply(a.eval(values), b.eval(values)),
+            Node::Call(func, args) => {
+                func.apply(&args.iter().map(|a| a.eval(values)).collect::<Vec<_>>())
+            }
+        }
+    }
+
+    // Append the node's operations to `ops`, returning the stack depth
+    // it needs.
+    fn compile(&self, ops: &mut Vec<Op>) -> usize {
+        match self {
+            Node::Const(c) => {
+                ops.push(Op::Const(*c));
+                1
+            }
+            Node::Var(idx) => {
+                ops.push(Op::Var(*idx));
+                1
+            }
+            Node::Neg(a) => {
+                let depth = a.compile(ops);
+                ops.push(Op::Neg);
+                depth
+            }
+            Node::Binary(op, a, b) => {
+                let depth = a.compile(ops);
+                match (op, small_int(b)) {
+                    (BinaryOp::Pow, Some(exponent)) => {
+                        ops.push(Op::PowInt(exponent));
+                        depth
+                    }
+                    _ => {
+                        let depth = depth.max(1 + b.compile(ops));
+                        ops.push(Op::Binary(*op));
+                        depth
+                    }
+                }
+            }
+            Node::Call(func, args) => {
+                let depth = args
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, arg)| idx + arg.compile(ops))
+                    .max()
+                    .unwrap_or(0);
+                ops.push(Op::Call(*func));
+                depth
+            }
+        }
+    }
+
+    // Replace the node with a constant if it doesn't use any
+    // variables. Children are folded as they're built, so only this
+    // node needs checking.
+    fn fold(self) -> Node {
+        let constant = match &self {
+            Node::Const(_) | Node::Var(_) => false,
+            Node::Neg(a) => matches!(**a, Node::Const(_)),
+            Node::Binary(_, a, b) => matches!(**a, Node::Const(_)) && matches!(**b, Node::Const(_)),
+            Node::Call(_, args) => args.iter().all(|a| matches!(a, Node::Const(_))),
+        };
+        if constant {
+            Node::Const(self.eval(&[]))
+        } else {
+            self
+        }
+    }
+}
+
+// The node's value, if it's a constant integer small enough for
+// powi.
+fn small_int(node: &Node) -> Option<i32> {
+    match node {
+        Node::Const(c) if c.fract() == 0.0 && c.abs() <= 64.0 => Some(*c as i32),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(f64),
+    Ident(String),
+    Op(char),
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>> {
+    let chars = src.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut idx = 0;
+    while idx < chars.len() {
+        let c = chars[idx];
+        if c.is_whitespace() {
+            idx += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = idx;
+            while idx < chars.len() && (chars[idx].is_ascii_digit() || chars[idx] == '.') {
+                idx += 1;
+            }
+            // An exponent, if digits follow.
+            if idx < chars.len() && (chars[idx] == 'e' || chars[idx] == 'E') {
+                let mut end = idx + 1;
+                if end < chars.len() && (chars[end] == '+' || chars[end] == '-') {
+                    end += 1;
+                }
+                if end < chars.len() && chars[end].is_ascii_digit() {
+                    idx = end;
+                    while idx < chars.len() && chars[idx].is_ascii_digit() {
+                        idx += 1;
+                    }
+                }
+            }
+            let text = chars[start..idx].iter().collect::<String>();
+            match text.parse() {
+                Ok(num) => tokens.push(Token::Num(num)),
+                Err(_) => bail!("Bad number '{}' in '{}'", text, src),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let start = idx;
+            while idx < chars.len() && (chars[idx].is_alphanumeric() || chars[idx] == '_') {
+                idx += 1;
+            }
+            tokens.push(Token::Ident(chars[start..idx].iter().collect()));
+        } else if "+-*/^(),".contains(c) {
+            tokens.push(Token::Op(c));
+            idx += 1;
+        } else {
+            bail!("Unexpected '{}' in '{}'", c, src);
+        }
+    }
+    Ok(tokens)
+}
+
+// Recursive descent parser over the tokens.
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    vars: &'a [&'a str],
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    // Consume the operator if it's next.
+    fn accept(&mut self, op: char) -> bool {
+        let found = self.peek() == Some(&Token::Op(op));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, op: char) -> Result<()> {
+        if !self.accept(op) {
+            bail!("Expected '{}' in '{}'", op, self.src);
+        }
+        Ok(())
+    }
+
+    // Sums and differences.
+    fn expr(&mut self) -> Result<Node> {
+        let mut node = self.term()?;
+        loop {
+            let op = if self.accept('+') {
+                BinaryOp::Add
+            } else if self.accept('-') {
+                BinaryOp::Sub
+            } else {
+                return Ok(node);
+            };
+            node = Node::Binary(op, Box::new(node), Box::new(self.term()?)).fold();
+        }
+    }
+
+    // Products and quotients.
+    fn term(&mut self) -> Result<Node> {
+        let mut node = self.unary()?;
+        loop {
+            let op = if self.accept('*') {
+                BinaryOp::Mul
+            } else if self.accept('/') {
+                BinaryOp::Div
+            } else {
+                return Ok(node);
+            };
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?)).fold();
+        }
+    }
+
+    // Signs, which bind less tightly than powers, so -2^2 is -4.
+    fn unary(&mut self) -> Result<Node> {
+        if self.accept('-') {
+            Ok(Node::Neg(Box::new(self.unary()?)).fold())
+        } else if self.accept('+') {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<Node> {
+        let base = self.atom()?;
+        if self.accept('^') {
+            let exponent = self.unary()?;
+            Ok(Node::Binary(BinaryOp::Pow, Box::new(base), Box::new(exponent)).fold())
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(num)) => Ok(Node::Const(num)),
+            Some(Token::Op('(')) => {
+                let node = self.expr()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(Token::Ident(name)) => self.ident(&name),
+            Some(Token::Op(op)) => bail!("Unexpected '{}' in '{}'", op, self.src),
+            None => bail!("Unexpected end of '{}'", self.src),
+        }
+    }
+
+    // A variable, constant or function call. Variables take priority,
+    // so they may shadow the constants.
+    fn ident(&mut self, name: &str) -> Result<Node> {
+        if let Some(idx) = self.vars.iter().position(|v| *v == name) {
+            return Ok(Node::Var(idx));
+        }
+        match name {
+            "pi" => return Ok(Node::Const(std::f64::consts::PI)),
+            "e" => return Ok(Node::Const(std::f64::consts::E)),
+            _ => {}
+        }
+        let Some(&(_, func)) = FUNCS.iter().find(|(n, _)| *n == name) else {
+            bail!("Unknown name '{}' in '{}'", name, self.src);
+        };
+        self.expect('(')?;
+        let mut args = vec![self.expr()?];
+        while self.accept(',') {
+            args.push(self.expr()?);
+        }
+        self.expect(')')?;
+        if args.len() != func.arity() {
+            bail!(
+                "'{}' takes {} argument(s), got {} in '{}'",
+                name,
+                func.arity(),
+                args.len(),
+                self.src
+            );
+        }
+        Ok(Node::Call(func, args).fold())
+    }
+}
+
+impl Expr {
+    // Parse an expression in the named variables.
+    pub fn parse(src: &str, vars: &[&str]) -> Result<Expr> {
+        let mut parser = Parser {
+            src,
+            tokens: tokenize(src)?,
+            pos: 0,
+            vars,
+        };
+        let root = parser.expr()?;
+        if parser.peek().is_some() {
+            bail!("Unexpected trailing input in '{}'", src);
+        }
+        let mut ops = Vec::new();
+        if root.compile(&mut ops) > MAX_STACK {
+            bail!("'{}' is too deeply nested", src);
+        }
+        Ok(Expr { ops })
+    }
+
+    // Evaluate with the variables' values, in the order their names
+    // were given to `parse`.
+    pub fn eval(&self, values: &[f64]) -> f64 {
+        let mut stack = [0.0; MAX_STACK];
+        let mut len = 0;
+        for op in self.ops.iter() {
+            match *op {
+                Op::Const(c) => {
+                    stack[len] = c;
+                    len += 1;
+                }
+                Op::Var(idx) => {
+                    stack[len] = values[idx];
+                    len += 1;
+                }
+                Op::Neg => stack[len - 1] = -stack[len - 1],
+                Op::Binary(op) => {
+                    len -= 1;
+                    stack[len - 1] = op.apply(stack[len - 1], stack[len]);
+                }
+                Op::PowInt(exponent) => stack[len - 1] = stack[len - 1].powi(exponent),
+                Op::Call(func) => {
+                    let arity = func.arity();
+                    len -= arity - 1;
+                    stack[len - 1] = func.apply(&stack[len - 1..len - 1 + arity]);
+                }
+            }
+        }
+        stack[0]
+    }
+}
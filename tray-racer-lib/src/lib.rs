@@ -1,3 +1,5 @@
+pub mod expr;
+pub mod postprocess;
 mod renderer;
 pub mod vec4;
 
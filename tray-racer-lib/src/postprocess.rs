@@ -0,0 +1,86 @@
+//
+// postprocess.rs: Image processing applied to finished renders.
+//
+
+use crate::renderer::RenderBuffer;
+
+////////////////////////////////////////////////////////////////////////
+// Unsharp mask: Sharpen by blurring the image, subtracting the blur to
+// leave the high-frequency detail, and adding that back scaled by an
+// amount.
+//
+
+// How many standard deviations the Gaussian kernel extends to either
+// side.
+const KERNEL_SIGMAS: f64 = 3.0;
+
+// Normalised 1D Gaussian kernel with the given standard deviation, in
+// pixels, centred in the middle entry.
+fn gaussian_kernel(sigma: f64) -> Vec<f32> {
+    let half = (KERNEL_SIGMAS * sigma).ceil() as isize;
+    let weights = (-half..=half)
+        .map(|d| (-0.5 * (d as f64 / sigma).powi(2)).exp())
+        .collect::<Vec<_>>();
+    let total = weights.iter().sum::<f64>();
+    weights.iter().map(|w| (w / total) as f32).collect()
+}
+
+// Convolve the pixels with the kernel along rows if horizontal, or
+// else columns. Pixels beyond the edges repeat the edge pixel.
+fn convolve(
+    pixels: &[[f32; 4]],
+    width: usize,
+    height: usize,
+    kernel: &[f32],
+    horizontal: bool,
+) -> Vec<[f32; 4]> {
+    let half = (kernel.len() / 2) as isize;
+    let len = if horizontal { width } else { height };
+    (0..pixels.len())
+        .map(|idx| {
+            let (x, y) = (idx % width, idx / width);
+            let pos = if horizontal { x } else { y } as isize;
+            let mut sum = [0.0; 4];
+            for (offset, weight) in (-half..=half).zip(kernel) {
+                let p = (pos + offset).clamp(0, len as isize - 1) as usize;
+                let src = if horizontal {
+                    pixels[y * width + p]
+                } else {
+                    pixels[p * width + x]
+                };
+                for (total, channel) in sum.iter_mut().zip(src) {
+                    *total += channel * weight;
+                }
+            }
+            sum
+        })
+        .collect()
+}
+
+// Sharpen the colour channels by `amount` times their difference from
+// a Gaussian blur with a standard deviation of `radius` pixels. Alpha
+// is left alone, and an amount of 0 leaves the image unchanged. The
+// result may go outside [0, 1], to be clamped when finalized.
+pub fn unsharp_mask(buffer: &RenderBuffer, amount: f64, radius: f64) -> RenderBuffer {
+    assert!(radius > 0.0);
+    let (width, height) = (buffer.width, buffer.height);
+    let pixels = (0..height)
+        .flat_map(|y| (0..width).map(move |x| buffer.get(x, y)))
+        .collect::<Vec<_>>();
+
+    let kernel = gaussian_kernel(radius);
+    let blurred = convolve(&pixels, width, height, &kernel, true);
+    let blurred = convolve(&blurred, width, height, &kernel, false);
+
+    let amount = amount as f32;
+    let mut sharpened = RenderBuffer::new(width, height);
+    for (idx, (pixel, blur)) in pixels.iter().zip(blurred.iter()).enumerate() {
+        let mut sample = *pixel;
+        // Colour channels only.
+        for ((channel, orig), blur) in sample.iter_mut().zip(pixel).zip(blur).take(3) {
+            *channel += amount * (orig - blur);
+        }
+        sharpened.add_sample(idx % width, idx / width, sample);
+    }
+    sharpened
+}
@@ -2,11 +2,18 @@
 // renderer.rs: Display-independent rendering of the scene.
 //
 
-use std::path::Path;
+use std::future::Future;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use rayon::prelude::*;
 
+use crate::expr::Expr;
 use crate::vec4::*;
 
 type Pixel = [u8; 4];
@@ -18,44 +25,623 @@ const EPSILON: f64 = 1.0e-7;
 // Environment map.
 //
 
-// Last bool is "is vertical?". Vertical pair flips in a different
-// way.
-type ImagePair = (image::RgbaImage, image::RgbaImage, bool);
+// An env map image. HDR images (from .hdr or .exr files) keep their
+// linear floating-point values, so that highlights aren't clipped;
+// everything else is 8-bit sRGB.
+#[derive(Clone)]
+enum EnvImage {
+    Ldr(image::RgbaImage),
+    Hdr(image::Rgba32FImage),
+}
 
-pub struct EnvMap {
+impl EnvImage {
+    fn open(path: &Path) -> image::ImageResult<EnvImage> {
+        let image = image::open(path)?;
+        Ok(match image.color() {
+            image::ColorType::Rgb32F | image::ColorType::Rgba32F => {
+                EnvImage::Hdr(image.into_rgba32f())
+            }
+            _ => EnvImage::Ldr(image.into_rgba8()),
+        })
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            EnvImage::Ldr(image) => image.dimensions(),
+            EnvImage::Hdr(image) => image.dimensions(),
+        }
+    }
+
+    // The texel's channels, in the encoding of `like`: 8-bit sRGB
+    // values for LDR images, or linear values with alpha in 0..1 for
+    // HDR ones. Converting HDR values to 8 bits clamps them.
+    fn texel_like(&self, x: u32, y: u32, like: &EnvImage) -> [f64; 4] {
+        match (self, like) {
+            (EnvImage::Ldr(image), EnvImage::Ldr(_)) => image.get_pixel(x, y).0.map(|c| c as f64),
+            (EnvImage::Hdr(image), EnvImage::Hdr(_)) => image.get_pixel(x, y).0.map(|c| c as f64),
+            (EnvImage::Ldr(image), EnvImage::Hdr(_)) => {
+                let [r, g, b, a] = image.get_pixel(x, y).0;
+                [
+                    srgb_to_linear(r),
+                    srgb_to_linear(g),
+                    srgb_to_linear(b),
+                    a as f32 / u8::MAX as f32,
+                ]
+                .map(|c| c as f64)
+            }
+            (EnvImage::Hdr(image), EnvImage::Ldr(_)) => {
+                let [r, g, b, a] = image.get_pixel(x, y).0;
+                [
+                    linear_to_srgb(r),
+                    linear_to_srgb(g),
+                    linear_to_srgb(b),
+                    (a.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8,
+                ]
+                .map(|c| c as f64)
+            }
+        }
+    }
+
+    // A new size x size image, copying each pixel from the pixel of
+    // this image that `source` gives for its coordinates.
+    fn remap(&self, size: u32, source: impl Fn(u32, u32) -> (u32, u32)) -> EnvImage {
+        match self {
+            EnvImage::Ldr(image) => EnvImage::Ldr(image::RgbaImage::from_fn(size, size, |x, y| {
+                let (sx, sy) = source(x, y);
+                *image.get_pixel(sx, sy)
+            })),
+            EnvImage::Hdr(image) => {
+                EnvImage::Hdr(image::Rgba32FImage::from_fn(size, size, |x, y| {
+                    let (sx, sy) = source(x, y);
+                    *image.get_pixel(sx, sy)
+                }))
+            }
+        }
+    }
+}
+
+// How sampling treats texels beyond an image's edges.
+#[derive(Clone, Copy)]
+enum Edges {
+    // Repeat the edge texels.
+    Clamp,
+    // Wrap around horizontally and clamp vertically, for
+    // equirectangular maps.
+    WrapX,
+    // Continue onto the neighbouring faces of a cube map, from the
+    // given face.
+    Cube(Face),
+}
+
+// A sampled env map texel: 8-bit sRGB from LDR images, or linear from
+// HDR ones.
+enum Texel {
+    Encoded(Pixel),
+    Linear([f32; 4]),
+}
+
+// The images for the positive and negative directions of an axis.
+type ImagePair = (EnvImage, EnvImage);
+
+// Cube faces, in pairs along each axis.
+#[derive(Clone)]
+struct CubeFaces {
     xmap: ImagePair,
     ymap: ImagePair,
     zmap: ImagePair,
 }
 
-// Build and sample a cubic environment map. Has various axis tweaks
-// to match the environment maps we use.
+// The faces for the positive and negative directions of the axis
+// being looked up. Last bool is "is vertical?". Vertical pair flips
+// in a different way.
+type FacePair = (Face, Face, bool);
+
+const X_FACES: FacePair = (Face::NegX, Face::PosX, false);
+const Y_FACES: FacePair = (Face::NegY, Face::PosY, true);
+const Z_FACES: FacePair = (Face::NegZ, Face::PosZ, false);
+
+// Extensions of the face images EnvMap reads, in the order tried.
+pub const FACE_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "webp", "hdr", "exr"];
+
+// Faces of a cube map, named after the image files EnvMap reads. The
+// files' names don't match the world axes they cover, so `dir` gives
+// the mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Face {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [
+        Face::PosX,
+        Face::NegX,
+        Face::PosY,
+        Face::NegY,
+        Face::PosZ,
+        Face::NegZ,
+    ];
+
+    // Name of the face, as the stem of its image file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Face::PosX => "posx",
+            Face::NegX => "negx",
+            Face::PosY => "posy",
+            Face::NegY => "negy",
+            Face::PosZ => "posz",
+            Face::NegZ => "negz",
+        }
+    }
+
+    // The face's image file in the directory, with the first of
+    // FACE_EXTENSIONS that exists.
+    pub fn find_file(&self, dir: &Path) -> Option<PathBuf> {
+        FACE_EXTENSIONS
+            .iter()
+            .map(|ext| dir.join(format!("{}.{}", self.name(), ext)))
+            .find(|file| file.exists())
+    }
+
+    // Name of the file written for the face.
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Face::PosX => "posx.jpg",
+            Face::NegX => "negx.jpg",
+            Face::PosY => "posy.jpg",
+            Face::NegY => "negy.jpg",
+            Face::PosZ => "posz.jpg",
+            Face::NegZ => "negz.jpg",
+        }
+    }
+
+    // The face covering the opposite direction.
+    pub fn opposite(&self) -> Face {
+        match self {
+            Face::PosX => Face::NegX,
+            Face::NegX => Face::PosX,
+            Face::PosY => Face::NegY,
+            Face::NegY => Face::PosY,
+            Face::PosZ => Face::NegZ,
+            Face::NegZ => Face::PosZ,
+        }
+    }
+
+    // Direction (not normalised) that EnvMap looks up at texture
+    // coordinates (u, v) in 0..1 of this face's image, with v
+    // increasing down the image. The inverse of `EnvMap::colour`.
+    pub fn dir(&self, u: f64, v: f64) -> Dir4 {
+        let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+        let (x, y, z) = match self {
+            Face::NegX => (a, b, 1.0),
+            Face::PosX => (-a, b, -1.0),
+            Face::NegZ => (-1.0, b, a),
+            Face::PosZ => (1.0, b, -a),
+            Face::NegY => (-b, 1.0, -a),
+            Face::PosY => (b, -1.0, -a),
+        };
+        Dir4 { x, y, z, w: 0.0 }
+    }
+}
+
+// Reasons a cube map's faces can't be loaded. Faces are named as in
+// `Face::name`.
+#[derive(Debug)]
+pub enum EnvMapError {
+    // The directory has no image for the face in any of
+    // FACE_EXTENSIONS.
+    MissingFace {
+        face: &'static str,
+        path: PathBuf,
+    },
+    // The face's image file couldn't be read or decoded.
+    DecodeFailed {
+        face: &'static str,
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    // The face's image isn't the same square size as the others.
+    DimensionMismatch {
+        face: &'static str,
+        size: (u32, u32),
+        expected: (u32, u32),
+    },
+}
+
+impl std::fmt::Display for EnvMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EnvMapError::MissingFace { face, path } => write!(
+                f,
+                "Env map in {} has no {} image for face {}",
+                path.display(),
+                FACE_EXTENSIONS.join("/"),
+                face
+            ),
+            EnvMapError::DecodeFailed { face, path, .. } => write!(
+                f,
+                "Couldn't load env map face {} from {}",
+                face,
+                path.display()
+            ),
+            EnvMapError::DimensionMismatch {
+                face,
+                size,
+                expected,
+            } => write!(
+                f,
+                "Env map face {} is {}x{}, but should be {}x{}",
+                face, size.0, size.1, expected.0, expected.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EnvMapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EnvMapError::DecodeFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EnvMap {
+    images: EnvMapImages,
+    // Cos and sin of the map's rotation around the y axis.
+    rotation: (f64, f64),
+    grade: ColourGrade,
+    alpha_policy: EnvMapAlphaPolicy,
+    filter: SamplingMode,
+    // If set, every lookup samples this face, for debugging.
+    debug_face: Option<Face>,
+}
+
+// The images an env map is sampled from.
+#[derive(Clone)]
+enum EnvMapImages {
+    Cube(Box<CubeFaces>),
+    // A single equirectangular (latitude-longitude) panorama, with +z
+    // in the middle and up (-y) along the top edge.
+    Equirect(EnvImage),
+}
+
+// How env map lookups sample the face images.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplingMode {
+    // The texel containing the point.
+    #[default]
+    Nearest,
+    // Blend of the four nearest texel centres, in the images' encoded
+    // (sRGB) values. Clamped at the edges of each face.
+    Bilinear,
+}
+
+// How env map lookups treat the alpha channel of the face images.
+// Faces without one, like JPEGs, read as opaque whatever the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnvMapAlphaPolicy {
+    // Alpha is forced to 1.
+    #[default]
+    Opaque,
+    // The linear colour is scaled by the alpha, which is kept.
+    Premultiply,
+    // Alpha is kept, and the colour left alone.
+    Passthrough,
+}
+
+// Colour adjustments applied to env map samples in linear space, to
+// even out the exposure of different skyboxes. Contrast is a power
+// curve pivoting around mid-grey. The default changes nothing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColourGrade {
+    pub brightness: f32,
+    pub contrast: f32,
+    pub saturation: f32,
+}
+
+// Linear value treated as mid-grey by the contrast adjustment.
+const MID_GREY: f32 = 0.18;
+
+impl Default for ColourGrade {
+    fn default() -> ColourGrade {
+        ColourGrade {
+            brightness: 1.0,
+            contrast: 1.0,
+            saturation: 1.0,
+        }
+    }
+}
+
+impl ColourGrade {
+    fn apply(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let rgb = rgb.map(|c| MID_GREY * (c * self.brightness / MID_GREY).powf(self.contrast));
+        // Rec. 709 luminance.
+        let luma = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+        rgb.map(|c| luma + (c - luma) * self.saturation)
+    }
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / u8::MAX as f32;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * u8::MAX as f32).round() as u8
+}
+
+// Convert linear RGBA, as from `Tracer::render_hdr`, to 8-bit sRGB
+// RGBA, clamping the colour channels to [0, 1].
+pub fn encode_srgb(pixels: &[[f32; 4]]) -> Vec<u8> {
+    pixels
+        .iter()
+        .flat_map(|[r, g, b, a]| {
+            [
+                linear_to_srgb(*r),
+                linear_to_srgb(*g),
+                linear_to_srgb(*b),
+                (a.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8,
+            ]
+        })
+        .collect()
+}
+
+fn decode_srgb([r, g, b, a]: Pixel) -> [f32; 4] {
+    [
+        srgb_to_linear(r),
+        srgb_to_linear(g),
+        srgb_to_linear(b),
+        a as f32 / u8::MAX as f32,
+    ]
+}
+
+// The image for `face` that makes the map mirror-symmetric, given the
+// opposite face's image. From `Face::dir`, reflecting the direction at
+// texture coordinates (u, v) on one face of a pair lands on the other
+// at (1 - u, v), except for the vertical pair, where it's (u, 1 - v).
+fn mirror_face(opposite: &EnvImage, face: Face) -> EnvImage {
+    let vertical = matches!(face, Face::PosY | Face::NegY);
+    match opposite {
+        EnvImage::Ldr(image) if vertical => EnvImage::Ldr(image::imageops::flip_vertical(image)),
+        EnvImage::Ldr(image) => EnvImage::Ldr(image::imageops::flip_horizontal(image)),
+        EnvImage::Hdr(image) if vertical => EnvImage::Hdr(image::imageops::flip_vertical(image)),
+        EnvImage::Hdr(image) => EnvImage::Hdr(image::imageops::flip_horizontal(image)),
+    }
+}
+
+// Load the image for a face from the file.
+fn open_face(face: Face, file: &Path) -> Result<EnvImage, EnvMapError> {
+    EnvImage::open(file).map_err(|source| EnvMapError::DecodeFailed {
+        face: face.name(),
+        path: file.to_path_buf(),
+        source,
+    })
+}
+
+// Build and sample an environment map. Cube maps have various axis
+// tweaks to match the environment maps we use.
 impl EnvMap {
     // Stub envmap for tests etc.
     pub fn new() -> EnvMap {
-        let img = image::RgbaImage::new(1, 1);
-        let img_pair = (img.clone(), img.clone(), false);
-        EnvMap {
+        let img = EnvImage::Ldr(image::RgbaImage::new(1, 1));
+        let img_pair = (img.clone(), img.clone());
+        EnvMap::with_images(EnvMapImages::Cube(Box::new(CubeFaces {
             xmap: img_pair.clone(),
             ymap: img_pair.clone(),
             zmap: img_pair.clone(),
+        })))
+    }
+
+    fn with_images(images: EnvMapImages) -> EnvMap {
+        EnvMap {
+            images,
+            rotation: (1.0, 0.0),
+            grade: ColourGrade::default(),
+            alpha_policy: EnvMapAlphaPolicy::default(),
+            filter: SamplingMode::default(),
+            debug_face: None,
         }
     }
 
-    pub fn from(path: &Path) -> Result<EnvMap> {
-        let open = |s: &str| image::open(path.join(s)).map(|img| img.into_rgba8());
+    // Load the faces from a directory, each as an image named after
+    // the face with any of FACE_EXTENSIONS. Faces may mix HDR and LDR
+    // images, but must all be square and the same size.
+    pub fn from(path: &Path) -> Result<EnvMap, EnvMapError> {
+        let env_map = EnvMap::from_faces(|face| {
+            let file = face
+                .find_file(path)
+                .ok_or_else(|| EnvMapError::MissingFace {
+                    face: face.name(),
+                    path: path.to_path_buf(),
+                })?;
+            open_face(face, &file)
+        })?;
+        env_map.check_face_sizes()?;
+        Ok(env_map)
+    }
+
+    // As `from`, but any missing face is made by mirroring the
+    // opposite face, for skyboxes that only come with half the faces.
+    // Each face's pair must have at least one image; if neither has,
+    // the error names the face asked for.
+    pub fn from_mirrored(path: &Path) -> Result<EnvMap, EnvMapError> {
+        let env_map = EnvMap::from_faces(|face| {
+            if let Some(file) = face.find_file(path) {
+                return open_face(face, &file);
+            }
+            let opposite = face.opposite();
+            let opposite_file =
+                opposite
+                    .find_file(path)
+                    .ok_or_else(|| EnvMapError::MissingFace {
+                        face: face.name(),
+                        path: path.to_path_buf(),
+                    })?;
+            Ok(mirror_face(&open_face(opposite, &opposite_file)?, face))
+        })?;
+        env_map.check_face_sizes()?;
+        Ok(env_map)
+    }
+
+    // Build from already-decoded face images, in the order of
+    // `Face::ALL`, for when there's no filesystem to load from (e.g.
+    // images embedded with `include_bytes!`). The faces are checked
+    // as in `from`.
+    pub fn from_images(faces: [image::RgbaImage; 6]) -> Result<EnvMap, EnvMapError> {
+        let mut faces = faces.map(|image| Some(EnvImage::Ldr(image)));
+        let env_map = EnvMap::from_faces(|face| {
+            let index = Face::ALL.iter().position(|f| *f == face).unwrap();
+            Ok(faces[index].take().unwrap())
+        })?;
+        env_map.check_face_sizes()?;
+        Ok(env_map)
+    }
 
-        Ok(EnvMap {
-            xmap: (open("negx.jpg")?, open("posx.jpg")?, false),
-            ymap: (open("negy.jpg")?, open("posy.jpg")?, true),
-            zmap: (open("negz.jpg")?, open("posz.jpg")?, false),
+    // Load the faces from a single image in the given layout, as
+    // written by `Tracer::render_cube_layout`.
+    pub fn from_layout(path: &Path, layout: CubeLayout) -> Result<EnvMap> {
+        let image = EnvImage::open(path)?;
+        let (width, height) = image.dimensions();
+        let (cols, rows) = layout.dimensions();
+        let (cols, rows) = (cols as u32, rows as u32);
+        ensure!(
+            width % cols == 0 && height % rows == 0 && width / cols == height / rows,
+            "{} is {}x{}, which doesn't divide into the {}x{} square faces of a {:?} layout",
+            path.display(),
+            width,
+            height,
+            cols,
+            rows,
+            layout
+        );
+        let size = width / cols;
+        let tiles = layout.tiles();
+        // Each face pixel's direction lands on a pixel centre in the
+        // tile facing it, so this is an exact copy.
+        EnvMap::from_faces(|face| {
+            Ok(image.remap(size, |ix, iy| {
+                let u = (ix as f64 + 0.5) / size as f64;
+                let v = (iy as f64 + 0.5) / size as f64;
+                let dir = face.dir(u, v);
+                let (col, row, [forward, right, down]) = tiles
+                    .iter()
+                    .max_by(|a, b| a.2[0].dot(dir).total_cmp(&b.2[0].dot(dir)))
+                    .unwrap();
+                let depth = dir.dot(*forward);
+                let to_pixel = |t: f64| {
+                    let pixel = ((t / depth + 1.0) * 0.5 * size as f64 - 0.5).round();
+                    (pixel as u32).min(size - 1)
+                };
+                (
+                    *col as u32 * size + to_pixel(dir.dot(*right)),
+                    *row as u32 * size + to_pixel(dir.dot(*down)),
+                )
+            }))
         })
     }
 
+    // Load a single equirectangular panorama, which must be twice as
+    // wide as it is tall.
+    pub fn from_equirect(path: &Path) -> Result<EnvMap> {
+        let image = EnvImage::open(path)?;
+        let (width, height) = image.dimensions();
+        ensure!(
+            width == 2 * height,
+            "{} is {}x{}, but an equirectangular map must be twice as wide as it is tall",
+            path.display(),
+            width,
+            height
+        );
+        Ok(EnvMap::with_images(EnvMapImages::Equirect(image)))
+    }
+
+    // Build from a function supplying each face's image.
+    fn from_faces<E>(mut face_image: impl FnMut(Face) -> Result<EnvImage, E>) -> Result<EnvMap, E> {
+        Ok(EnvMap::with_images(EnvMapImages::Cube(Box::new(
+            CubeFaces {
+                xmap: (face_image(Face::NegX)?, face_image(Face::PosX)?),
+                ymap: (face_image(Face::NegY)?, face_image(Face::PosY)?),
+                zmap: (face_image(Face::NegZ)?, face_image(Face::PosZ)?),
+            },
+        ))))
+    }
+
+    // Check that a cube map's faces are square and all the same size,
+    // as sampling assumes, taking the first face's width as the size.
+    fn check_face_sizes(&self) -> Result<(), EnvMapError> {
+        let (size, _) = self.image(Face::ALL[0]).dimensions();
+        for face in Face::ALL {
+            let face_size = self.image(face).dimensions();
+            if face_size != (size, size) {
+                return Err(EnvMapError::DimensionMismatch {
+                    face: face.name(),
+                    size: face_size,
+                    expected: (size, size),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Orient the map by rotating it around the (vertical) y axis.
+    pub fn set_rotation(&mut self, degrees: f64) {
+        let radians = degrees.to_radians();
+        self.rotation = (radians.cos(), radians.sin());
+    }
+
+    pub fn set_grade(&mut self, grade: ColourGrade) {
+        self.grade = grade;
+    }
+
+    pub fn grade(&self) -> ColourGrade {
+        self.grade
+    }
+
+    pub fn set_alpha_policy(&mut self, policy: EnvMapAlphaPolicy) {
+        self.alpha_policy = policy;
+    }
+
+    pub fn alpha_policy(&self) -> EnvMapAlphaPolicy {
+        self.alpha_policy
+    }
+
+    pub fn set_filter(&mut self, filter: SamplingMode) {
+        self.filter = filter;
+    }
+
+    pub fn filter(&self) -> SamplingMode {
+        self.filter
+    }
+
+    // Force lookups to sample the given face, at the texture
+    // coordinates on whichever face the direction selects, so that
+    // its content is repeated across the whole map. For tracking down
+    // which face an artefact comes from. Equirectangular maps have no
+    // faces, and ignore this.
+    pub fn set_debug_face(&mut self, face: Option<Face>) {
+        self.debug_face = face;
+    }
+
     // Coordinates should be normalised to have largest direction in z.
-    fn colour_face(&self, x: f64, y: f64, z: f64, img_pair: &ImagePair) -> Pixel {
-        // Get image for appropriate direction.
-        let img = if z > 0.0 { &img_pair.0 } else { &img_pair.1 };
+    fn face_coords(x: f64, y: f64, z: f64, img_pair: &FacePair) -> (Face, f64, f64) {
+        // Get face for appropriate direction.
+        let face = if z > 0.0 { img_pair.0 } else { img_pair.1 };
         // Normalise coordinates. Does some flipping as needed to make
         // the faces' edges match up.
         let (x, y) = if img_pair.2 {
@@ -64,29 +650,214 @@ impl EnvMap {
             (x / z, y / z.abs())
         };
         // Convert face coordinates -1..1 to texture coordinates 0..1.
-        let x = 0.5 * (x + 1.0);
-        let y = 0.5 * (y + 1.0);
-        // Then scale to pixel coordinates.
-        let (w, h) = img.dimensions();
-        // Mapping semi-open interval [0..1) to [0..size).
-        let ix = ((x * w as f64) as u32).min(w - 1);
-        let iy = ((y * h as f64) as u32).min(h - 1);
-        img.get_pixel(ix, iy).0
+        (face, 0.5 * (x + 1.0), 0.5 * (y + 1.0))
     }
 
-    // Ignores the w component.
-    fn colour(&self, dir: Dir4) -> Pixel {
-        let (ax, ay, az) = (dir.x.abs(), dir.y.abs(), dir.z.abs());
+    // The direction in the map's own orientation.
+    fn rotate(&self, dir: Dir4) -> Dir4 {
+        let (rot_cos, rot_sin) = self.rotation;
+        Dir4 {
+            x: dir.x * rot_cos - dir.z * rot_sin,
+            y: dir.y,
+            z: dir.x * rot_sin + dir.z * rot_cos,
+            w: dir.w,
+        }
+    }
+
+    // The face, and texture coordinates in 0..1 on it, that `colour`
+    // samples for the given direction of a cube map. Ignores the w
+    // component.
+    pub fn face_uv(&self, dir: Dir4) -> (Face, f64, f64) {
+        EnvMap::cube_face_uv(self.rotate(dir))
+    }
+
+    // As `face_uv`, for a direction in the map's own orientation.
+    fn cube_face_uv(dir: Dir4) -> (Face, f64, f64) {
+        let a = dir.abs();
+        // The face is chosen by the largest component. Directions
+        // exactly on an edge or corner, where components tie, go to
+        // the first of z, x and y in that order, so that every
+        // direction maps to exactly one face. Either face's edge pixel
+        // would be a reasonable answer there.
+        //
         // We do some coordinate flipping to make sure the faces'
         // edges match up.
-        if az > ax && az > ay {
-            self.colour_face(dir.x, dir.y, dir.z, &self.xmap)
-        } else if ax > ay {
-            self.colour_face(dir.z, dir.y, -dir.x, &self.zmap)
+        if a.z >= a.x && a.z >= a.y {
+            EnvMap::face_coords(dir.x, dir.y, dir.z, &X_FACES)
+        } else if a.x >= a.y {
+            EnvMap::face_coords(dir.z, dir.y, -dir.x, &Z_FACES)
         } else {
-            self.colour_face(-dir.z, -dir.x, dir.y, &self.ymap)
+            EnvMap::face_coords(-dir.z, -dir.x, dir.y, &Y_FACES)
+        }
+    }
+
+    // Texture coordinates in 0..1 that `colour` samples for the given
+    // direction of an equirectangular map, with u increasing to the
+    // right as seen from inside (towards -x from +z, as +x is on the
+    // left) and v increasing downwards (+y). Ignores the w component.
+    pub fn equirect_uv(&self, dir: Dir4) -> (f64, f64) {
+        let dir = self.rotate(dir);
+        let horizontal = dir.x.hypot(dir.z);
+        let u = 0.5 + (-dir.x).atan2(dir.z) / (2.0 * std::f64::consts::PI);
+        let v = 0.5 + dir.y.atan2(horizontal) / std::f64::consts::PI;
+        (u, v)
+    }
+
+    // The image for a face, for uploading elsewhere (e.g. as textures
+    // for GPU tracing). None if it's not an 8-bit cube map.
+    pub fn face_image(&self, face: Face) -> Option<&image::RgbaImage> {
+        match (&self.images, self.image(face)) {
+            (EnvMapImages::Cube(_), EnvImage::Ldr(image)) => Some(image),
+            _ => None,
+        }
+    }
+
+    // Cos and sin of the map's rotation around the y axis.
+    pub fn rotation(&self) -> (f64, f64) {
+        self.rotation
+    }
+
+    // The cube face's image. Equirectangular maps only have the one.
+    fn image(&self, face: Face) -> &EnvImage {
+        let faces = match &self.images {
+            EnvMapImages::Cube(faces) => faces,
+            EnvMapImages::Equirect(image) => return image,
+        };
+        match face {
+            Face::NegX => &faces.xmap.0,
+            Face::PosX => &faces.xmap.1,
+            Face::NegY => &faces.ymap.0,
+            Face::PosY => &faces.ymap.1,
+            Face::NegZ => &faces.zmap.0,
+            Face::PosZ => &faces.zmap.1,
+        }
+    }
+
+    // Ignores the w component. HDR texels are clamped to the 8-bit
+    // range, as with ToneMap::None.
+    fn colour(&self, dir: Dir4) -> Pixel {
+        let texel = self.colour_raw(dir);
+        // Premultiplying works in linear space, so only the other
+        // policies can skip the conversion.
+        if let Texel::Encoded(mut pixel) = texel {
+            if self.grade == ColourGrade::default()
+                && self.alpha_policy != EnvMapAlphaPolicy::Premultiply
+            {
+                if self.alpha_policy == EnvMapAlphaPolicy::Opaque {
+                    pixel[3] = u8::MAX;
+                }
+                return pixel;
+            }
+        }
+        let [r, g, b, a] = self.grade_texel(texel);
+        [
+            linear_to_srgb(r),
+            linear_to_srgb(g),
+            linear_to_srgb(b),
+            (a * u8::MAX as f32).round() as u8,
+        ]
+    }
+
+    // The graded colour in linear space, before clamping, with alpha
+    // treated according to the alpha policy. HDR maps' highlights
+    // come through above 1.0. Ignores the w component.
+    pub fn colour_f32(&self, dir: Dir4) -> [f32; 4] {
+        self.grade_texel(self.colour_raw(dir))
+    }
+
+    fn grade_texel(&self, texel: Texel) -> [f32; 4] {
+        let [r, g, b, a] = match texel {
+            Texel::Encoded(pixel) => decode_srgb(pixel),
+            Texel::Linear(texel) => texel,
+        };
+        let [r, g, b] = self.grade.apply([r, g, b]);
+        match self.alpha_policy {
+            EnvMapAlphaPolicy::Opaque => [r, g, b, 1.0],
+            EnvMapAlphaPolicy::Premultiply => [r * a, g * a, b * a, a],
+            EnvMapAlphaPolicy::Passthrough => [r, g, b, a],
+        }
+    }
+
+    // The sampled texel, without grading.
+    fn colour_raw(&self, dir: Dir4) -> Texel {
+        match &self.images {
+            EnvMapImages::Cube(_) => {
+                let (face, x, y) = self.face_uv(dir);
+                match self.debug_face {
+                    Some(debug_face) => self.sample(self.image(debug_face), x, y, Edges::Clamp),
+                    None => self.sample(self.image(face), x, y, Edges::Cube(face)),
+                }
+            }
+            EnvMapImages::Equirect(img) => {
+                let (x, y) = self.equirect_uv(dir);
+                self.sample(img, x, y, Edges::WrapX)
+            }
+        }
+    }
+
+    // Sample the image at texture coordinates in 0..1 according to
+    // the sampling mode, treating texels beyond its edges as `edges`
+    // says. Only bilinear sampling reaches beyond the edges.
+    fn sample(&self, img: &EnvImage, x: f64, y: f64, edges: Edges) -> Texel {
+        // Scale to pixel coordinates.
+        let (w, h) = img.dimensions();
+        let channels = match self.filter {
+            SamplingMode::Nearest => {
+                // Mapping semi-open interval [0..1) to [0..size).
+                let ix = ((x * w as f64) as u32).min(w - 1);
+                let iy = ((y * h as f64) as u32).min(h - 1);
+                img.texel_like(ix, iy, img)
+            }
+            SamplingMode::Bilinear => {
+                // Texel centres are at integer coordinates, so the
+                // image's edges are half a texel beyond the outermost.
+                let fx = x * w as f64 - 0.5;
+                let fy = y * h as f64 - 0.5;
+                let (x0, y0) = (fx.floor(), fy.floor());
+                let (tx, ty) = (fx - x0, fy - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let texel = |ix: i64, iy: i64| self.edge_texel(img, ix, iy, edges);
+                let lerp = |a: [f64; 4], b: [f64; 4], t: f64| -> [f64; 4] {
+                    std::array::from_fn(|idx| a[idx] + (b[idx] - a[idx]) * t)
+                };
+                let top = lerp(texel(x0, y0), texel(x0 + 1, y0), tx);
+                let bottom = lerp(texel(x0, y0 + 1), texel(x0 + 1, y0 + 1), tx);
+                lerp(top, bottom, ty)
+            }
+        };
+        match img {
+            EnvImage::Ldr(_) => Texel::Encoded(channels.map(|c| c.round() as u8)),
+            EnvImage::Hdr(_) => Texel::Linear(channels.map(|c| c as f32)),
         }
     }
+
+    // The image's texel at the given coordinates, which may be up to
+    // a texel beyond its edges, in the image's own encoding.
+    fn edge_texel(&self, img: &EnvImage, ix: i64, iy: i64, edges: Edges) -> [f64; 4] {
+        let (w, h) = img.dimensions();
+        let (w, h) = (w as i64, h as i64);
+        let inside = (0..w).contains(&ix) && (0..h).contains(&iy);
+        let (ix, iy) = match edges {
+            Edges::Cube(face) if !inside => {
+                // Extend the face's plane out to the texel's centre,
+                // and take the nearest texel to that direction on the
+                // neighbouring face. The neighbour's texels don't
+                // line up with the extended grid, but they're close
+                // enough to hide the seam.
+                let u = (ix as f64 + 0.5) / w as f64;
+                let v = (iy as f64 + 0.5) / h as f64;
+                let (next_face, u, v) = EnvMap::cube_face_uv(face.dir(u, v));
+                let next = self.image(next_face);
+                let (nw, nh) = next.dimensions();
+                let nx = ((u * nw as f64) as u32).min(nw - 1);
+                let ny = ((v * nh as f64) as u32).min(nh - 1);
+                return next.texel_like(nx, ny, img);
+            }
+            Edges::WrapX => (ix.rem_euclid(w), iy.clamp(0, h - 1)),
+            _ => (ix.clamp(0, w - 1), iy.clamp(0, h - 1)),
+        };
+        img.texel_like(ix as u32, iy as u32, img)
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////
@@ -96,11 +867,86 @@ impl EnvMap {
 // Ray stepping size.
 pub const RAY_STEP: f64 = 0.01;
 
+// Default for `Tracer::throat_blend`.
+pub const DEFAULT_THROAT_BLEND: f64 = 0.1;
+
+// Smallest magnitude of w_scale we use, as the solver becomes
+// unstable with very small values.
+const MIN_W_SCALE: f64 = 0.02;
+
+// Apply the MIN_W_SCALE floor to w_scale. A hard floor would make the
+// surface jump as w_scale is swept across it, so below 2 * MIN_W_SCALE
+// we instead follow a quadratic that is MIN_W_SCALE at zero, and
+// meets the identity with matching slope. The result is continuous,
+// smooth and monotonic in the magnitude. Zero isn't special-cased as
+// flat space, which would bring back a jump at zero; flat space is
+// `dist_expr` "w".
+//
+// The surface only depends on w_scale squared, so negating it reflects
+// the embedding in w, which maps the surface onto itself: negative
+// values render exactly as the corresponding positive ones. We
+// return the magnitude to make that explicit.
+fn effective_w_scale(w_scale: f64) -> f64 {
+    let mag = w_scale.abs();
+    if mag < 2.0 * MIN_W_SCALE {
+        MIN_W_SCALE + mag * mag / (4.0 * MIN_W_SCALE)
+    } else {
+        mag
+    }
+}
+
+// A throat beyond the central one, centred at the given point in x,
+// y and z (w is ignored). w_scale and radius are as for the central
+// throat's, in `Tracer`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Throat {
+    pub centre: Point4,
+    pub w_scale: f64,
+    pub radius: f64,
+}
+
+// A smooth approximation to the minimum of a and b, which differs from
+// it only where they are within k of each other (Quilez's polynomial
+// smooth minimum).
+fn smooth_min(a: f64, b: f64, k: f64) -> f64 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (k - (a - b).abs()).max(0.0) / k;
+    a.min(b) - h * h * k * 0.25
+}
+
+// The sum of the sum of a_i * b_i over the pairs, and c. Uses
+// error-free transformations to carry the rounding errors of the
+// products and sums, adding them in at the end (Ogita, Rump and
+// Oishi's Dot2), so that the result is as accurate as if computed at
+// twice the precision and then rounded.
+fn compensated_dot(pairs: &[(f64, f64)], c: f64) -> f64 {
+    let mut sum = c;
+    let mut error = 0.0;
+    for &(a, b) in pairs {
+        let product = a * b;
+        let product_error = a.mul_add(b, -product);
+        // Knuth's TwoSum.
+        let new_sum = sum + product;
+        let b_virtual = new_sum - sum;
+        let sum_error = (sum - (new_sum - b_virtual)) + (product - b_virtual);
+        sum = new_sum;
+        error += product_error + sum_error;
+    }
+    sum + error
+}
+
+#[derive(Clone)]
 pub struct Tracer {
-    pub env_map_pos: EnvMap,
-    pub env_map_neg: EnvMap,
+    // Env maps are shared, so that tracers can be cloned (e.g. one
+    // per render thread or animation frame) without copying the
+    // images. Use `Arc::make_mut` to modify them.
+    pub env_map_pos: Arc<EnvMap>,
+    pub env_map_neg: Arc<EnvMap>,
     // How we scale w in the equation. effectively controls the depth
-    // of the wormhole.
+    // of the wormhole. Only the magnitude matters, and small values
+    // are floored (see `effective_w_scale`).
     pub w_scale: f64,
     // Radius of the wormhole.
     pub radius: f64,
@@ -108,304 +954,3203 @@ pub struct Tracer {
     // so that the direction will not change further, and we can look
     // it up in the environment map.
     pub infinity: f64,
+    // Optional cutaway plane, as a point on the plane and a normal.
+    // Rays whose paths cross to the side the normal points away from
+    // are rendered transparent.
+    pub clip_plane: Option<(Point4, Dir4)>,
+    // Trace each ray inside `catch_unwind`, so that a ray that panics
+    // produces PANIC_COLOUR rather than aborting the render.
+    pub catch_panics: bool,
+    // If set, stop tracing a ray once it has crossed the throat (w
+    // changing sign) more than this many times, sampling the env map
+    // for its current direction.
+    pub max_crossings: Option<usize>,
+    // Evaluate the surface function with compensated arithmetic, as
+    // its terms cancel near the surface. Slower, and experimental.
+    pub compensated_dist: bool,
+    // If set, replaces the wormhole's surface function with an
+    // expression in the variables named by DIST_EXPR_VARS, so that
+    // other implicit surfaces can be explored. w_scale and radius are
+    // then ignored by the tracer, but not by things that assume the
+    // wormhole's shape, like `curved_region_bound` and the app's GPU
+    // tracer.
+    pub dist_expr: Option<Expr>,
+    // Sample env_map_pos whichever side of the throat a ray ends up
+    // on, to check a skybox's alignment without the other one.
+    pub pos_map_only: bool,
+    // How the fixed-step tracer advances rays. The adaptive tracer
+    // always uses projection.
+    pub integrator: Integrator,
+    // Further throats joining the two sides, each blended into the
+    // surface with a smooth minimum of the surface functions. As with
+    // `dist_expr`, things that assume the central wormhole's shape
+    // ignore them.
+    pub extra_throats: Vec<Throat>,
+    // How close, in units of the surface function, throats' surface
+    // functions must be to be blended, rounding off the creases where
+    // their sheets meet. 0 gives a hard minimum.
+    pub throat_blend: f64,
+    // If set, replaces the wormhole with a black hole of the given
+    // Schwarzschild radius: the surface is Flamm's paraboloid, the
+    // embedding of the space around the black hole, and rays that
+    // cross its throat, the event horizon, are captured rather than
+    // coming out the other side. As with `dist_expr`, the wormhole's
+    // parameters are then ignored by the tracer only.
+    pub black_hole: Option<f64>,
+}
+
+impl Tracer {
+    // A tracer for the plain wormhole, with the optional features
+    // turned off.
+    pub fn new(
+        env_map_pos: Arc<EnvMap>,
+        env_map_neg: Arc<EnvMap>,
+        w_scale: f64,
+        radius: f64,
+        infinity: f64,
+    ) -> Tracer {
+        Tracer {
+            env_map_pos,
+            env_map_neg,
+            w_scale,
+            radius,
+            infinity,
+            clip_plane: None,
+            catch_panics: false,
+            max_crossings: None,
+            compensated_dist: false,
+            dist_expr: None,
+            pos_map_only: false,
+            integrator: Integrator::Projection,
+            extra_throats: Vec::new(),
+            throat_blend: DEFAULT_THROAT_BLEND,
+            black_hole: None,
+        }
+    }
+}
+
+// Schemes for advancing a ray by a fixed step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Integrator {
+    // Step along the direction of the previous step, and project back
+    // onto the surface along the normal. Error is first order in the
+    // step size.
+    Projection,
+    // Integrate the geodesic equation with 4th-order Runge-Kutta.
+    // Slower per step, but much more accurate for a given step size.
+    Rk4,
+}
+
+// Variables of `Tracer::dist_expr`, in the order they're evaluated
+// with.
+pub const DIST_EXPR_VARS: [&str; 4] = ["x", "y", "z", "w"];
+
+// Anti-aliasing approaches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Antialias {
+    // One ray per pixel.
+    None,
+    // Supersample pixels where the path length differs sharply from
+    // their neighbours', which marks the edges of the lensed regions.
+    DepthEdge,
+    // Supersample pixels where the number of throat crossings differs
+    // from their neighbours', which marks the lensed rings.
+    CrossingEdge,
+    // Supersample every pixel on an N x N jittered grid. One sample
+    // per pixel is the same as None.
+    Supersample(usize),
+}
+
+// Reconstruction filters, weighting supersamples by their offset from
+// the pixel centre when combining them. Widths are in pixels, and
+// filters are separable in x and y.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    // Equal weights within the width, for a plain average.
+    Box(f64),
+    // Weights falling linearly to zero at the edges of the width.
+    Tent(f64),
+    // Gaussian, with a standard deviation of a quarter of the width.
+    Gaussian(f64),
+}
+
+impl Filter {
+    fn weight(&self, dx: f64, dy: f64) -> f32 {
+        (self.weight_1d(dx) * self.weight_1d(dy)) as f32
+    }
+
+    // Weights for a pixel's samples at the given offsets from its
+    // centre. If the filter is too narrow to reach any of them, the
+    // nearest sample takes all the weight, rather than leaving the
+    // pixel empty.
+    fn sample_weights(&self, offsets: &[(f64, f64)]) -> Vec<f32> {
+        let mut weights = offsets
+            .iter()
+            .map(|(dx, dy)| self.weight(*dx, *dy))
+            .collect::<Vec<_>>();
+        if weights.iter().all(|weight| *weight <= 0.0) {
+            let nearest = offsets
+                .iter()
+                .map(|(dx, dy)| dx.hypot(*dy))
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b));
+            if let Some((idx, _)) = nearest {
+                weights[idx] = 1.0;
+            }
+        }
+        weights
+    }
+
+    fn weight_1d(&self, d: f64) -> f64 {
+        match *self {
+            Filter::Box(width) => {
+                if d.abs() <= 0.5 * width {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Tent(width) => (1.0 - d.abs() / (0.5 * width)).max(0.0),
+            Filter::Gaussian(width) => {
+                let sigma = 0.25 * width;
+                (-0.5 * (d / sigma).powi(2)).exp()
+            }
+        }
+    }
+}
+
+// Handedness of the camera's coordinate system. With no rotation, the
+// camera is at (0, 0, -1) looking along +z, with +y towards the
+// bottom of the image and positive tilt looking towards -y.
+//
+// Left-handed (the original convention) puts +x on the left of the
+// image, so that screen right x screen up = forward, and positive
+// turn looks towards -x. Right-handed mirrors the world's x axis,
+// putting +x on the right, and making positive turn and pan rotate
+// the other way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handedness {
+    Left,
+    Right,
 }
 
 // Configuration for the screen we expect. `render` then returns an
 // array of pixels that would fill in that canvas.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct CanvasConfig {
     // Width and height in pixels.
     pub width: usize,
     pub height: usize,
     // Aspect ratio in the form of height of a pixel / width of a pixel.
     pub aspect: f64,
-    // Field of view, in degrees.
+    // Horizontal field of view, in degrees.
     pub fov_degrees: f64,
+    // Vertical field of view, in degrees. If None, it follows from
+    // the horizontal one and the aspect ratios of the pixels and the
+    // canvas. Setting it stretches the view anamorphically.
+    pub fov_v_degrees: Option<f64>,
+    pub antialias: Antialias,
+    // How supersamples are combined, when anti-aliasing.
+    pub filter: Filter,
+    pub handedness: Handedness,
+    // Offset added to each pixel's position before finding its ray,
+    // in pixels. Allows sub-pixel shifted renders to be accumulated
+    // externally.
+    pub pixel_offset: (f64, f64),
+    // Tone map and gamma correction applied when `render` and the
+    // other whole-image renders convert their samples to 8 bits.
+    // ToneMap::None with a gamma of 1.0 leaves the colours as sampled.
+    pub tone_map: ToneMap,
+    pub gamma: f32,
+}
+
+impl CanvasConfig {
+    // Horizontal and vertical extents of the view, as the tangents of
+    // half the fields of view.
+    pub fn view_scale(&self) -> (f64, f64) {
+        let half_tan = |degrees: f64| (degrees * std::f64::consts::PI / 180.0 * 0.5).tan();
+        let x_scale = half_tan(self.fov_degrees);
+        let y_scale = match self.fov_v_degrees {
+            Some(fov_v) => half_tan(fov_v),
+            None => x_scale * self.aspect * self.height as f64 / self.width as f64,
+        };
+        (x_scale, y_scale)
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////
-// Fixed-step renderer
+// Render buffer: Accumulates floating-point samples per pixel, so that
+// supersampling, tone mapping and output bit depth can be handled in
+// one place before converting to integer pixels.
 //
 
-impl Tracer {
-    // Render a whole scene by tracing all the rays in the canvas.
-    pub fn render(
-        &self,
-        conf: &CanvasConfig,
-        tilt: f64,
-        turn: f64,
-        pan: f64,
-        step_size: Option<f64>,
-    ) -> Vec<u8> {
-        let tilt_rad = -tilt * std::f64::consts::PI / 180.0;
-        let tilt_cos = tilt_rad.cos();
-        let tilt_sin = tilt_rad.sin();
+// Tone-mapping operators applied to the colour channels on finalize.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToneMap {
+    // Clamp to [0, 1].
+    None,
+    // c / (1 + c), compressing rather than clipping highlights.
+    Reinhard,
+    // Narkowicz's rational fit to the ACES filmic curve, which
+    // compresses highlights with a gentle toe in the shadows.
+    Aces,
+}
 
-        let turn_rad = -turn * std::f64::consts::PI / 180.0;
-        let turn_cos = turn_rad.cos();
-        let turn_sin = turn_rad.sin();
+impl ToneMap {
+    // Map a colour channel, ready for clamping to [0, 1].
+    pub fn apply(self, c: f32) -> f32 {
+        match self {
+            ToneMap::None => c,
+            ToneMap::Reinhard => c.max(0.0) / (1.0 + c.max(0.0)),
+            ToneMap::Aces => {
+                let c = c.max(0.0);
+                (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14)
+            }
+        }
+    }
+}
 
-        let fov_rad = conf.fov_degrees * std::f64::consts::PI / 180.0;
-        let fov = (fov_rad * 0.5).tan();
+// Dithering of the colour channels when converting to 8 bits, trading
+// a little noise for the banding of smooth gradients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dither {
+    None,
+    // Thresholds from an 8x8 Bayer matrix.
+    Ordered,
+    // Thresholds from a tiled blue-noise pattern, which has no
+    // visible structure.
+    BlueNoise,
+}
 
-        // Invariants: start + step * (size - 1)/2 = 0.
-        let x_range = fov * 2.0;
-        let x_step = -x_range / conf.width as f64;
-        let x_start = -0.5 * x_step * (conf.width - 1) as f64;
+// Samples are RGBA with channels nominally in [0, 1].
+#[derive(Clone)]
+pub struct RenderBuffer {
+    pub width: usize,
+    pub height: usize,
+    sums: Vec<[f32; 4]>,
+    weights: Vec<f32>,
+}
 
-        let y_range = x_range * conf.aspect * conf.height as f64 / conf.width as f64;
-        let y_step = -y_range / conf.height as f64;
-        let y_start = -0.5 * y_step * (conf.height - 1) as f64;
+impl RenderBuffer {
+    pub fn new(width: usize, height: usize) -> RenderBuffer {
+        RenderBuffer {
+            width,
+            height,
+            sums: vec![[0.0; 4]; width * height],
+            weights: vec![0.0; width * height],
+        }
+    }
 
-        // Set the camera position.
-        let pan_rad = pan * std::f64::consts::PI / 180.0;
-        let pan_sin = pan_rad.sin();
-        let pan_cos = pan_rad.cos();
-        let origin = Point4 {
-            x: pan_sin,
+    // Build a buffer with one sample per pixel from 8-bit RGBA data.
+    pub fn from_pixels(width: usize, height: usize, pixels: &[u8]) -> RenderBuffer {
+        assert_eq!(pixels.len(), width * height * 4);
+        RenderBuffer {
+            width,
+            height,
+            sums: pixels
+                .chunks(4)
+                .map(|p| to_sample([p[0], p[1], p[2], p[3]]))
+                .collect(),
+            weights: vec![1.0; width * height],
+        }
+    }
+
+    // Build an opaque grey-scale buffer from values nominally in
+    // [0, 1].
+    pub fn from_grey(width: usize, height: usize, values: &[f64]) -> RenderBuffer {
+        assert_eq!(values.len(), width * height);
+        RenderBuffer {
+            width,
+            height,
+            sums: values
+                .iter()
+                .map(|v| {
+                    let v = *v as f32;
+                    [v, v, v, 1.0]
+                })
+                .collect(),
+            weights: vec![1.0; width * height],
+        }
+    }
+
+    pub fn add_sample(&mut self, x: usize, y: usize, sample: [f32; 4]) {
+        self.add_weighted_sample(x, y, sample, 1.0);
+    }
+
+    // Add a sample, contributing in proportion to its weight to the
+    // pixel's weighted average.
+    pub fn add_weighted_sample(&mut self, x: usize, y: usize, sample: [f32; 4], weight: f32) {
+        let idx = y * self.width + x;
+        for (total, channel) in self.sums[idx].iter_mut().zip(sample.iter()) {
+            *total += channel * weight;
+        }
+        self.weights[idx] += weight;
+    }
+
+    // Replace the pixel's samples with the single sample, for drawing
+    // over a render.
+    pub fn set(&mut self, x: usize, y: usize, sample: [f32; 4]) {
+        let idx = y * self.width + x;
+        self.sums[idx] = sample;
+        self.weights[idx] = 1.0;
+    }
+
+    // Average of the samples at the given pixel. Pixels with no
+    // samples are transparent black.
+    pub fn get(&self, x: usize, y: usize) -> [f32; 4] {
+        let idx = y * self.width + x;
+        let weight = self.weights[idx];
+        if weight == 0.0 {
+            return [0.0; 4];
+        }
+        self.sums[idx].map(|total| total / weight)
+    }
+
+    // Convert to 8-bit RGBA, applying the tone map and then gamma
+    // correction to the colour channels.
+    pub fn finalize(&self, tone_map: ToneMap, gamma: f32) -> Vec<u8> {
+        self.finalize_dithered(tone_map, gamma, Dither::None)
+    }
+
+    // As `finalize`, dithering the colour channels.
+    pub fn finalize_dithered(&self, tone_map: ToneMap, gamma: f32, dither: Dither) -> Vec<u8> {
+        self.quantize(tone_map, gamma, u8::MAX as f32, dither)
+            .into_iter()
+            .map(|c| c as u8)
+            .collect()
+    }
+
+    // As `finalize`, but to tightly-packed 8-bit RGB. Alpha is
+    // dropped, not composited.
+    pub fn finalize_rgb(&self, tone_map: ToneMap, gamma: f32) -> Vec<u8> {
+        self.quantize(tone_map, gamma, u8::MAX as f32, Dither::None)
+            .chunks(4)
+            .flat_map(|pixel| pixel[..3].iter().map(|c| *c as u8))
+            .collect()
+    }
+
+    // As `finalize`, but to 16-bit RGBA.
+    pub fn finalize_u16(&self, tone_map: ToneMap, gamma: f32) -> Vec<u16> {
+        self.quantize(tone_map, gamma, u16::MAX as f32, Dither::None)
+            .into_iter()
+            .map(|c| c as u16)
+            .collect()
+    }
+
+    // Tone map, gamma correct and scale channels to [0, max], rounding
+    // the colour channels against the dither's thresholds.
+    // Non-finite channels (from degenerate rays) are replaced by zero,
+    // with a warning giving the number of affected pixels.
+    fn quantize(&self, tone_map: ToneMap, gamma: f32, max: f32, dither: Dither) -> Vec<f32> {
+        let (channels, scrubbed) = self.quantize_counted(tone_map, gamma, max, dither);
+        if scrubbed > 0 {
+            log::warn!("Replaced non-finite channels in {} pixels", scrubbed);
+        }
+        channels
+    }
+
+    // As `quantize`, without the warning, also returning the number of
+    // pixels with non-finite channels replaced.
+    fn quantize_counted(
+        &self,
+        tone_map: ToneMap,
+        gamma: f32,
+        max: f32,
+        dither: Dither,
+    ) -> (Vec<f32>, usize) {
+        let mut scrubbed = 0;
+        let channels = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let pixel = self.get(x, y);
+                if pixel.iter().any(|c| !c.is_finite()) {
+                    scrubbed += 1;
+                }
+                let [r, g, b, a] = pixel.map(|c| if c.is_finite() { c } else { 0.0 });
+                let offset = dither_offset(dither, x, y);
+                let colour = |c: f32| {
+                    let c = tone_map.apply(c).clamp(0.0, 1.0).powf(1.0 / gamma);
+                    (c * max + offset).round()
+                };
+                [
+                    colour(r),
+                    colour(g),
+                    colour(b),
+                    (a.clamp(0.0, 1.0) * max).round(),
+                ]
+            })
+            .collect();
+        (channels, scrubbed)
+    }
+}
+
+fn to_sample(pixel: Pixel) -> [f32; 4] {
+    pixel.map(|c| c as f32 / u8::MAX as f32)
+}
+
+////////////////////////////////////////////////////////////////////////
+// Dithering: Per-pixel offsets added before rounding to the output's
+// levels. Each pattern's thresholds are evenly spread over
+// (-0.5, 0.5), so a flat value between two levels is rounded up in
+// the proportion that it lies between them.
+//
+
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+// Side of the tiled blue-noise pattern, and the standard deviation, in
+// pixels, of the Gaussian used to measure clustering in it.
+const BLUE_NOISE_SIZE: usize = 32;
+const BLUE_NOISE_SIGMA: f64 = 1.5;
+// Seed for the blue-noise pattern's initial points, so that output is
+// reproducible.
+const BLUE_NOISE_SEED: u64 = 0x626c7565;
+
+fn dither_offset(dither: Dither, x: usize, y: usize) -> f32 {
+    match dither {
+        Dither::None => 0.0,
+        Dither::Ordered => (BAYER[y % 8][x % 8] as f32 + 0.5) / 64.0 - 0.5,
+        Dither::BlueNoise => {
+            static RANKS: OnceLock<Vec<usize>> = OnceLock::new();
+            let ranks = RANKS.get_or_init(blue_noise_ranks);
+            let n = BLUE_NOISE_SIZE;
+            let rank = ranks[(y % n) * n + x % n];
+            (rank as f32 + 0.5) / (n * n) as f32 - 0.5
+        }
+    }
+}
+
+// A binary pattern on the blue-noise torus, along with each pixel's
+// Gaussian-weighted count of the set pixels around it.
+#[derive(Clone)]
+struct NoisePattern<'a> {
+    kernel: &'a [f64],
+    set: Vec<bool>,
+    energy: Vec<f64>,
+}
+
+impl NoisePattern<'_> {
+    fn toggle(&mut self, idx: usize) {
+        let n = BLUE_NOISE_SIZE;
+        self.set[idx] = !self.set[idx];
+        let sign = if self.set[idx] { 1.0 } else { -1.0 };
+        for (other, energy) in self.energy.iter_mut().enumerate() {
+            let dx = (other % n + n - idx % n) % n;
+            let dy = (other / n + n - idx / n) % n;
+            *energy += sign * self.kernel[dy * n + dx];
+        }
+    }
+
+    // The most crowded set pixel if `set`, otherwise the emptiest
+    // unset pixel.
+    fn extreme(&self, set: bool) -> usize {
+        let candidates = (0..self.set.len()).filter(|idx| self.set[*idx] == set);
+        let energy = |idx: &usize| self.energy[*idx];
+        if set {
+            candidates.max_by(|a, b| energy(a).total_cmp(&energy(b)))
+        } else {
+            candidates.min_by(|a, b| energy(a).total_cmp(&energy(b)))
+        }
+        .unwrap()
+    }
+}
+
+// Rank of each pixel of a blue-noise pattern, by Ulichney's
+// void-and-cluster method: pixels are added in turn to the largest
+// gap, so that every threshold gives evenly spread points.
+fn blue_noise_ranks() -> Vec<usize> {
+    let n = BLUE_NOISE_SIZE;
+    let count = n * n;
+    let wrap = |d: usize| d.min(n - d) as f64;
+    let kernel = (0..count)
+        .map(|idx| {
+            let (dx, dy) = (wrap(idx % n), wrap(idx / n));
+            (-(dx * dx + dy * dy) / (2.0 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp()
+        })
+        .collect::<Vec<_>>();
+    let mut pattern = NoisePattern {
+        kernel: &kernel,
+        set: vec![false; count],
+        energy: vec![0.0; count],
+    };
+
+    // Start from random points, and even them out by moving the most
+    // crowded point to the largest gap until that's where it was.
+    let initial = count / 10;
+    let mut index = 0;
+    while pattern.set.iter().filter(|s| **s).count() < initial {
+        let idx = (hash_u64(BLUE_NOISE_SEED, index) % count as u64) as usize;
+        if !pattern.set[idx] {
+            pattern.toggle(idx);
+        }
+        index += 1;
+    }
+    loop {
+        let cluster = pattern.extreme(true);
+        pattern.toggle(cluster);
+        let void = pattern.extreme(false);
+        if void == cluster {
+            pattern.toggle(cluster);
+            break;
+        }
+        pattern.toggle(void);
+    }
+
+    // Rank the initial points by removing the most crowded first, and
+    // the rest by filling the largest gaps.
+    let mut ranks = vec![0; count];
+    let mut removing = pattern.clone();
+    for rank in (0..initial).rev() {
+        let cluster = removing.extreme(true);
+        removing.toggle(cluster);
+        ranks[cluster] = rank;
+    }
+    for rank in initial..count {
+        let void = pattern.extreme(false);
+        pattern.toggle(void);
+        ranks[void] = rank;
+    }
+    ranks
+}
+
+////////////////////////////////////////////////////////////////////////
+// Letterboxing: Fitting a render of one aspect ratio into an output
+// of another, with bars filling the rest.
+//
+
+// The largest size with the given aspect ratio (width / height) that
+// fits within the output size.
+pub fn fit_aspect(width: usize, height: usize, aspect: f64) -> (usize, usize) {
+    assert!(aspect > 0.0);
+    if (width as f64) > height as f64 * aspect {
+        let fitted = (height as f64 * aspect).round() as usize;
+        (fitted.clamp(1, width), height)
+    } else {
+        let fitted = (width as f64 / aspect).round() as usize;
+        (width, fitted.clamp(1, height))
+    }
+}
+
+// Centre an RGBA image within a larger output, filling the bars
+// around it with the given colour. Works on either 8 or 16-bit
+// channels.
+pub fn letterbox<T: Copy>(
+    pixels: &[T],
+    width: usize,
+    height: usize,
+    out_width: usize,
+    out_height: usize,
+    fill: [T; 4],
+) -> Vec<T> {
+    assert_eq!(pixels.len(), width * height * 4);
+    assert!(width <= out_width && height <= out_height);
+    let (left, top) = ((out_width - width) / 2, (out_height - height) / 2);
+    let mut out = fill.repeat(out_width * out_height);
+    for (y, row) in pixels.chunks(width * 4).enumerate() {
+        let start = ((top + y) * out_width + left) * 4;
+        out[start..start + width * 4].copy_from_slice(row);
+    }
+    out
+}
+
+////////////////////////////////////////////////////////////////////////
+// Camera set-up, mapping pixels to rays.
+//
+
+struct View {
+    origin: Point4,
+    pixel_offset: (f64, f64),
+    x_start: f64,
+    x_step: f64,
+    y_start: f64,
+    y_step: f64,
+    axes: [Dir4; 3],
+}
+
+// Camera position for the given pan, in degrees, at unit distance from
+// the wormhole.
+fn camera_origin(pan: f64, mirror_x: bool) -> Point4 {
+    let pan_rad = pan * std::f64::consts::PI / 180.0;
+    Point4 {
+        x: if mirror_x { -1.0 } else { 1.0 } * pan_rad.sin(),
+        y: 0.0,
+        z: -pan_rad.cos(),
+        w: 1.0,
+    }
+}
+
+// A camera's position and orientation. The axes are the world
+// directions of the camera-space x (towards the left of the image
+// when left-handed), y (towards the bottom of the image) and z
+// (forwards) directions.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraFrame {
+    pub origin: Point4,
+    pub axes: [Dir4; 3],
+}
+
+// The camera frame for the given tilt, turn and pan, in degrees.
+pub fn camera_frame(conf: &CanvasConfig, tilt: f64, turn: f64, pan: f64) -> CameraFrame {
+    let tilt_rad = -tilt * std::f64::consts::PI / 180.0;
+    let turn_rad = -turn * std::f64::consts::PI / 180.0;
+    let pan_rad = pan * std::f64::consts::PI / 180.0;
+    let (tilt_cos, tilt_sin) = (tilt_rad.cos(), tilt_rad.sin());
+    let (turn_cos, turn_sin) = (turn_rad.cos(), turn_rad.sin());
+    let (pan_cos, pan_sin) = (pan_rad.cos(), pan_rad.sin());
+
+    // Set the camera position.
+    let mirror_x = conf.handedness == Handedness::Right;
+    let origin = camera_origin(pan, mirror_x);
+
+    let rotate = |x: f64, y: f64, z: f64| {
+        let tx = x;
+        let ty = y * tilt_cos + z * tilt_sin;
+        let tz = -y * tilt_sin + z * tilt_cos;
+
+        let t2x = tx * turn_cos + tz * turn_sin;
+        let t2y = ty;
+        let t2z = -tx * turn_sin + tz * turn_cos;
+
+        // And rotate the looking direction to be centered around (0, 0, 0)
+        let x = t2x * pan_cos - t2z * pan_sin;
+        Dir4 {
+            x: if mirror_x { -x } else { x },
+            y: t2y,
+            z: t2x * pan_sin + t2z * pan_cos,
+            w: 0.0,
+        }
+    };
+
+    CameraFrame {
+        origin,
+        axes: [
+            rotate(1.0, 0.0, 0.0),
+            rotate(0.0, 1.0, 0.0),
+            rotate(0.0, 0.0, 1.0),
+        ],
+    }
+}
+
+// The camera frame at an arbitrary origin, with tilt and turn as for
+// `camera_frame` with no pan, and then rolled by rotating the camera's
+// x axis towards its y axis by `roll` degrees.
+pub fn camera_frame_at(
+    conf: &CanvasConfig,
+    origin: Point4,
+    tilt: f64,
+    turn: f64,
+    roll: f64,
+) -> CameraFrame {
+    let roll_rad = roll * std::f64::consts::PI / 180.0;
+    let (roll_cos, roll_sin) = (roll_rad.cos(), roll_rad.sin());
+    let [x_axis, y_axis, z_axis] = camera_frame(conf, tilt, turn, 0.0).axes;
+    CameraFrame {
+        origin,
+        axes: [
+            x_axis.scale(roll_cos).add(y_axis.scale(roll_sin)),
+            y_axis.scale(roll_cos).sub(x_axis.scale(roll_sin)),
+            z_axis,
+        ],
+    }
+}
+
+impl View {
+    fn new(conf: &CanvasConfig, tilt: f64, turn: f64, pan: f64) -> View {
+        View::from_frame(conf, &camera_frame(conf, tilt, turn, pan))
+    }
+
+    fn from_frame(conf: &CanvasConfig, frame: &CameraFrame) -> View {
+        let (x_scale, y_scale) = conf.view_scale();
+
+        // Invariants: start + step * (size - 1)/2 = 0.
+        let x_range = x_scale * 2.0;
+        let x_step = -x_range / conf.width as f64;
+        let x_start = -0.5 * x_step * (conf.width - 1) as f64;
+
+        let y_range = y_scale * 2.0;
+        let y_step = -y_range / conf.height as f64;
+        let y_start = -0.5 * y_step * (conf.height - 1) as f64;
+
+        View {
+            origin: frame.origin,
+            pixel_offset: conf.pixel_offset,
+            x_start,
+            x_step,
+            y_start,
+            y_step,
+            axes: frame.axes,
+        }
+    }
+
+    // Direction of the ray through the given pixel coordinates. Pixel
+    // centres are at integer coordinates, before applying the pixel
+    // offset.
+    fn dir(&self, px: f64, py: f64) -> Dir4 {
+        let x = self.x_start + (px + self.pixel_offset.0) * self.x_step;
+        let y = self.y_start + (py + self.pixel_offset.1) * self.y_step;
+        let [x_axis, y_axis, z_axis] = self.axes;
+        x_axis.scale(x).add(y_axis.scale(y)).add(z_axis)
+    }
+
+    // The inverse of `dir`: the pixel coordinates a direction from the
+    // origin passes through, or None if it points sideways or behind.
+    fn pixel(&self, dir: Dir4) -> Option<(f64, f64)> {
+        let [x, y, z] = self.axes.map(|axis| dir.dot(axis.norm()) / axis.len());
+        if z <= EPSILON {
+            return None;
+        }
+        let px = (x / z - self.x_start) / self.x_step - self.pixel_offset.0;
+        let py = (y / z - self.y_start) / self.y_step - self.pixel_offset.1;
+        Some((px, py))
+    }
+}
+
+// The origin and direction of the ray through the given pixel
+// coordinates, for querying single rays. Pixel centres are at integer
+// coordinates, as in rendering.
+pub fn camera_ray(
+    conf: &CanvasConfig,
+    tilt: f64,
+    turn: f64,
+    pan: f64,
+    px: f64,
+    py: f64,
+) -> (Point4, Dir4) {
+    let view = View::new(conf, tilt, turn, pan);
+    (view.origin, view.dir(px, py))
+}
+
+////////////////////////////////////////////////////////////////////////
+// Parallel transport: Moving a camera frame across the surface while
+// keeping it as unrotated as the curved space allows, so that an
+// observer moving through the space sees the horizon evolve smoothly.
+//
+// We move between the positions in small steps, and after each step
+// project the axes orthogonally into the tangent space at the new
+// point, restoring their lengths. In the limit of small steps, this is
+// the surface's (Levi-Civita) connection, preserving lengths and
+// angles between the axes. The straight line between the positions
+// may pass through the throat, where there's no surface, so we go
+// round the wormhole's axis instead, interpolating the distance from
+// it and turning between the directions to the positions.
+//
+// Camera frames built from tilt, turn and pan have w = 0 axes, which
+// the tracer treats as the tangent vectors above them, so we first
+// lift the axes vertically into the tangent space.
+//
+
+// Largest 3D distance moved between re-projections of the axes.
+const TRANSPORT_STEP: f64 = 0.01;
+
+impl Tracer {
+    // Move the frame to a new origin by parallel transport around the
+    // wormhole's axis. None if the path can't be projected onto the
+    // surface, as when an origin is inside the throat.
+    pub fn transport_frame(&self, frame: &CameraFrame, origin: Point4) -> Option<CameraFrame> {
+        let start = self.project_vertical(frame.origin)?;
+        let norm = self.normal_at(start);
+        let mut axes = frame.axes.map(|v| Dir4 {
+            w: v.w - v.dot(norm) / norm.w,
+            ..v
+        });
+
+        let (start_r, end_r) = (dist_3d(frame.origin), dist_3d(origin));
+        if start_r <= 0.0 || end_r <= 0.0 {
+            return None;
+        }
+        let flat = |p: Point4| Dir4 { w: 0.0, ..p };
+        let start_dir = flat(frame.origin).scale(start_r.recip());
+        let end_dir = flat(origin).scale(end_r.recip());
+        let angle = start_dir.dot(end_dir).clamp(-1.0, 1.0).acos();
+        // The direction, perpendicular to start_dir, that we turn
+        // towards. Opposite directions don't give one, so then we turn
+        // around the y axis, as panning does.
+        let perp = end_dir.sub(start_dir.scale(start_dir.dot(end_dir)));
+        let perp = if perp.len() > EPSILON {
+            perp.norm()
+        } else {
+            let around_y = Dir4 {
+                x: start_dir.z,
+                y: 0.0,
+                z: -start_dir.x,
+                w: 0.0,
+            };
+            if around_y.len() > EPSILON {
+                around_y.norm()
+            } else {
+                Dir4 {
+                    x: 0.0,
+                    y: -start_dir.z,
+                    z: start_dir.y,
+                    w: 0.0,
+                }
+                .norm()
+            }
+        };
+
+        let path_len = angle * start_r.max(end_r) + (end_r - start_r).abs();
+        let steps = (path_len / TRANSPORT_STEP).ceil();
+        for i in 1..=steps as usize {
+            let t = i as f64 / steps;
+            let r = start_r + t * (end_r - start_r);
+            let dir = start_dir
+                .scale((t * angle).cos())
+                .add(perp.scale((t * angle).sin()));
+            let p = Point4 {
+                w: frame.origin.w + t * (origin.w - frame.origin.w),
+                ..dir.scale(r)
+            };
+            let norm = self.normal_at(self.project_vertical(p)?).norm();
+            axes = axes.map(|v| v.sub(norm.scale(v.dot(norm))).norm().scale(v.len()));
+        }
+        Some(CameraFrame { origin, axes })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Fixed-step renderer
+//
+
+// The geometry of a traced ray, for uses that don't need its colour.
+pub struct GeometryResult {
+    // Direction of the final step, which determines the colour.
+    pub final_dir: Dir4,
+    pub path_len: f64,
+    // Closest approach to the wormhole's axis, ignoring w.
+    pub min_dist: f64,
+    // Number of times the ray crossed the throat (w changed sign).
+    pub crossings: usize,
+    pub steps: usize,
+    // Newton-Raphson iterations taken by the steps, including retries
+    // with smaller steps, but not the projections that start the ray.
+    pub newton_iters: usize,
+    // Stopped by the clip plane.
+    pub clipped: bool,
+    // Fell into a black hole, or was still orbiting it after
+    // MAX_TRACE_STEPS, so never escaped.
+    pub captured: bool,
+    // Did the ray start on the positive-w side, where the camera is?
+    pub start_positive_w: bool,
+}
+
+impl GeometryResult {
+    fn new(start: Point4) -> GeometryResult {
+        GeometryResult {
+            final_dir: Dir4 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+                w: 0.0,
+            },
+            path_len: 0.0,
+            min_dist: dist_3d(start),
+            crossings: 0,
+            steps: 0,
+            newton_iters: 0,
+            clipped: false,
+            captured: false,
+            start_positive_w: start.w >= 0.0,
+        }
+    }
+
+    // Did the ray escape on the positive-w side?
+    pub fn positive_w(&self) -> bool {
+        self.final_dir.w > 0.0
+    }
+
+    // Did the ray reach infinity, so that its final direction can be
+    // looked up?
+    pub fn escaped(&self) -> bool {
+        !self.clipped && !self.captured
+    }
+}
+
+// Distance from the origin, ignoring w.
+fn dist_3d(p: Point4) -> f64 {
+    (p.x * p.x + p.y * p.y + p.z * p.z).sqrt()
+}
+
+// Colour of rays removed by the clip plane.
+const CLIPPED_COLOUR: Pixel = [0, 0, 0, 0];
+
+// Colour of rays captured by a black hole.
+const CAPTURED_COLOUR: Pixel = [0, 0, 0, 255];
+
+// Steps after which a ray near a black hole is assumed to be orbiting
+// forever, and taken as captured. Far more than any ray reaching
+// infinity should need at typical step sizes. Other surfaces have no
+// orbits, so their rays aren't capped.
+const MAX_TRACE_STEPS: usize = 100_000;
+
+// Colour of rays whose tracing panicked, with `catch_panics` set.
+const PANIC_COLOUR: Pixel = [255, 0, 255, 255];
+
+// Number of rows `render_scanlines` renders in parallel before
+// passing them on.
+const SCANLINE_BATCH: usize = 64;
+
+// A future that is pending the first time it's polled, waking itself
+// immediately, to give the executor a chance to run other tasks.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// Callback for render progress, given the number of rows done and the
+// total. Calls are made one at a time, once for each count, in order.
+pub type Progress<'a> = &'a (dyn Fn(usize, usize) + Sync);
+
+// The result of a render that may be cancelled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RenderOutcome {
+    // The finished image's pixels.
+    Complete(Vec<u8>),
+    // The cancel flag was set before the render finished, and the
+    // partial image was discarded.
+    Cancelled,
+}
+
+impl RenderOutcome {
+    // The pixels, if the render completed.
+    pub fn complete(self) -> Option<Vec<u8>> {
+        match self {
+            RenderOutcome::Complete(pixels) => Some(pixels),
+            RenderOutcome::Cancelled => None,
+        }
+    }
+}
+
+// Runs a render's rows as parallel tasks, each of which bails out if
+// the render has been cancelled. Completed rows are reported to the
+// progress callback, counting and reporting under one lock so that
+// the counts arrive in order.
+struct RowTasks<'a> {
+    done: Mutex<usize>,
+    total: usize,
+    progress: Option<Progress<'a>>,
+    cancel: Option<&'a AtomicBool>,
+}
+
+impl<'a> RowTasks<'a> {
+    fn new(
+        total: usize,
+        progress: Option<Progress<'a>>,
+        cancel: Option<&'a AtomicBool>,
+    ) -> RowTasks<'a> {
+        RowTasks {
+            done: Mutex::new(0),
+            total,
+            progress,
+            cancel,
+        }
+    }
+
+    // Render each row with `render_row`, concatenating the rows in
+    // order. Returns None if cancelled.
+    fn run<T: Send>(
+        &self,
+        rows: Range<usize>,
+        render_row: impl Fn(usize) -> Vec<T> + Sync,
+    ) -> Option<Vec<T>> {
+        let rows = rows
+            .into_par_iter()
+            .map(|y| {
+                if self
+                    .cancel
+                    .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+                {
+                    return None;
+                }
+                let row = render_row(y);
+                self.row_done();
+                Some(row)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(rows.into_iter().flatten().collect())
+    }
+
+    fn row_done(&self) {
+        if let Some(progress) = self.progress {
+            let mut done = self.done.lock().unwrap();
+            *done += 1;
+            progress(*done, self.total);
+        }
+    }
+}
+
+// Progress reporting and cancellation for long renders.
+pub struct RenderControl<'a> {
+    // Called as each row completes.
+    pub progress: Progress<'a>,
+    // Checked before each row is started. Once set, the render stops
+    // as soon as the rows in flight finish.
+    pub cancel: &'a AtomicBool,
+}
+
+// A rectangle of the canvas, in pixels, for rendering part of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Tracer {
+    // Render a whole scene by tracing all the rays in the canvas. If
+    // the cancel flag is set, rows not yet started are skipped and
+    // the render is abandoned.
+    pub fn render(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+        cancel: Option<&AtomicBool>,
+    ) -> RenderOutcome {
+        let view = View::new(conf, tilt, turn, pan);
+        match self.render_view(conf, &view, step_size, None, cancel) {
+            Some(buffer) => RenderOutcome::Complete(buffer.finalize(conf.tone_map, conf.gamma)),
+            None => RenderOutcome::Cancelled,
+        }
+    }
+
+    // As `render`, but to tightly-packed RGB, 3 bytes per pixel.
+    pub fn render_rgb(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> Vec<u8> {
+        self.render_buffer(conf, tilt, turn, pan, step_size)
+            .finalize_rgb(conf.tone_map, conf.gamma)
+    }
+
+    // Render a whole scene into a buffer of samples, for the caller
+    // to finalize.
+    pub fn render_buffer(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> RenderBuffer {
+        let view = View::new(conf, tilt, turn, pan);
+        self.render_view(conf, &view, step_size, None, None)
+            .expect("Render cancelled without a cancel flag")
+    }
+
+    // As `render_buffer`, reporting progress as rows complete.
+    // Anti-aliasing modes that make two passes over the image count
+    // each pass's rows towards the total.
+    pub fn render_buffer_with_progress(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+        progress: Progress,
+    ) -> RenderBuffer {
+        let view = View::new(conf, tilt, turn, pan);
+        self.render_view(conf, &view, step_size, Some(progress), None)
+            .expect("Render cancelled without a cancel flag")
+    }
+
+    // Render a whole scene into a buffer of samples, from an
+    // arbitrary camera frame.
+    pub fn render_from_frame(
+        &self,
+        conf: &CanvasConfig,
+        frame: &CameraFrame,
+        step_size: Option<f64>,
+    ) -> RenderBuffer {
+        self.render_view(conf, &View::from_frame(conf, frame), step_size, None, None)
+            .expect("Render cancelled without a cancel flag")
+    }
+
+    fn render_view(
+        &self,
+        conf: &CanvasConfig,
+        view: &View,
+        step_size: Option<f64>,
+        progress: Option<Progress>,
+        cancel: Option<&AtomicBool>,
+    ) -> Option<RenderBuffer> {
+        match conf.antialias {
+            Antialias::Supersample(grid) if grid > 1 => {
+                let tasks = RowTasks::new(conf.height, progress, cancel);
+                self.render_supersampled(conf, view, step_size, grid, &tasks)
+            }
+            Antialias::DepthEdge | Antialias::CrossingEdge => {
+                let tasks = RowTasks::new(2 * conf.height, progress, cancel);
+                self.render_edge_aa(conf, view, step_size, &tasks)
+            }
+            _ => {
+                let tasks = RowTasks::new(conf.height, progress, cancel);
+                let pixels = tasks.run(0..conf.height, |y| {
+                    self.render_rows(conf, view, step_size, y..y + 1)
+                })?;
+                Some(RenderBuffer::from_pixels(conf.width, conf.height, &pixels))
+            }
+        }
+    }
+
+    // Render the scene, passing each row of pixels to `sink` strictly
+    // in order, so that the whole image need not be held in
+    // memory. Batches of rows are rendered ahead in parallel, and
+    // tone mapped and gamma corrected as `render` does. Anti-aliasing
+    // isn't supported, and must be turned off.
+    pub fn render_scanlines(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+        mut sink: impl FnMut(&[u8]),
+    ) {
+        assert_eq!(
+            conf.antialias,
+            Antialias::None,
+            "Scanline renders can't be anti-aliased"
+        );
+        let view = View::new(conf, tilt, turn, pan);
+        for start in (0..conf.height).step_by(SCANLINE_BATCH) {
+            let end = (start + SCANLINE_BATCH).min(conf.height);
+            let pixels = self.render_rows(conf, &view, step_size, start..end);
+            RenderBuffer::from_pixels(conf.width, end - start, &pixels)
+                .finalize(conf.tone_map, conf.gamma)
+                .chunks(conf.width * 4)
+                .for_each(&mut sink);
+        }
+    }
+
+    // Render a whole scene as `render` does, yielding to the executor
+    // between batches of rows, so that a single-threaded environment
+    // such as the browser can keep the UI responsive. Anti-aliased
+    // renders are done in one go.
+    pub async fn render_async(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> Vec<u8> {
+        if conf.antialias != Antialias::None {
+            return self
+                .render_buffer(conf, tilt, turn, pan, step_size)
+                .finalize(conf.tone_map, conf.gamma);
+        }
+
+        let view = View::new(conf, tilt, turn, pan);
+        let mut pixels = Vec::with_capacity(conf.width * conf.height * 4);
+        for start in (0..conf.height).step_by(SCANLINE_BATCH) {
+            let end = (start + SCANLINE_BATCH).min(conf.height);
+            pixels.extend(self.render_rows(conf, &view, step_size, start..end));
+            YieldNow(false).await;
+        }
+        RenderBuffer::from_pixels(conf.width, conf.height, &pixels)
+            .finalize(conf.tone_map, conf.gamma)
+    }
+
+    // Render just the given range of rows of the scene, without
+    // anti-aliasing, so that callers can process the image in strips.
+    // The tone map and gamma are left to the caller.
+    pub fn render_region(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+        rows: Range<usize>,
+    ) -> Vec<u8> {
+        let view = View::new(conf, tilt, turn, pan);
+        self.render_rows(conf, &view, step_size, rows)
+    }
+
+    // Render just the given tile of the scene, without anti-aliasing.
+    // Rays are cast as for the whole canvas, so stitching tiles
+    // together reproduces `render`'s output exactly.
+    pub fn render_tile(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+        tile: &Tile,
+    ) -> Vec<u8> {
+        assert!(tile.x0 + tile.width <= conf.width && tile.y0 + tile.height <= conf.height);
+        let view = View::new(conf, tilt, turn, pan);
+        let pixels = self.render_rect(
+            &view,
+            step_size,
+            tile.x0..tile.x0 + tile.width,
+            tile.y0..tile.y0 + tile.height,
+        );
+        RenderBuffer::from_pixels(tile.width, tile.height, &pixels)
+            .finalize(conf.tone_map, conf.gamma)
+    }
+
+    // Render a whole scene as `render` does, without anti-aliasing,
+    // under the given control.
+    pub fn render_controlled(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+        control: &RenderControl,
+    ) -> RenderOutcome {
+        match self.render_buffer_controlled(conf, tilt, turn, pan, step_size, control) {
+            Some(buffer) => RenderOutcome::Complete(buffer.finalize(conf.tone_map, conf.gamma)),
+            None => RenderOutcome::Cancelled,
+        }
+    }
+
+    // As `render_controlled`, into a buffer of samples for the caller
+    // to finalize. None if cancelled.
+    pub fn render_buffer_controlled(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+        control: &RenderControl,
+    ) -> Option<RenderBuffer> {
+        let view = View::new(conf, tilt, turn, pan);
+        let tasks = RowTasks::new(conf.height, Some(control.progress), Some(control.cancel));
+        let pixels = tasks.run(0..conf.height, |y| {
+            self.render_rows(conf, &view, step_size, y..y + 1)
+        })?;
+        Some(RenderBuffer::from_pixels(conf.width, conf.height, &pixels))
+    }
+
+    // Render the scene in linear floating-point RGBA, without
+    // anti-aliasing. Unlike `render`, colours aren't clamped, so HDR
+    // env maps' highlights come through above 1.0. `encode_srgb`
+    // converts the result to 8-bit pixels.
+    pub fn render_hdr(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> Vec<[f32; 4]> {
+        let view = View::new(conf, tilt, turn, pan);
+        (0..conf.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let view = &view;
+                (0..conf.width).map(move |x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    match self.trace_geometry_caught(view.origin, dir, step_size) {
+                        Some(geom) => self.shade_linear(&geom),
+                        None => decode_srgb(PANIC_COLOUR),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // Render a range of rows in parallel, without anti-aliasing.
+    fn render_rows(
+        &self,
+        conf: &CanvasConfig,
+        view: &View,
+        step_size: Option<f64>,
+        rows: Range<usize>,
+    ) -> Vec<u8> {
+        self.render_rect(view, step_size, 0..conf.width, rows)
+    }
+
+    // Render a rectangle of pixels in parallel, row by row, without
+    // anti-aliasing.
+    fn render_rect(
+        &self,
+        view: &View,
+        step_size: Option<f64>,
+        cols: Range<usize>,
+        rows: Range<usize>,
+    ) -> Vec<u8> {
+        rows.into_par_iter()
+            .flat_map_iter(|y| {
+                cols.clone().flat_map(move |x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    self.trace_ray(view.origin, dir, step_size).0
+                })
+            })
+            .collect::<Vec<u8>>()
+    }
+
+    // Trace a single ray with the fixed-step or adaptive tracer,
+    // returning the colour and path length. Panics are caught if
+    // `catch_panics` is set.
+    fn trace_ray(&self, p: Point4, dir: Dir4, step_size: Option<f64>) -> (Pixel, f64) {
+        let (colour, geom) = self.trace_ray_geometry(p, dir, step_size);
+        (colour, geom.map_or(0.0, |geom| geom.path_len))
+    }
+
+    // As `trace_ray`, but returning the ray's geometry, or None if it
+    // panicked.
+    fn trace_ray_geometry(
+        &self,
+        p: Point4,
+        dir: Dir4,
+        step_size: Option<f64>,
+    ) -> (Pixel, Option<GeometryResult>) {
+        let geom = self.trace_geometry_caught(p, dir, step_size);
+        let colour = geom.as_ref().map_or(PANIC_COLOUR, |geom| self.shade(geom));
+        (colour, geom)
+    }
+
+    // Trace a ray's geometry, returning None if it panicked with
+    // `catch_panics` set.
+    fn trace_geometry_caught(
+        &self,
+        p: Point4,
+        dir: Dir4,
+        step_size: Option<f64>,
+    ) -> Option<GeometryResult> {
+        if self.catch_panics {
+            return std::panic::catch_unwind(|| self.trace_geometry(p, dir, step_size)).ok();
+        }
+        Some(self.trace_geometry(p, dir, step_size))
+    }
+
+    // The colour of a traced ray.
+    fn shade(&self, geom: &GeometryResult) -> Pixel {
+        if geom.clipped {
+            CLIPPED_COLOUR
+        } else if geom.captured {
+            CAPTURED_COLOUR
+        } else {
+            self.colour_for(geom.final_dir)
+        }
+    }
+
+    // As `shade`, but the unclamped linear colour.
+    fn shade_linear(&self, geom: &GeometryResult) -> [f32; 4] {
+        if geom.clipped {
+            decode_srgb(CLIPPED_COLOUR)
+        } else if geom.captured {
+            decode_srgb(CAPTURED_COLOUR)
+        } else {
+            let final_dir = geom.final_dir;
+            self.env_map_for(final_dir.w > 0.0).colour_f32(final_dir)
+        }
+    }
+
+    // Trace a single ray with the fixed-step or adaptive tracer,
+    // returning its geometry without looking up a colour.
+    pub fn trace_geometry(&self, p: Point4, dir: Dir4, step_size: Option<f64>) -> GeometryResult {
+        if let Some(step_size) = step_size {
+            self.trace(p, dir, step_size)
+        } else {
+            self.trace_adaptive(p, dir)
+        }
+    }
+
+    // Trace a single ray.
+    fn trace(&self, p: Point4, dir: Dir4, step_size: f64) -> GeometryResult {
+        let (mut p, mut old_p, mut dir) = self.start_fixed(p, dir, step_size);
+
+        let mut geom = GeometryResult::new(p);
+        while p.len() < self.infinity {
+            let (step, iters) = self.advance(p, dir, step_size);
+            geom.newton_iters += iters;
+            if let Some((new_p, new_dir)) = step {
+                (p, old_p, dir) = (new_p, p, new_dir);
+            } else {
+                panic!("trace_aux could not extend path");
+            }
+
+            if self.record_step(&mut geom, p, old_p) {
+                break;
+            }
+        }
+
+        geom.final_dir = self.fixed_final_dir(p, old_p, dir);
+        geom
+    }
+
+    // Start a fixed-step ray, returning its first point on the
+    // surface, the point a step behind it, and its unit direction of
+    // travel.
+    fn start_fixed(&self, p: Point4, dir: Dir4, step_size: f64) -> (Point4, Point4, Dir4) {
+        let delta = dir.norm().scale(step_size);
+        let p = self.project_vertical(p).unwrap();
+        let old_p = self.project_vertical(p.sub(delta)).unwrap();
+        let dir = match self.integrator {
+            Integrator::Projection => p.sub(old_p).norm(),
+            Integrator::Rk4 => self.vertical_tangent_dir(p, dir),
+        };
+        (p, old_p, dir)
+    }
+
+    // Advance a fixed-step ray at p travelling in the unit direction
+    // dir by a step, with the tracer's integrator. Returns the new
+    // point and direction, or None if the step failed, and the
+    // Newton-Raphson iterations taken.
+    fn advance(&self, p: Point4, dir: Dir4, step_size: f64) -> (Option<(Point4, Dir4)>, usize) {
+        match self.integrator {
+            Integrator::Projection => {
+                let norm = self.normal_at(p).norm();
+                let (new_p, iters) = self.step_counted(p, dir.scale(step_size), norm);
+                (new_p.map(|new_p| (new_p, new_p.sub(p).norm())), iters)
+            }
+            Integrator::Rk4 => self.step_rk4(p, dir, step_size),
+        }
+    }
+
+    // The final direction of a fixed-step ray. Projection uses the
+    // chord of the last step, while RK4 tracks the direction itself,
+    // which is more accurate.
+    fn fixed_final_dir(&self, p: Point4, old_p: Point4, dir: Dir4) -> Dir4 {
+        match self.integrator {
+            Integrator::Projection => p.sub(old_p),
+            Integrator::Rk4 => dir,
+        }
+    }
+
+    // Record a step in the ray's geometry, returning true if the ray
+    // should stop, due to the clip plane, being captured, or having
+    // crossed the throat more than `max_crossings` times.
+    fn record_step(&self, geom: &mut GeometryResult, p: Point4, old_p: Point4) -> bool {
+        geom.path_len += p.sub(old_p).len();
+        geom.min_dist = geom.min_dist.min(dist_3d(p));
+        geom.steps += 1;
+        if p.w.signum() != old_p.w.signum() {
+            geom.crossings += 1;
+        }
+        geom.clipped = self.is_clipped(p);
+        geom.captured =
+            self.black_hole.is_some() && (geom.crossings > 0 || geom.steps >= MAX_TRACE_STEPS);
+        geom.clipped || geom.captured || self.max_crossings.is_some_and(|max| geom.crossings > max)
+    }
+
+    // Is the point on the removed side of the clip plane?
+    fn is_clipped(&self, p: Point4) -> bool {
+        self.clip_plane
+            .is_some_and(|(origin, normal)| p.sub(origin).dot(normal) < 0.0)
+    }
+
+    // Look up the colour for a ray escaping with the given direction.
+    fn colour_for(&self, final_dir: Dir4) -> Pixel {
+        self.env_map_for(final_dir.w > 0.0).colour(final_dir)
+    }
+
+    // The env map sampled by rays ending up on the given side.
+    fn env_map_for(&self, positive_w: bool) -> &EnvMap {
+        if positive_w || self.pos_map_only {
+            &self.env_map_pos
+        } else {
+            &self.env_map_neg
+        }
+    }
+
+    // Take a single step, as the fixed-step tracer does. Along with
+    // `dist`, `project_vertical` and `normal_at`, this is public so
+    // that other integration schemes can be built on the solver from
+    // outside the crate.
+    pub fn step_once(&self, p: Point4, delta: Dir4, norm: Dir4) -> Option<Point4> {
+        self.step(p, delta, norm)
+    }
+
+    // Take a step from p in direction delta, constrained to the
+    // surface in direction norm.
+    fn step(&self, p: Point4, delta: Dir4, norm: Dir4) -> Option<Point4> {
+        self.step_counted(p, delta, norm).0
+    }
+
+    // As `step`, also returning the Newton-Raphson iterations taken.
+    fn step_counted(&self, p: Point4, delta: Dir4, norm: Dir4) -> (Option<Point4>, usize) {
+        let mut delta = delta;
+        // If curvature is extreme, there may be no intersection,
+        // because the normal at p and the normal at the intersection
+        // point are sufficiently different. We try again with a
+        // smaller step.
+        //
+        // An example of extreme curvature is the "wormhole" surface
+        // with w_scale around e.g. 0.01.
+        const MAX_ITER: usize = 8;
+        let mut new_p = None;
+        let mut iter = 0;
+        let mut newton_iters = 0;
+        while new_p.is_none() && iter < MAX_ITER {
+            // If it takes too many iterations, we're probably best
+            // off taking a smaller step, so set the Newton-Raphson
+            // convergence iterations low.
+            let iters;
+            (new_p, iters) = self.intersect_line_counted(p + delta, norm, 3);
+            newton_iters += iters;
+            delta = delta * 0.5;
+            iter += 1;
+        }
+        (new_p, newton_iters)
+    }
+
+    // Not a true distance, but the implicit surface function, where
+    // the surface is all points where dist == 0.
+    pub fn dist(&self, point: Point4) -> f64 {
+        if let Some(expr) = &self.dist_expr {
+            return expr.eval(&[point.x, point.y, point.z, point.w]);
+        }
+
+        // Flamm's paraboloid: the distance from the centre grows with
+        // the square of w, from the horizon at w = 0.
+        if let Some(r_s) = self.black_hole {
+            return dist_3d(point) - r_s - point.w * point.w / (4.0 * r_s);
+        }
+
+        let origin = Point4 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            w: 0.0,
+        };
+        let central = self.throat_dist(point, origin, self.w_scale, self.radius);
+        self.extra_throats.iter().fold(central, |d, throat| {
+            let throat_d = self.throat_dist(point, throat.centre, throat.w_scale, throat.radius);
+            smooth_min(d, throat_d, self.throat_blend)
+        })
+    }
+
+    // The surface function of a single throat centred at the given
+    // point. Taking the minimum over throats gives a surface whose
+    // height in w is the least of theirs, so that it has a hole
+    // joining the two sides at each throat.
+    fn throat_dist(&self, point: Point4, centre: Point4, w_scale: f64, radius: f64) -> f64 {
+        let w_scale = effective_w_scale(w_scale);
+        let (x, y, z, w) = (
+            point.x - centre.x,
+            point.y - centre.y,
+            point.z - centre.z,
+            point.w / w_scale,
+        );
+        if self.compensated_dist {
+            return compensated_dot(&[(x, x), (y, y), (z, z), (w, -w)], -radius);
+        }
+        x * x + y * y + z * z - w * w - radius
+    }
+
+    fn intersect_line(&self, point: Point4, direction: Dir4, max_iters: usize) -> Option<Point4> {
+        self.intersect_line_counted(point, direction, max_iters).0
+    }
+
+    // As `intersect_line`, also returning the iterations taken,
+    // counting the one that finds it has converged.
+    fn intersect_line_counted(
+        &self,
+        point: Point4,
+        direction: Dir4,
+        max_iters: usize,
+    ) -> (Option<Point4>, usize) {
+        // Newton-Raphson solver on dist(point + lambda direction)
+        let mut lambda = 0.0;
+        for iter in 0..max_iters {
+            let guess = point + direction * lambda;
+            let guess_val = self.dist(guess);
+            if guess_val.abs() < EPSILON {
+                return (Some(guess), iter + 1);
+            }
+
+            let guess2 = point + direction * (lambda + EPSILON);
+            let guess2_val = self.dist(guess2);
+
+            let dguess_val = (guess2_val - guess_val) / EPSILON;
+
+            lambda -= guess_val / dguess_val;
+        }
+
+        // Could fall back to binary chop, but as it generally seems
+        // to converge in <= 2 iterations if there is a solution, this
+        // seems excessive.
+        (None, max_iters)
+    }
+
+    // Intersect the surface with a line in the w-axis from the
+    // point. Outside the throat, the line meets the surface on both
+    // sides, and we take the intersection on the side the point's w
+    // is on, with w = 0 counting as positive. Intersections within
+    // EPSILON of w = 0, where the sides meet, suit either side.
+    pub fn project_vertical(&self, point: Point4) -> Option<Point4> {
+        const VERTICAL: Dir4 = Dir4 {
+            x: 0.0,
             y: 0.0,
-            z: -pan_cos,
+            z: 0.0,
             w: 1.0,
         };
+        let negative = point.w < 0.0;
+        let on_side = |p: &Point4| p.w.abs() <= EPSILON || (p.w < 0.0) == negative;
+        // Plenty of iterations to converge, since the starting point
+        // may be far from the intersection.
+        self.intersect_line(point, VERTICAL, 10)
+            .filter(on_side)
+            .or_else(|| {
+                // Near w = 0 the surface function is nearly flat in w,
+                // so the solver can diverge or overshoot to the other
+                // side. Retry from further out on the point's side.
+                let w = if negative { -1.0 } else { 1.0 };
+                self.intersect_line(Point4 { w, ..point }, VERTICAL, 10)
+                    .filter(on_side)
+            })
+    }
+
+    // Whether a camera at the given origin is on the positive-w side
+    // of the wormhole, once projected onto the surface. None if it
+    // can't be projected, being inside the throat.
+    pub fn camera_side(&self, origin: Point4) -> Option<bool> {
+        self.project_vertical(origin).map(|p| p.w >= 0.0)
+    }
+
+    // Calculate a normal vector using finite differences. Not
+    // normalised, and scaled by EPSILON.
+    pub fn normal_at(&self, p: Point4) -> Dir4 {
+        let base_dist = self.dist(p);
+        Dir4 {
+            x: self.dist(Point4 {
+                x: p.x + EPSILON,
+                ..p
+            }) - base_dist,
+            y: self.dist(Point4 {
+                y: p.y + EPSILON,
+                ..p
+            }) - base_dist,
+            z: self.dist(Point4 {
+                z: p.z + EPSILON,
+                ..p
+            }) - base_dist,
+            w: self.dist(Point4 {
+                w: p.w + EPSILON,
+                ..p
+            }) - base_dist,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Adaptive tracer
+//
+
+const TARGET_NORM_DIFF: f64 = 1.0e-4;
+const BASE_ADAPTIVE_STEP: f64 = 0.01;
+const MAX_ADAPTIVE_STEP: f64 = 0.1;
+
+impl Tracer {
+    // Trace a single ray.
+    fn trace_adaptive(&self, p: Point4, dir: Dir4) -> GeometryResult {
+        self.trace_adaptive_from(p, dir, BASE_ADAPTIVE_STEP).0
+    }
+
+    // Trace a single ray, starting from the given step size. Also
+    // returns the step size chosen after the first step.
+    fn trace_adaptive_from(
+        &self,
+        p: Point4,
+        dir: Dir4,
+        initial_step: f64,
+    ) -> (GeometryResult, f64) {
+        // We'll adapt the step size, so that the optimal size from
+        // the previous step is used for the next one.
+        let mut step_size = initial_step;
+        let mut first_step = None;
+        // The initial direction is found from a point a base step
+        // back, whatever the initial step, as a longer chord would
+        // skew it.
+        let delta = dir.norm().scale(BASE_ADAPTIVE_STEP);
+        let mut p = self.project_vertical(p).unwrap();
+        let mut norm = self.normal_at(p).norm();
+        let mut old_p = self.project_vertical(p.sub(delta)).unwrap();
+
+        let mut geom = GeometryResult::new(p);
+        while p.len() < self.infinity {
+            let delta = p.sub(old_p).norm();
+            ((p, norm), old_p) = (
+                self.step_adaptive(p, delta, norm, &mut step_size, &mut geom.newton_iters),
+                p,
+            );
+            first_step.get_or_insert(step_size);
+            if self.record_step(&mut geom, p, old_p) {
+                break;
+            }
+        }
+
+        geom.final_dir = p.sub(old_p);
+        (geom, first_step.unwrap_or(initial_step))
+    }
+
+    // Take a step from p in direction delta, constrained to the
+    // surface in direction norm. Adds the Newton-Raphson iterations
+    // taken to `newton_iters`. If the solver doesn't converge, as
+    // where the curvature changes abruptly, the step is halved and
+    // retried, as with `step_counted`.
+    fn step_adaptive(
+        &self,
+        p: Point4,
+        delta: Dir4,
+        norm: Dir4,
+        step_size: &mut f64,
+        newton_iters: &mut usize,
+    ) -> (Point4, Dir4) {
+        const MAX_ITER: usize = 8;
+        let mut iter = 0;
+        let (base, projection, new_p) = loop {
+            let base = p + delta * *step_size;
+            let (solution, iters) = self.intersect_line_adaptive(base, norm);
+            *newton_iters += iters;
+            match solution {
+                Some((projection, new_p)) => break (base, projection, new_p),
+                None if iter + 1 < MAX_ITER => *step_size *= 0.5,
+                None => panic!("step_adaptive could not extend path"),
+            }
+            iter += 1;
+        };
+
+        // Now, calculate the next step size.
+        let new_norm = self.normal_at(new_p).norm();
+        let other_p = base + new_norm * projection;
+        let actual_norm_diff = (new_p - other_p).len() / *step_size;
+        *step_size = (*step_size * TARGET_NORM_DIFF / actual_norm_diff).min(MAX_ADAPTIVE_STEP);
+
+        (new_p, new_norm)
+    }
+
+    // Returns the distance moved along direction and the point on the
+    // surface, or None if the solver didn't converge, and the
+    // iterations taken, as `intersect_line_counted`.
+    fn intersect_line_adaptive(
+        &self,
+        point: Point4,
+        direction: Dir4,
+    ) -> (Option<(f64, Point4)>, usize) {
+        // Newton-Raphson solver on dist(point + lambda direction)
+        const MAX_ITERS: usize = 3;
+        let mut lambda = 0.0;
+        for iter in 0..MAX_ITERS {
+            let guess = point + direction * lambda;
+            let guess_val = self.dist(guess);
+            if guess_val.abs() < EPSILON {
+                return (Some((lambda, guess)), iter + 1);
+            }
+
+            let guess2 = point + direction * (lambda + EPSILON);
+            let guess2_val = self.dist(guess2);
+
+            let dguess_val = (guess2_val - guess_val) / EPSILON;
+
+            lambda -= guess_val / dguess_val;
+        }
+
+        (None, MAX_ITERS)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Runge-Kutta integration: Rather than stepping along chords and
+// projecting back onto the surface, integrate the geodesic equation
+// for the position and unit direction of the ray. A geodesic on the
+// surface dist = 0 only accelerates along the normal, just enough to
+// stay on the surface:
+//
+//   a = -(v . H v) / |grad|^2 grad
+//
+// where H is the Hessian of dist. Derivatives are taken by central
+// differences, which are exact for the wormhole's quadratic surface
+// up to rounding. After each step, the point is projected back onto
+// the surface and the direction into its tangent space, so that
+// rounding doesn't drift.
+//
+
+// Step for the central differences. Much larger than EPSILON, as the
+// second difference divides by its square, amplifying rounding.
+const GEODESIC_DIFF_STEP: f64 = 1.0e-2;
+
+impl Tracer {
+    // Take a step of the given arc length from p in the unit tangent
+    // direction v. Returns the new point and direction, or None if
+    // the point couldn't be projected back onto the surface, and the
+    // Newton-Raphson iterations taken. As with `step_counted`, if the
+    // curvature is too extreme to project back, smaller steps are
+    // tried.
+    fn step_rk4(&self, p: Point4, v: Dir4, step_size: f64) -> (Option<(Point4, Dir4)>, usize) {
+        const MAX_ITER: usize = 8;
+        let mut h = step_size;
+        let mut newton_iters = 0;
+        for _ in 0..MAX_ITER {
+            let (step, iters) = self.step_rk4_once(p, v, h);
+            newton_iters += iters;
+            if step.is_some() {
+                return (step, newton_iters);
+            }
+            h *= 0.5;
+        }
+        (None, newton_iters)
+    }
+
+    fn step_rk4_once(&self, p: Point4, v: Dir4, h: f64) -> (Option<(Point4, Dir4)>, usize) {
+        let deriv = |p: Point4, v: Dir4| (v, self.geodesic_accel(p, v));
+        let (dp1, dv1) = deriv(p, v);
+        let (dp2, dv2) = deriv(p + dp1 * (0.5 * h), v + dv1 * (0.5 * h));
+        let (dp3, dv3) = deriv(p + dp2 * (0.5 * h), v + dv2 * (0.5 * h));
+        let (dp4, dv4) = deriv(p + dp3 * h, v + dv3 * h);
+        let new_p = p + (dp1 + dp2 * 2.0 + dp3 * 2.0 + dp4) * (h / 6.0);
+        let new_v = v + (dv1 + dv2 * 2.0 + dv3 * 2.0 + dv4) * (h / 6.0);
+
+        let norm = self.gradient(new_p).norm();
+        let (new_p, iters) = self.intersect_line_counted(new_p, norm, 3);
+        (
+            new_p.map(|new_p| (new_p, self.tangent_dir(new_p, new_v))),
+            iters,
+        )
+    }
+
+    // Acceleration of a geodesic through p with unit velocity v.
+    fn geodesic_accel(&self, p: Point4, v: Dir4) -> Dir4 {
+        let h = GEODESIC_DIFF_STEP;
+        let grad = self.gradient(p);
+        let curvature =
+            (self.dist(p + v * h) - 2.0 * self.dist(p) + self.dist(p - v * h)) / (h * h);
+        grad * (-curvature / grad.dot(grad))
+    }
+
+    // Gradient of dist, by central differences.
+    fn gradient(&self, p: Point4) -> Dir4 {
+        let h = GEODESIC_DIFF_STEP;
+        let diff = |offset: Dir4| (self.dist(p + offset) - self.dist(p - offset)) / (2.0 * h);
+        let zero = Dir4 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            w: 0.0,
+        };
+        Dir4 {
+            x: diff(Dir4 { x: h, ..zero }),
+            y: diff(Dir4 { y: h, ..zero }),
+            z: diff(Dir4 { z: h, ..zero }),
+            w: diff(Dir4 { w: h, ..zero }),
+        }
+    }
+
+    // The unit direction of dir's component in the surface's tangent
+    // space at p. Uses `gradient` rather than `normal_at`, whose
+    // shorter difference is too noisy to reproject every step.
+    fn tangent_dir(&self, p: Point4, dir: Dir4) -> Dir4 {
+        let norm = self.gradient(p).norm();
+        dir.sub(norm.scale(dir.dot(norm))).norm()
+    }
+
+    // The unit tangent at p of the view ray's vertical projection
+    // onto the surface, which is what `start_fixed`'s chord
+    // approximates: dir's x, y and z are kept, and w is chosen to
+    // lie in the tangent space. Where the surface is vertical, falls
+    // back to `tangent_dir`.
+    fn vertical_tangent_dir(&self, p: Point4, dir: Dir4) -> Dir4 {
+        let grad = self.gradient(p);
+        if grad.w.abs() <= EPSILON {
+            return self.tangent_dir(p, dir);
+        }
+        let w = -(grad.x * dir.x + grad.y * dir.y + grad.z * dir.z) / grad.w;
+        Dir4 { w, ..dir }.norm()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Seeded adaptive stepping: The adaptive tracer starts every ray from
+// BASE_ADAPTIVE_STEP, and spends its first step adapting. Neighbouring
+// rays want similar steps, so a coarse pre-pass records the adapted
+// step for each region of the image, and rays in the full render
+// start from their region's step.
+//
+
+// Width and height of the pre-pass regions, in pixels.
+const STEP_SEED_CELL: usize = 16;
+
+// The adapted initial step size for each region of the image.
+struct StepSeeds {
+    columns: usize,
+    steps: Vec<f64>,
+}
+
+impl StepSeeds {
+    // The step size for the region containing the given pixel.
+    fn get(&self, x: usize, y: usize) -> f64 {
+        self.steps[(y / STEP_SEED_CELL) * self.columns + x / STEP_SEED_CELL]
+    }
+}
+
+impl Tracer {
+    // Render a whole scene with the adaptive tracer, as
+    // `render_buffer` does, seeding each ray's step size from a coarse
+    // pre-pass. No anti-aliasing is performed.
+    pub fn render_seeded(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+    ) -> RenderBuffer {
+        let view = View::new(conf, tilt, turn, pan);
+        let seeds = self.step_seeds(conf, &view);
+        let pixels = (0..conf.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let (view, seeds) = (&view, &seeds);
+                (0..conf.width).flat_map(move |x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    self.trace_ray_seeded(view.origin, dir, seeds.get(x, y)).0
+                })
+            })
+            .collect::<Vec<u8>>();
+        RenderBuffer::from_pixels(conf.width, conf.height, &pixels)
+    }
+
+    // The pre-pass, tracing one ray through the centre of each
+    // region. Rays that panic, with `catch_panics` set, keep the
+    // default step.
+    fn step_seeds(&self, conf: &CanvasConfig, view: &View) -> StepSeeds {
+        let columns = conf.width.div_ceil(STEP_SEED_CELL);
+        let rows = conf.height.div_ceil(STEP_SEED_CELL);
+        // Pixel centres are at integer coordinates, so a region's
+        // centre is midway between its first and last pixels.
+        let centre = |idx: usize, len: usize| {
+            let start = idx * STEP_SEED_CELL;
+            let end = (start + STEP_SEED_CELL).min(len);
+            (start + end - 1) as f64 / 2.0
+        };
+        let steps = (0..rows)
+            .into_par_iter()
+            .flat_map_iter(|row| {
+                (0..columns).map(move |column| {
+                    let dir = view.dir(centre(column, conf.width), centre(row, conf.height));
+                    self.trace_adaptive_caught(view.origin, dir, BASE_ADAPTIVE_STEP)
+                        .map_or(BASE_ADAPTIVE_STEP, |(_, step)| step)
+                })
+            })
+            .collect();
+        StepSeeds { columns, steps }
+    }
+
+    // As `trace_ray`, for the adaptive tracer starting from the given
+    // step size.
+    fn trace_ray_seeded(&self, p: Point4, dir: Dir4, initial_step: f64) -> (Pixel, f64) {
+        match self.trace_adaptive_caught(p, dir, initial_step) {
+            Some((geom, _)) => (self.shade(&geom), geom.path_len),
+            None => (PANIC_COLOUR, 0.0),
+        }
+    }
+
+    // As `trace_geometry_caught`, for the adaptive tracer starting
+    // from the given step size, also returning the final step size.
+    fn trace_adaptive_caught(
+        &self,
+        p: Point4,
+        dir: Dir4,
+        initial_step: f64,
+    ) -> Option<(GeometryResult, f64)> {
+        if self.catch_panics {
+            return std::panic::catch_unwind(|| self.trace_adaptive_from(p, dir, initial_step))
+                .ok();
+        }
+        Some(self.trace_adaptive_from(p, dir, initial_step))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Edge anti-aliasing: We render a first pass, and then supersample
+// only the pixels on edges found from the rays' geometry. Path length
+// changes sharply at the edges of the lensed regions, which makes it a
+// more reliable edge signal than colour in this scene. Changes in the
+// number of throat crossings mark where rays start going round the
+// throat, which is where the lensing detail concentrates.
+//
+
+// Relative path length difference between neighbouring pixels that
+// counts as an edge.
+const DEPTH_EDGE_THRESHOLD: f64 = 0.02;
+// Supersample edge pixels on an N x N grid.
+const EDGE_GRID: usize = 4;
+
+// Find the pixels whose value differs from any of their 4-connected
+// neighbours'.
+fn find_edges<T: Copy>(
+    width: usize,
+    height: usize,
+    values: &[T],
+    differs: impl Fn(T, T) -> bool,
+) -> Vec<bool> {
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let value = values[y * width + x];
+            (x > 0 && differs(value, values[y * width + x - 1]))
+                || (x + 1 < width && differs(value, values[y * width + x + 1]))
+                || (y > 0 && differs(value, values[(y - 1) * width + x]))
+                || (y + 1 < height && differs(value, values[(y + 1) * width + x]))
+        })
+        .collect()
+}
+
+// Pixels whose path length differs sharply from a neighbour's.
+fn depth_edges(width: usize, height: usize, depths: &[f64]) -> Vec<bool> {
+    find_edges(width, height, depths, |a, b| {
+        (a - b).abs() > DEPTH_EDGE_THRESHOLD * a.min(b)
+    })
+}
+
+// Pixels whose ray crosses the throat a different number of times
+// from a neighbour's.
+fn crossing_edges(width: usize, height: usize, crossings: &[usize]) -> Vec<bool> {
+    find_edges(width, height, crossings, |a, b| a != b)
+}
+
+impl Tracer {
+    fn render_edge_aa(
+        &self,
+        conf: &CanvasConfig,
+        view: &View,
+        step_size: Option<f64>,
+        tasks: &RowTasks,
+    ) -> Option<RenderBuffer> {
+        let first_pass = tasks.run(0..conf.height, |y| {
+            (0..conf.width)
+                .map(|x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    self.trace_ray_geometry(view.origin, dir, step_size)
+                })
+                .collect()
+        })?;
+
+        // Rays that panicked count as having no length or crossings.
+        let edges = match conf.antialias {
+            Antialias::CrossingEdge => {
+                let crossings = first_pass
+                    .iter()
+                    .map(|(_, geom)| geom.as_ref().map_or(0, |geom| geom.crossings))
+                    .collect::<Vec<_>>();
+                crossing_edges(conf.width, conf.height, &crossings)
+            }
+            Antialias::None | Antialias::DepthEdge | Antialias::Supersample(_) => {
+                let depths = first_pass
+                    .iter()
+                    .map(|(_, geom)| geom.as_ref().map_or(0.0, |geom| geom.path_len))
+                    .collect::<Vec<_>>();
+                depth_edges(conf.width, conf.height, &depths)
+            }
+        };
+        log::debug!(
+            "Supersampling {} edge pixels",
+            edges.iter().filter(|is_edge| **is_edge).count()
+        );
 
-        let render_row = |y: f64| {
-            let mut v = Vec::new();
-            let mut x = x_start;
-            for _ in 0..conf.width {
-                let z = 1.0;
+        // Non-edge pixels keep their first-pass sample, while edge
+        // pixels are replaced by the supersampled grid.
+        let mut buffer = RenderBuffer::new(conf.width, conf.height);
+        for (idx, ((pixel, _), is_edge)) in first_pass.iter().zip(edges.iter()).enumerate() {
+            if !is_edge {
+                buffer.add_sample(idx % conf.width, idx / conf.width, to_sample(*pixel));
+            }
+        }
+        // The second pass works a row at a time, so that rows without
+        // edges still count towards progress.
+        let offset = |s: usize| (s as f64 + 0.5) / EDGE_GRID as f64 - 0.5;
+        let offsets = (0..EDGE_GRID.pow(2))
+            .map(|s| (offset(s % EDGE_GRID), offset(s / EDGE_GRID)))
+            .collect::<Vec<_>>();
+        let weights = conf.filter.sample_weights(&offsets);
+        let edge_samples = tasks.run(0..conf.height, |y| {
+            (0..conf.width)
+                .filter(|x| edges[y * conf.width + x])
+                .flat_map(|x| {
+                    offsets
+                        .iter()
+                        .zip(weights.iter())
+                        .map(move |((dx, dy), weight)| {
+                            let dir = view.dir(x as f64 + dx, y as f64 + dy);
+                            let (sample, _) = self.trace_ray(view.origin, dir, step_size);
+                            (x, y, sample, *weight)
+                        })
+                })
+                .collect()
+        })?;
+        for (x, y, sample, weight) in edge_samples {
+            buffer.add_weighted_sample(x, y, to_sample(sample), weight);
+        }
+
+        Some(buffer)
+    }
+}
 
-                let tx = x;
-                let ty = y * tilt_cos + z * tilt_sin;
-                let tz = -y * tilt_sin + z * tilt_cos;
+////////////////////////////////////////////////////////////////////////
+// Supersampling: Trace an N x N grid of rays across every pixel, each
+// jittered randomly within its cell of the grid, and combine them
+// with the reconstruction filter. Slower than edge anti-aliasing, but
+// doesn't depend on finding the edges.
+//
 
-                let t2x = tx * turn_cos + tz * turn_sin;
-                let t2y = ty;
-                let t2z = -tx * turn_sin + tz * turn_cos;
+const SUPERSAMPLE_SEED: u64 = 0x73757065;
 
-                // And rotate the looking direction to be centered around (0, 0, 0)
-                let dir = Dir4 {
-                    x: t2x * pan_cos - t2z * pan_sin,
-                    y: t2y,
-                    z: t2x * pan_sin + t2z * pan_cos,
-                    w: 0.0,
-                };
+impl Tracer {
+    fn render_supersampled(
+        &self,
+        conf: &CanvasConfig,
+        view: &View,
+        step_size: Option<f64>,
+        grid: usize,
+        tasks: &RowTasks,
+    ) -> Option<RenderBuffer> {
+        let samples_per_pixel = grid * grid;
+        let pixels = tasks.run(0..conf.height, |y| {
+            (0..conf.width)
+                .map(|x| {
+                    let pixel_index = ((y * conf.width + x) * samples_per_pixel) as u64;
+                    // Offsets are in pixels, so the view scales them by
+                    // its per-pixel steps.
+                    let offsets = (0..samples_per_pixel)
+                        .map(|s| {
+                            let index = 2 * (pixel_index + s as u64);
+                            let offset = |cell: usize, jitter_index: u64| {
+                                let jitter = hash_unit(SUPERSAMPLE_SEED, jitter_index);
+                                (cell as f64 + jitter) / grid as f64 - 0.5
+                            };
+                            (offset(s % grid, index), offset(s / grid, index + 1))
+                        })
+                        .collect::<Vec<_>>();
+                    let weights = conf.filter.sample_weights(&offsets);
+                    let mut sum = [0.0; 4];
+                    let mut total_weight = 0.0;
+                    for ((dx, dy), weight) in offsets.into_iter().zip(weights) {
+                        let dir = view.dir(x as f64 + dx, y as f64 + dy);
+                        let (sample, _) = self.trace_ray(view.origin, dir, step_size);
+                        for (total, channel) in sum.iter_mut().zip(to_sample(sample)) {
+                            *total += channel * weight;
+                        }
+                        total_weight += weight;
+                    }
+                    (sum, total_weight)
+                })
+                .collect()
+        })?;
+
+        let mut buffer = RenderBuffer::new(conf.width, conf.height);
+        for (idx, (sum, total_weight)) in pixels.into_iter().enumerate() {
+            let average = sum.map(|total| total / total_weight);
+            buffer.add_weighted_sample(idx % conf.width, idx / conf.width, average, total_weight);
+        }
+        Some(buffer)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Origin jitter: Render the scene several times with the camera origin
+// moved randomly within a small sphere, and average the results, for
+// a motion-blur look. Unlike depth of field, every ray in a sample
+// shares the same displaced origin.
+//
+
+// SplitMix64, giving a well-mixed, reproducible stream of values from
+// a seed and an index into the stream.
+fn hash_u64(seed: u64, index: u64) -> u64 {
+    let mut z = seed.wrapping_add(index.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Uniform value in [0, 1).
+fn hash_unit(seed: u64, index: u64) -> f64 {
+    (hash_u64(seed, index) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Uniformly distributed point in the 3D ball of the given radius, by
+// rejection sampling from the enclosing cube.
+fn jitter_offset(seed: u64, sample: u64, radius: f64) -> Dir4 {
+    let mut index = sample << 32;
+    loop {
+        let mut coord = || {
+            index += 1;
+            hash_unit(seed, index) * 2.0 - 1.0
+        };
+        let offset = Dir4 {
+            x: coord(),
+            y: coord(),
+            z: coord(),
+            w: 0.0,
+        };
+        if offset.len() <= 1.0 {
+            return offset.scale(radius);
+        }
+    }
+}
+
+// Origin jitter settings: `samples` renders are made with the origin
+// moved within `radius`, deterministically for a given `seed`.
+pub struct OriginJitter {
+    pub radius: f64,
+    pub samples: usize,
+    pub seed: u64,
+}
+
+impl Tracer {
+    // Render the scene with origin jitter, accumulated into a
+    // buffer. No anti-aliasing is performed.
+    pub fn render_jittered(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+        jitter: &OriginJitter,
+    ) -> RenderBuffer {
+        let mut view = View::new(conf, tilt, turn, pan);
+        let origin = view.origin;
+
+        let mut buffer = RenderBuffer::new(conf.width, conf.height);
+        for sample in 0..jitter.samples {
+            let offset = jitter_offset(jitter.seed, sample as u64, jitter.radius);
+            view.origin = origin.add(offset);
+            let pixels = self.render_rows(conf, &view, step_size, 0..conf.height);
+            for (idx, pixel) in pixels.chunks(4).enumerate() {
+                let pixel = [pixel[0], pixel[1], pixel[2], pixel[3]];
+                buffer.add_sample(idx % conf.width, idx / conf.width, to_sample(pixel));
+            }
+        }
+
+        buffer
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Path tracer, returning the points along each ray rather than a
+// colour, for inspecting the geodesics themselves.
+//
+
+impl Tracer {
+    // Trace all the rays in the canvas, returning each ray's path.
+    pub fn render_paths(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> Vec<Vec<Point4>> {
+        let view = View::new(conf, tilt, turn, pan);
+        (0..conf.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let view = &view;
+                (0..conf.width).map(move |x| {
+                    self.trace_path(view.origin, view.dir(x as f64, y as f64), step_size)
+                })
+            })
+            .collect()
+    }
 
-                v.extend(if let Some(step_size) = step_size {
-                    self.trace(origin, dir, step_size)
+    // Trace a single ray, returning the points along its path, from
+    // the origin (projected onto the surface) to the first point past
+    // infinity.
+    pub fn trace_path(&self, p: Point4, dir: Dir4, step_size: Option<f64>) -> Vec<Point4> {
+        let initial_step = step_size.unwrap_or(BASE_ADAPTIVE_STEP);
+        let (mut p, mut old_p, mut dir) = self.start_fixed(p, dir, initial_step);
+
+        let mut path = vec![p];
+        if let Some(step_size) = step_size {
+            while p.len() < self.infinity {
+                if let Some((new_p, new_dir)) = self.advance(p, dir, step_size).0 {
+                    (p, dir) = (new_p, new_dir);
                 } else {
-                    self.trace_adaptive(origin, dir)
-                });
-                x += x_step;
+                    panic!("trace_path could not extend path");
+                }
+                path.push(p);
+            }
+        } else {
+            let mut step_size = initial_step;
+            let mut norm = self.normal_at(p).norm();
+            let mut newton_iters = 0;
+            while p.len() < self.infinity {
+                let delta = p.sub(old_p).norm();
+                ((p, norm), old_p) = (
+                    self.step_adaptive(p, delta, norm, &mut step_size, &mut newton_iters),
+                    p,
+                );
+                path.push(p);
+            }
+        }
+        path
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Cube map renderer: Render the full view from the camera position as
+// the six faces of a cube map, in the layout EnvMap reads, so the
+// result can be reused as an environment map.
+//
+
+impl Tracer {
+    // Render one size x size face of the cube map seen from the camera
+    // position for the given pan.
+    pub fn render_cube_face(
+        &self,
+        face: Face,
+        size: usize,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> Vec<u8> {
+        let origin = camera_origin(pan, false);
+        (0..size)
+            .into_par_iter()
+            .flat_map_iter(|iy| {
+                (0..size).flat_map(move |ix| {
+                    let u = (ix as f64 + 0.5) / size as f64;
+                    let v = (iy as f64 + 0.5) / size as f64;
+                    self.trace_ray(origin, face.dir(u, v), step_size).0
+                })
+            })
+            .collect()
+    }
+
+    // Render the cube map as a single image in the given layout,
+    // returning the pixels and the image's width and height. Areas not
+    // covered by a face are transparent.
+    pub fn render_cube_layout(
+        &self,
+        layout: CubeLayout,
+        size: usize,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> (Vec<u8>, usize, usize) {
+        // Render the faces, and then place them by looking up each
+        // tile pixel's direction in them, which handles each face's
+        // rotation for us. Tile pixel centres land on face pixel
+        // centres, so this is an exact copy.
+        let Ok(mut faces) = EnvMap::from_faces(|face| {
+            let pixels = self.render_cube_face(face, size, pan, step_size);
+            let image = image::RgbaImage::from_raw(size as u32, size as u32, pixels).unwrap();
+            Ok::<_, std::convert::Infallible>(EnvImage::Ldr(image))
+        });
+        // Keep clipped rays' transparency.
+        faces.set_alpha_policy(EnvMapAlphaPolicy::Passthrough);
+
+        let (cols, rows) = layout.dimensions();
+        let (width, height) = (cols * size, rows * size);
+        let mut pixels = vec![0; width * height * 4];
+        for (col, row, [forward, right, down]) in layout.tiles() {
+            for iy in 0..size {
+                for ix in 0..size {
+                    let a = 2.0 * (ix as f64 + 0.5) / size as f64 - 1.0;
+                    let b = 2.0 * (iy as f64 + 0.5) / size as f64 - 1.0;
+                    let dir = forward.add(right.scale(a)).add(down.scale(b));
+                    let idx = ((row * size + iy) * width + col * size + ix) * 4;
+                    pixels[idx..idx + 4].copy_from_slice(&faces.colour(dir));
+                }
             }
-            v
+        }
+        (pixels, width, height)
+    }
+}
+
+// Single-image cube map layouts. Faces are oriented as seen by a
+// viewer at the camera looking along +z with +y down and +x to the
+// left (see Handedness), turning to face each direction in turn, with
+// the up and down faces reached by tilting from the front face.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CubeLayout {
+    // Horizontal cross, 4 x 3 faces: up above front, down below it,
+    // and left, front, right, back along the middle row.
+    Cross,
+    // Vertical cross, 3 x 4 faces: as the horizontal cross, but with
+    // back below down, upside down, rather than beside right.
+    VerticalCross,
+    // 6 x 1 faces: right, left, up, down, front, back.
+    Strip,
+}
+
+impl CubeLayout {
+    // Size of the layout, in faces.
+    pub fn dimensions(&self) -> (usize, usize) {
+        match self {
+            CubeLayout::Cross => (4, 3),
+            CubeLayout::VerticalCross => (3, 4),
+            CubeLayout::Strip => (6, 1),
+        }
+    }
+
+    // The column and row of each face, along with the face's
+    // forward, right and down directions.
+    fn tiles(&self) -> Vec<(usize, usize, [Dir4; 3])> {
+        let axis = |x: f64, y: f64, z: f64| Dir4 { x, y, z, w: 0.0 };
+        let (fwd, back) = (axis(0.0, 0.0, 1.0), axis(0.0, 0.0, -1.0));
+        let (right, left) = (axis(-1.0, 0.0, 0.0), axis(1.0, 0.0, 0.0));
+        let (down, up) = (axis(0.0, 1.0, 0.0), axis(0.0, -1.0, 0.0));
+
+        let front_face = [fwd, right, down];
+        let right_face = [right, back, down];
+        let back_face = [back, left, down];
+        // Reached by tilting on past the down face.
+        let back_face_below = [back, right, up];
+        let left_face = [left, fwd, down];
+        let up_face = [up, right, fwd];
+        let down_face = [down, right, back];
+
+        match self {
+            CubeLayout::Cross => vec![
+                (1, 0, up_face),
+                (0, 1, left_face),
+                (1, 1, front_face),
+                (2, 1, right_face),
+                (3, 1, back_face),
+                (1, 2, down_face),
+            ],
+            CubeLayout::VerticalCross => vec![
+                (1, 0, up_face),
+                (0, 1, left_face),
+                (1, 1, front_face),
+                (2, 1, right_face),
+                (1, 2, down_face),
+                (1, 3, back_face_below),
+            ],
+            CubeLayout::Strip => vec![
+                (0, 0, right_face),
+                (1, 0, left_face),
+                (2, 0, up_face),
+                (3, 0, down_face),
+                (4, 0, front_face),
+                (5, 0, back_face),
+            ],
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Disk-only renderer: Only trace rays that pass close to the throat,
+// and look the rest up in the env map along their initial direction.
+//
+// This is an approximation, not an optimisation that preserves the
+// output. Far from the throat the surface tends to a cone (w
+// proportional to distance), and rays crossing a cone are bent by an
+// amount that doesn't shrink with their closest approach, so untraced
+// pixels are off by up to a few degrees for typical smoothness
+// values. Every ray is bent, so no choice of which rays to trace would
+// make the output match a full render. It's only for quick previews
+// of the lensed region.
+//
+
+impl Tracer {
+    // Render, tracing only the rays whose straight-line closest
+    // approach to the throat is within `trace_radius`. No
+    // anti-aliasing is performed.
+    pub fn render_disk_only(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+        trace_radius: f64,
+    ) -> Vec<u8> {
+        let view = View::new(conf, tilt, turn, pan);
+        let origin = view.origin;
+        // Untraced rays stay on the camera's side of the wormhole.
+        let side = self.project_vertical(origin).unwrap().w.signum();
+        (0..conf.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let view = &view;
+                (0..conf.width).flat_map(move |x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    if closest_approach(origin, dir) <= trace_radius {
+                        self.trace_ray(origin, dir, step_size).0
+                    } else {
+                        self.colour_for(Dir4 { w: side, ..dir })
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+// Closest 3D distance to the origin of the ray from p along dir,
+// ignoring w and curvature.
+fn closest_approach(p: Point4, dir: Dir4) -> f64 {
+    let p = Point4 { w: 0.0, ..p };
+    let dir = Dir4 { w: 0.0, ..dir };
+    let t = (-p.dot(dir) / dir.dot(dir)).max(0.0);
+    dist_3d(p.add(dir.scale(t)))
+}
+
+////////////////////////////////////////////////////////////////////////
+// Curved region bound: A sphere in (x, y, z) outside which the surface
+// bends less than a threshold, for culling and picking `infinity`.
+//
+// Writing rho for the 3D distance from the axis, the surface is
+// w = f(rho) = s sqrt(rho^2 - r), a hypersurface of revolution. Its
+// principal curvatures are f'' / (1 + f'^2)^(3/2) along the radius,
+// and f' / (rho sqrt(1 + f'^2)) in the two directions around it. With
+// D = (1 + s^2) rho^2 - r, these simplify to -s r / D^(3/2) and
+// s / sqrt(D), both falling as rho grows, so the bound is where the
+// larger reaches the threshold.
+//
+// As the surface tends to a cone, the curvature around the axis only
+// falls as 1 / rho. Rays outside the bound are still deflected
+// slightly, as described for the disk-only renderer.
+//
+
+impl Tracer {
+    // A sphere in (x, y, z), centred on the throat, outside which all
+    // of the surface's principal curvatures are at most
+    // `max_curvature` (radians turned by the normal per unit
+    // distance). Returns the centre, with w = 0, and the radius.
+    pub fn curved_region_bound(&self, max_curvature: f64) -> (Point4, f64) {
+        assert!(max_curvature > 0.0);
+        let centre = Point4 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            w: 0.0,
         };
+        let s = effective_w_scale(self.w_scale);
+        let r = self.radius;
+        // The smallest D at which each curvature is within the limit.
+        let around_d = (s / max_curvature).powi(2);
+        let radial_d = (s * r.abs() / max_curvature).powf(2.0 / 3.0);
+        let d = around_d.max(radial_d);
+        // The surface only reaches down to rho^2 = r.
+        let rho_sq = ((d + r) / (1.0 + s * s)).max(r).max(0.0);
+        (centre, rho_sq.sqrt())
+    }
+}
 
+////////////////////////////////////////////////////////////////////////
+// Depth output: The path length of each pixel's ray, for compositing.
+//
+
+impl Tracer {
+    pub fn render_depth(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> Vec<f64> {
+        let view = View::new(conf, tilt, turn, pan);
         (0..conf.height)
             .into_par_iter()
-            .map(|y| render_row(y_start + y as f64 * y_step))
-            .flatten()
-            .collect::<Vec<u8>>()
+            .flat_map_iter(|y| {
+                let view = &view;
+                (0..conf.width).map(move |x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    self.trace_ray(view.origin, dir, step_size).1
+                })
+            })
+            .collect()
     }
 
-    // Trace a single ray.
-    fn trace(&self, p: Point4, dir: Dir4, step_size: f64) -> Pixel {
-        let delta = dir.norm().scale(step_size);
-        let mut p = self.project_vertical(p).unwrap();
-        let mut old_p = self.project_vertical(p.sub(delta)).unwrap();
+    // Render the scene as `render` does, without anti-aliasing, along
+    // with each pixel's path length, tracing each ray only once.
+    // Rays that panicked have a path length of 0.
+    pub fn render_with_depth(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> (Vec<u8>, Vec<f32>) {
+        let view = View::new(conf, tilt, turn, pan);
+        let (pixels, depths): (Vec<Pixel>, Vec<f32>) = (0..conf.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let view = &view;
+                (0..conf.width).map(move |x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    let (colour, depth) = self.trace_ray(view.origin, dir, step_size);
+                    (colour, depth as f32)
+                })
+            })
+            .unzip();
+        let pixels = pixels.concat();
+        let colours = RenderBuffer::from_pixels(conf.width, conf.height, &pixels)
+            .finalize(conf.tone_map, conf.gamma);
+        (colours, depths)
+    }
+}
 
-        while p.len() < self.infinity {
-            let delta = p.sub(old_p).norm().scale(step_size);
-            let norm = self.normal_at(p).norm();
+// Rescale depths so that the nearest maps to 0 and the farthest to 1,
+// also returning the original minimum and maximum. If all depths are
+// equal, they map to 0.
+pub fn normalize_depth(depths: &[f64]) -> (Vec<f64>, f64, f64) {
+    let (min, max) = depths
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &d| {
+            (min.min(d), max.max(d))
+        });
+    let range = max - min;
+    let normalized = depths
+        .iter()
+        .map(|d| if range > 0.0 { (d - min) / range } else { 0.0 })
+        .collect();
+    (normalized, min, max)
+}
 
-            if let Some(new_p) = self.step(p, delta, norm) {
-                (p, old_p) = (new_p, p);
-            } else {
-                panic!("trace_aux could not extend path");
+////////////////////////////////////////////////////////////////////////
+// Solver cost: How hard the Newton-Raphson solver works along each
+// pixel's ray, as opposed to how many steps the path takes.
+//
+
+impl Tracer {
+    // The total Newton-Raphson iterations and the number of steps
+    // taken by each pixel's ray.
+    pub fn render_newton_cost(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> Vec<(usize, usize)> {
+        let view = View::new(conf, tilt, turn, pan);
+        (0..conf.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let view = &view;
+                (0..conf.width).map(move |x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    let geom = self.trace_geometry(view.origin, dir, step_size);
+                    (geom.newton_iters, geom.steps)
+                })
+            })
+            .collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Apparent positions: Find where in the image a world direction
+// appears. Lensing can show the same direction at several places, so
+// we scan the whole field of final ray directions for cells that
+// contain the target, and then refine each match by tracing.
+//
+
+// Newton iterations refining each apparent position.
+const APPARENT_REFINE_ITERS: usize = 4;
+// Finite-difference step for the refinement, in pixels.
+const APPARENT_REFINE_STEP: f64 = 0.01;
+// Offset from the target direction (roughly in radians) below which
+// the refinement has converged.
+const APPARENT_TOLERANCE: f64 = 1.0e-6;
+// Matches closer than this, in pixels, are the same image.
+const APPARENT_MERGE_DIST: f64 = 0.5;
+
+impl Tracer {
+    // The final direction of each pixel's ray, or None if it didn't
+    // escape.
+    pub fn render_directions(
+        &self,
+        conf: &CanvasConfig,
+        camera: &CameraFrame,
+        step_size: Option<f64>,
+    ) -> Vec<Option<Dir4>> {
+        let view = View::from_frame(conf, camera);
+        (0..conf.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let view = &view;
+                (0..conf.width).map(move |x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    let geom = self.trace_geometry(view.origin, dir, step_size);
+                    geom.escaped().then_some(geom.final_dir)
+                })
+            })
+            .collect()
+    }
+
+    // Pixel coordinates at which rays end up pointing along
+    // `world_dir`, which is compared ignoring w. If w is non-zero, only
+    // rays ending on that side of the wormhole match. Rays are traced
+    // adaptively. Degenerate images, such as Einstein rings, are only
+    // partially found.
+    pub fn apparent_positions(
+        &self,
+        conf: &CanvasConfig,
+        camera: &CameraFrame,
+        world_dir: Dir4,
+    ) -> Vec<(f64, f64)> {
+        let view = View::from_frame(conf, camera);
+        let target = Dir4 {
+            w: 0.0,
+            ..world_dir
+        }
+        .norm();
+        let (u, v) = perpendicular_basis(target);
+        // Offset of a final direction from the target, in the plane
+        // perpendicular to it, or None if it can't match.
+        let offset = |dir: Option<Dir4>| {
+            let dir = dir?;
+            if world_dir.w * dir.w < 0.0 {
+                return None;
+            }
+            let dir = Dir4 { w: 0.0, ..dir }.norm();
+            (dir.dot(target) > 0.0).then(|| (dir.dot(u), dir.dot(v)))
+        };
+        let trace_offset = |px: f64, py: f64| {
+            let geom = self.trace_geometry(view.origin, view.dir(px, py), None);
+            offset(geom.escaped().then_some(geom.final_dir))
+        };
+
+        let field = self
+            .render_directions(conf, camera, None)
+            .into_iter()
+            .map(offset)
+            .collect::<Vec<_>>();
+        let at = |x: usize, y: usize| field[y * conf.width + x];
+
+        let mut positions: Vec<(f64, f64)> = Vec::new();
+        for y in 0..conf.height.saturating_sub(1) {
+            for x in 0..conf.width.saturating_sub(1) {
+                // Split each cell between pixel centres into two
+                // triangles, and find the target in either.
+                let corners = [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)];
+                for tri in [[0, 1, 2], [3, 2, 1]] {
+                    let points = tri.map(|i| corners[i]);
+                    let Some(values) = points
+                        .iter()
+                        .map(|(x, y)| at(*x, *y))
+                        .collect::<Option<Vec<_>>>()
+                    else {
+                        continue;
+                    };
+                    let Some((a, b)) = triangle_zero(values[0], values[1], values[2]) else {
+                        continue;
+                    };
+                    let coord = |c: fn(&(usize, usize)) -> usize| {
+                        let (c0, c1, c2) = (
+                            c(&points[0]) as f64,
+                            c(&points[1]) as f64,
+                            c(&points[2]) as f64,
+                        );
+                        c0 + a * (c1 - c0) + b * (c2 - c0)
+                    };
+                    let estimate = (coord(|p| p.0), coord(|p| p.1));
+                    // Cells straddling discontinuities can give false
+                    // estimates, which won't refine to a match.
+                    let Some(pos) = refine_position(estimate, &trace_offset) else {
+                        continue;
+                    };
+                    let is_new = positions
+                        .iter()
+                        .all(|(px, py)| (px - pos.0).hypot(py - pos.1) > APPARENT_MERGE_DIST);
+                    if is_new {
+                        positions.push(pos);
+                    }
+                }
             }
         }
+        positions
+    }
+}
 
-        let final_dir = p.sub(old_p);
-        if final_dir.w > 0.0 {
-            self.env_map_pos.colour(final_dir)
-        } else {
-            self.env_map_neg.colour(final_dir)
+// One of the world axes, positive or negative, and where it appears.
+#[derive(Clone, Copy, Debug)]
+pub struct AxisPosition {
+    // 0 to 3 for x, y, z and w.
+    pub axis: usize,
+    pub positive: bool,
+    pub pos: (f64, f64),
+}
+
+impl Tracer {
+    // Pixel coordinates at which `world_dir` appears to the camera
+    // before lensing, once projected into the surface's tangent space
+    // at the camera's projection onto the surface. None if the camera
+    // is inside the throat, the direction is normal to the surface, or
+    // the projection points sideways or behind.
+    pub fn local_position(
+        &self,
+        conf: &CanvasConfig,
+        camera: &CameraFrame,
+        world_dir: Dir4,
+    ) -> Option<(f64, f64)> {
+        let view = View::from_frame(conf, camera);
+        let norm = self.normal_at(self.project_vertical(camera.origin)?).norm();
+        let tangent = world_dir.sub(norm.scale(world_dir.dot(norm)));
+        if tangent.len() <= EPSILON {
+            return None;
         }
+        view.pixel(tangent)
     }
 
-    // Take a step from p in direction delta, constrained to the
-    // surface in direction norm.
-    fn step(&self, p: Point4, delta: Dir4, norm: Dir4) -> Option<Point4> {
-        let mut delta = delta;
-        // If curvature is extreme, there may be no intersection,
-        // because the normal at p and the normal at the intersection
-        // point are sufficiently different. We try again with a
-        // smaller step.
-        //
-        // An example of extreme curvature is the "wormhole" surface
-        // with w_scale around e.g. 0.01.
-        const MAX_ITER: usize = 8;
-        let mut new_p = None;
-        let mut iter = 0;
-        while new_p.is_none() && iter < MAX_ITER {
-            // If it takes too many iterations, we're probably best
-            // off taking a smaller step, so set the Newton-Raphson
-            // convergence iterations low.
-            new_p = self.intersect_line(p.add(delta), norm, 3);
-            delta = delta.scale(0.5);
-            iter += 1;
+    // Where each world axis appears, in both directions. Escaping rays
+    // end up nearly flat in w, so the x, y and z axes are placed with
+    // `apparent_positions`, and the w axis, which no ray escapes
+    // along, with `local_position`.
+    pub fn axis_positions(&self, conf: &CanvasConfig, camera: &CameraFrame) -> Vec<AxisPosition> {
+        let mut positions = Vec::new();
+        for axis in 0..4 {
+            for positive in [true, false] {
+                let mut coords = [0.0; 4];
+                coords[axis] = if positive { 1.0 } else { -1.0 };
+                let dir = Dir4 {
+                    x: coords[0],
+                    y: coords[1],
+                    z: coords[2],
+                    w: coords[3],
+                };
+                let found = if axis == 3 {
+                    self.local_position(conf, camera, dir).into_iter().collect()
+                } else {
+                    self.apparent_positions(conf, camera, dir)
+                };
+                positions.extend(found.into_iter().map(|pos| AxisPosition {
+                    axis,
+                    positive,
+                    pos,
+                }));
+            }
+        }
+        positions
+    }
+}
+
+// Two unit vectors perpendicular to dir and each other, ignoring w.
+fn perpendicular_basis(dir: Dir4) -> (Dir4, Dir4) {
+    // Start from the axis least aligned with dir.
+    let a = dir.abs();
+    let axis = if a.x <= a.y && a.x <= a.z {
+        Dir4 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+            w: 0.0,
+        }
+    } else if a.y <= a.z {
+        Dir4 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+            w: 0.0,
         }
-        new_p
-    }
-
-    // Not a true distance, but the implicit surface function, where
-    // the surface is all points where dist == 0.
-    fn dist(&self, point: Point4) -> f64 {
-        // If w_scale is zero, the implicit surface needs to be
-        // special-cased to work.
-        if self.w_scale.abs() <= EPSILON {
-            return point.w;
+    } else {
+        Dir4 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+            w: 0.0,
         }
+    };
+    let u = axis.sub(dir.scale(axis.dot(dir))).norm();
+    let v = Dir4 {
+        x: dir.y * u.z - dir.z * u.y,
+        y: dir.z * u.x - dir.x * u.z,
+        z: dir.x * u.y - dir.y * u.x,
+        w: 0.0,
+    };
+    (u, v)
+}
 
-        let w_scale = self.w_scale.signum() * self.w_scale.abs().max(0.02);
-        let (x, y, z, w) = (point.x, point.y, point.z, point.w / w_scale);
-        x * x + y * y + z * z - w * w - self.radius
+// Barycentric coordinates (a, b) of the zero of the linear
+// interpolation of 2D values over a triangle, p0 + a (p1 - p0) + b (p2
+// - p0), if it lies within the triangle.
+fn triangle_zero(p0: (f64, f64), p1: (f64, f64), p2: (f64, f64)) -> Option<(f64, f64)> {
+    let (e1, e2) = ((p1.0 - p0.0, p1.1 - p0.1), (p2.0 - p0.0, p2.1 - p0.1));
+    let det = e1.0 * e2.1 - e2.0 * e1.1;
+    if det == 0.0 {
+        return None;
     }
+    let a = (-p0.0 * e2.1 + e2.0 * p0.1) / det;
+    let b = (-e1.0 * p0.1 + p0.0 * e1.1) / det;
+    (a >= 0.0 && b >= 0.0 && a + b <= 1.0).then_some((a, b))
+}
 
-    fn intersect_line(&self, point: Point4, direction: Dir4, max_iters: usize) -> Option<Point4> {
-        // Newton-Raphson solver on dist(point + lambda direction)
-        let mut lambda = 0.0;
-        for _ in 0..max_iters {
-            let guess = point.add(direction.scale(lambda));
-            let guess_val = self.dist(guess);
-            if guess_val.abs() < EPSILON {
-                return Some(guess);
-            }
-
-            let guess2 = point.add(direction.scale(lambda + EPSILON));
-            let guess2_val = self.dist(guess2);
-
-            let dguess_val = (guess2_val - guess_val) / EPSILON;
-
-            lambda -= guess_val / dguess_val;
+// Refine a position where `offset` is estimated to be zero, with
+// Newton's method using finite differences. Returns None if the
+// iteration fails, doesn't converge, or wanders off.
+fn refine_position(
+    estimate: (f64, f64),
+    offset: &impl Fn(f64, f64) -> Option<(f64, f64)>,
+) -> Option<(f64, f64)> {
+    let h = APPARENT_REFINE_STEP;
+    let mut pos = estimate;
+    for _ in 0..APPARENT_REFINE_ITERS {
+        let (Some(f), Some(fx), Some(fy)) = (
+            offset(pos.0, pos.1),
+            offset(pos.0 + h, pos.1),
+            offset(pos.0, pos.1 + h),
+        ) else {
+            return None;
+        };
+        // Jacobian columns.
+        let (jx, jy) = (
+            ((fx.0 - f.0) / h, (fx.1 - f.1) / h),
+            ((fy.0 - f.0) / h, (fy.1 - f.1) / h),
+        );
+        let det = jx.0 * jy.1 - jy.0 * jx.1;
+        if det == 0.0 {
+            return None;
         }
-
-        // Could fall back to binary chop, but as it generally seems
-        // to converge in <= 2 iterations if there is a solution, this
-        // seems excessive.
-        None
+        pos.0 -= (f.0 * jy.1 - jy.0 * f.1) / det;
+        pos.1 -= (jx.0 * f.1 - f.0 * jx.1) / det;
     }
+    let (a, b) = offset(pos.0, pos.1)?;
+    let converged = a.hypot(b) <= APPARENT_TOLERANCE;
+    let nearby = (pos.0 - estimate.0).hypot(pos.1 - estimate.1) <= 1.0;
+    (converged && nearby).then_some(pos)
+}
 
-    // Intersect the surface with a line in the w-axis from the
-    // point.
-    fn project_vertical(&self, point: Point4) -> Option<Point4> {
-        const VERTICAL: Dir4 = Dir4 {
-            x: 0.0,
-            y: 0.0,
-            z: 0.0,
-            w: 1.0,
+////////////////////////////////////////////////////////////////////////
+// Constant apparent pan: Lensing makes the view appear to speed up
+// and slow down under a linear pan. We measure how far the centre
+// ray's escape direction turns as the pan changes, and invert that to
+// choose pans that space the turning evenly over the frames.
+//
+
+// Number of pans sampled across the sweep.
+const APPARENT_PAN_SAMPLES: usize = 1024;
+
+impl Tracer {
+    // The pan for each frame of a sweep from `pans.0` to `pans.1`
+    // degrees, such that the centre ray's escape direction turns by
+    // the same angle between frames. Directions are compared ignoring
+    // w, as the env maps do. If it doesn't turn at all, the sweep is
+    // linear.
+    pub fn constant_apparent_pans(
+        &self,
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pans: (f64, f64),
+        frames: usize,
+        step_size: Option<f64>,
+    ) -> Vec<f64> {
+        let (start, end) = pans;
+        let sample_pan =
+            |idx: usize| start + (end - start) * idx as f64 / APPARENT_PAN_SAMPLES as f64;
+        // A single pixel canvas has its pixel on the centre ray.
+        let centre = CanvasConfig {
+            width: 1,
+            height: 1,
+            ..*conf
         };
-        // Plenty of iterations to converge, since the starting point
-        // may be far from the intersection.
-        self.intersect_line(point, VERTICAL, 10)
-    }
+        let dirs = (0..=APPARENT_PAN_SAMPLES)
+            .into_par_iter()
+            .map(|idx| {
+                let (origin, dir) = camera_ray(&centre, tilt, turn, sample_pan(idx), 0.0, 0.0);
+                let dir = self.trace_geometry(origin, dir, step_size).final_dir;
+                Dir4 { w: 0.0, ..dir }.norm()
+            })
+            .collect::<Vec<_>>();
 
-    // Calculate a normal vector using finite differences.
-    fn normal_at(&self, p: Point4) -> Dir4 {
-        let base_dist = self.dist(p);
-        Dir4 {
-            x: self.dist(Point4 {
-                x: p.x + EPSILON,
-                ..p
-            }) - base_dist,
-            y: self.dist(Point4 {
-                y: p.y + EPSILON,
-                ..p
-            }) - base_dist,
-            z: self.dist(Point4 {
-                z: p.z + EPSILON,
-                ..p
-            }) - base_dist,
-            w: self.dist(Point4 {
-                w: p.w + EPSILON,
-                ..p
-            }) - base_dist,
+        // Cumulative angle turned through at each sample.
+        let mut turned = vec![0.0];
+        for pair in dirs.windows(2) {
+            let angle = pair[0].dot(pair[1]).clamp(-1.0, 1.0).acos();
+            turned.push(turned.last().unwrap() + angle);
         }
+        let total = *turned.last().unwrap();
+
+        (0..frames)
+            .map(|frame| {
+                let t = if frames > 1 {
+                    frame as f64 / (frames - 1) as f64
+                } else {
+                    0.0
+                };
+                if total == 0.0 {
+                    return start + t * (end - start);
+                }
+                // Interpolate between the samples either side of the
+                // target angle.
+                let target = t * total;
+                let idx = turned
+                    .partition_point(|a| *a < target)
+                    .clamp(1, APPARENT_PAN_SAMPLES);
+                let (lo, hi) = (turned[idx - 1], turned[idx]);
+                let frac = if hi > lo {
+                    ((target - lo) / (hi - lo)).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                sample_pan(idx - 1) + frac * (sample_pan(idx) - sample_pan(idx - 1))
+            })
+            .collect()
     }
 }
 
 ////////////////////////////////////////////////////////////////////////
-// Adaptive tracer
+// Ring analysis: Measure the apparent size of the Einstein ring, the
+// edge of the region of rays that pass through the wormhole.
 //
 
-const TARGET_NORM_DIFF: f64 = 1.0e-4;
-const BASE_ADAPTIVE_STEP: f64 = 0.01;
-const MAX_ADAPTIVE_STEP: f64 = 0.1;
+// Bisection steps used to refine the ring edge between pixels.
+const RING_REFINE_ITERS: usize = 20;
 
 impl Tracer {
-    // Trace a single ray.
-    fn trace_adaptive(&self, p: Point4, dir: Dir4) -> Pixel {
-        // We'll adapt the step size, so that the optimal size from
-        // the previous step is used for the next one.
-        let mut step_size = BASE_ADAPTIVE_STEP;
-        let delta = dir.norm().scale(step_size);
-        let mut p = self.project_vertical(p).unwrap();
-        let mut norm = self.normal_at(p).norm();
-        let mut old_p = self.project_vertical(p.sub(delta)).unwrap();
+    // Distance in pixels from the centre of the image to the edge of
+    // the region seen through the wormhole, looking straight at it
+    // (no tilt, turn or pan). Returns None if the centre isn't seen
+    // through the wormhole, so there's no ring. A ring whose edge is
+    // off the image is reported at half the image's width, the most
+    // that can be measured.
+    pub fn ring_radius(&self, conf: &CanvasConfig, step_size: Option<f64>) -> Option<f64> {
+        let view = View::new(conf, 0.0, 0.0, 0.0);
+        let centre_x = (conf.width - 1) as f64 * 0.5;
+        let centre_y = (conf.height - 1) as f64 * 0.5;
+        let passes_through = |r: f64| {
+            let dir = view.dir(centre_x + r, centre_y);
+            !self
+                .trace_geometry(view.origin, dir, step_size)
+                .positive_w()
+        };
 
-        while p.len() < self.infinity {
-            let delta = p.sub(old_p).norm();
-            ((p, norm), old_p) = (self.step_adaptive(p, delta, norm, &mut step_size), p);
+        if !passes_through(0.0) {
+            return None;
         }
+        let Some(outside) = (1..conf.width / 2).find(|r| !passes_through(*r as f64)) else {
+            return Some((conf.width / 2) as f64);
+        };
 
-        let final_dir = p.sub(old_p);
-        if final_dir.w > 0.0 {
-            self.env_map_pos.colour(final_dir)
-        } else {
-            self.env_map_neg.colour(final_dir)
+        let (mut inside, mut outside) = ((outside - 1) as f64, outside as f64);
+        for _ in 0..RING_REFINE_ITERS {
+            let mid = 0.5 * (inside + outside);
+            if passes_through(mid) {
+                inside = mid;
+            } else {
+                outside = mid;
+            }
         }
+        Some(0.5 * (inside + outside))
     }
+}
 
-    // Take a step from p in direction delta, constrained to the
-    // surface in direction norm.
-    fn step_adaptive(
+////////////////////////////////////////////////////////////////////////
+// Env map UV renderer, showing where in the env maps each ray's colour
+// comes from, for debugging the cube map sampling. Texture
+// coordinates u and v are in the red and green channels, and the
+// face's index in `Face::ALL` is in blue, in steps of 51.
+//
+
+impl Tracer {
+    pub fn render_envmap_uv(
         &self,
-        p: Point4,
-        delta: Dir4,
-        norm: Dir4,
-        step_size: &mut f64,
-    ) -> (Point4, Dir4) {
-        let delta = delta.scale(*step_size);
-        let base = p.add(delta);
-        let (projection, new_p) = self.intersect_line_adaptive(base, norm);
+        conf: &CanvasConfig,
+        tilt: f64,
+        turn: f64,
+        pan: f64,
+        step_size: Option<f64>,
+    ) -> Vec<u8> {
+        let view = View::new(conf, tilt, turn, pan);
+        let to_u8 = |c: f64| (c * u8::MAX as f64).round().clamp(0.0, u8::MAX as f64) as u8;
+        (0..conf.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let view = &view;
+                (0..conf.width).flat_map(move |x| {
+                    let dir = view.dir(x as f64, y as f64);
+                    let geom = self.trace_geometry(view.origin, dir, step_size);
+                    if geom.clipped {
+                        return CLIPPED_COLOUR;
+                    }
+                    if geom.captured {
+                        return CAPTURED_COLOUR;
+                    }
+                    let env_map = self.env_map_for(geom.positive_w());
+                    let (face, u, v) = env_map.face_uv(geom.final_dir);
+                    let index = Face::ALL.iter().position(|f| *f == face).unwrap();
+                    [to_u8(u), to_u8(v), (index * 51) as u8, u8::MAX]
+                })
+            })
+            .collect()
+    }
+}
 
-        // Now, calculate the next step size.
-        let new_norm = self.normal_at(new_p).norm();
-        let other_p = base.add(new_norm.scale(projection));
-        let actual_norm_diff = new_p.sub(other_p).len() / *step_size;
-        *step_size = (*step_size * TARGET_NORM_DIFF / actual_norm_diff).min(MAX_ADAPTIVE_STEP);
+////////////////////////////////////////////////////////////////////////
+// Height map renderer, showing the w coordinate of the surface itself
+// rather than tracing rays across it.
+//
 
-        (new_p, new_norm)
-    }
+// Colour used where there is no surface above a grid point (i.e.
+// inside the wormhole's throat).
+const NO_SURFACE_COLOUR: Pixel = [255, 0, 0, 255];
 
-    fn intersect_line_adaptive(&self, point: Point4, direction: Dir4) -> (f64, Point4) {
-        // Newton-Raphson solver on dist(point + lambda direction)
-        const MAX_ITERS: usize = 3;
-        let mut lambda = 0.0;
-        for _ in 0..MAX_ITERS {
-            let guess = point.add(direction.scale(lambda));
-            let guess_val = self.dist(guess);
-            if guess_val.abs() < EPSILON {
-                return (lambda, guess);
-            }
+impl Tracer {
+    // Render the w coordinate of the surface over the x/y plane, with
+    // the canvas width covering -infinity..infinity. Heights are
+    // normalised so that the full grey range is used.
+    pub fn render_height_map(&self, conf: &CanvasConfig) -> Vec<u8> {
+        // Invariants: start + step * (size - 1)/2 = 0.
+        let x_range = self.infinity * 2.0;
+        let x_step = x_range / conf.width as f64;
+        let x_start = -0.5 * x_step * (conf.width - 1) as f64;
 
-            let guess2 = point.add(direction.scale(lambda + EPSILON));
-            let guess2_val = self.dist(guess2);
+        let y_range = x_range * conf.aspect * conf.height as f64 / conf.width as f64;
+        let y_step = y_range / conf.height as f64;
+        let y_start = -0.5 * y_step * (conf.height - 1) as f64;
 
-            let dguess_val = (guess2_val - guess_val) / EPSILON;
+        let heights = (0..conf.height)
+            .into_par_iter()
+            .flat_map_iter(|iy| {
+                let y = y_start + iy as f64 * y_step;
+                (0..conf.width).map(move |ix| {
+                    let x = x_start + ix as f64 * x_step;
+                    // Start above the surface, as the camera does.
+                    self.project_vertical(Point4 {
+                        x,
+                        y,
+                        z: 0.0,
+                        w: 1.0,
+                    })
+                    .map(|p| p.w)
+                })
+            })
+            .collect::<Vec<_>>();
 
-            lambda -= guess_val / dguess_val;
-        }
+        let (min, max) = heights
+            .iter()
+            .flatten()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &w| {
+                (min.min(w), max.max(w))
+            });
+        let scale = if max > min { 255.0 / (max - min) } else { 0.0 };
 
-        panic!("step_adaptive could not extend path");
+        heights
+            .iter()
+            .flat_map(|height| match height {
+                Some(w) => {
+                    let v = ((w - min) * scale) as u8;
+                    [v, v, v, 255]
+                }
+                None => NO_SURFACE_COLOUR,
+            })
+            .collect()
     }
 }
 
@@ -420,20 +4165,29 @@ pub struct RayStats {
     pub deriv_dir: Dir4,
     pub point: Dir4,
     pub len: f64,
+    // Total angle turned through along the path, in radians.
+    pub turning: f64,
+    // Set if the path is much longer than the straight line from the
+    // start to infinity, suggesting the ray is orbiting near the
+    // photon sphere.
+    pub near_capture: bool,
 }
 
+// How many times longer than the straight-line path a ray must be to
+// count as near capture.
+const NEAR_CAPTURE_FACTOR: f64 = 2.0;
+
 impl Tracer {
     // Render a whole scene by tracing all the rays in the canvas.
     pub fn render_ray_stats(&self, conf: &CanvasConfig, step_size: f64) -> Vec<RayStats> {
-        let fov_rad = conf.fov_degrees * std::f64::consts::PI / 180.0;
-        let fov = (fov_rad * 0.5).tan();
+        let (x_scale, y_scale) = conf.view_scale();
 
         // Invariants: start + step * (size - 1)/2 = 0.
-        let x_range = fov * 2.0;
+        let x_range = x_scale * 2.0;
         let x_step = -x_range / conf.width as f64;
         let x_start = -0.5 * x_step * (conf.width - 1) as f64;
 
-        let y_range = x_range * conf.aspect * conf.height as f64 / conf.width as f64;
+        let y_range = y_scale * 2.0;
         let y_step = -y_range / conf.height as f64;
         let y_start = -0.5 * y_step * (conf.height - 1) as f64;
 
@@ -466,37 +4220,77 @@ impl Tracer {
 
     // Trace a single ray, collecting stats.
     fn trace_ray_stats(&self, p: Point4, dir: Dir4, step_size: f64) -> RayStats {
-        let delta = dir.norm().scale(step_size);
-        let mut p = self.project_vertical(p).unwrap();
-        let mut old_p = self.project_vertical(p.sub(delta)).unwrap();
+        let (mut p, mut old_p, mut dir) = self.start_fixed(p, dir, step_size);
+
+        // Distance to infinity if the ray were to go in a straight
+        // line from the start.
+        let b = p.dot(dir);
+        let straight_len = -b + (b * b - p.dot(p) + self.infinity.powi(2)).sqrt();
 
         let mut len = 0.0;
+        let mut turning = 0.0;
+        let mut old_dir = dir;
         while p.len() < self.infinity {
-            let delta = p.sub(old_p).norm().scale(step_size);
-            let norm = self.normal_at(p).norm();
-
-            if let Some(new_p) = self.step(p, delta, norm) {
-                (p, old_p) = (new_p, p);
+            old_dir = dir;
+            if let Some((new_p, new_dir)) = self.advance(p, dir, step_size).0 {
+                (p, old_p, dir) = (new_p, p, new_dir);
             } else {
                 panic!("trace_aux could not extend path");
             }
 
             len += p.sub(old_p).len();
+            turning += dir.dot(old_dir).clamp(-1.0, 1.0).acos();
         }
 
-        let step_dir = p.sub(old_p);
+        // RK4 is accurate enough that the amount the last step
+        // overshoots the radius would dominate its error, so the last
+        // step is redone to end on the radius.
+        let (step_dir, point) = match self.integrator {
+            Integrator::Projection => (p.sub(old_p), self.clip_to_radius(p, old_p)),
+            Integrator::Rk4 => {
+                (p, dir) = self.clip_rk4_to_radius(old_p, old_dir, step_size);
+                (dir, p)
+            }
+        };
         let norm = self.normal_at(p).norm();
         let deriv_dir = step_dir.sub(norm.scale(step_dir.dot(norm)));
-        let point = self.clip_to_radius(p, old_p);
 
         RayStats {
             step_dir,
             deriv_dir,
             point,
             len,
+            turning,
+            near_capture: len > NEAR_CAPTURE_FACTOR * straight_len,
         }
     }
 
+    // As `clip_to_radius`, for RK4: find the partial step from p in
+    // direction dir that ends on the radius, returning the point and
+    // direction there. The radius is matched much more closely than
+    // EPSILON, which would limit the accuracy of the direction.
+    fn clip_rk4_to_radius(&self, p: Point4, dir: Dir4, step_size: f64) -> (Point4, Dir4) {
+        const TOLERANCE: f64 = 1.0e-12;
+        const MAX_ITERS: usize = 10;
+        let mut step = step_size;
+        let mut end = (p, dir);
+        for _ in 0..MAX_ITERS {
+            end = self
+                .step_rk4(p, dir, step)
+                .0
+                .expect("clip_rk4_to_radius could not extend path");
+            let (end_p, end_dir) = end;
+            let radius_diff = end_p.len() - self.infinity;
+            if radius_diff.abs() < TOLERANCE {
+                break;
+            }
+            // d radius / d step is the radial component of the
+            // direction.
+            step -= radius_diff * end_p.len() / end_p.dot(end_dir);
+        }
+        end
+    }
+
     // Excessively precise way to clip the line to end on the given
     // radius, so that the clipping doesn't distort the error
     // calculation.
@@ -505,7 +4299,7 @@ impl Tracer {
         let delta = p.sub(prev_p);
         let mut lambda = 0.0;
         loop {
-            let guess = prev_p.add(delta.scale(lambda));
+            let guess = prev_p.lerp(&p, lambda);
             let radius_diff = guess.dot(guess) - self.infinity.powi(2);
             if radius_diff.abs() < EPSILON {
                 return guess;
@@ -518,6 +4312,81 @@ impl Tracer {
     }
 }
 
+////////////////////////////////////////////////////////////////////////
+// Step size suggestion: Probe a few rays to pick a fixed step size for
+// a target accuracy. As in the convergence tests, errors are measured
+// against a trace with a much smaller step. Near the throat, the error
+// shrinks faster than the step size, so rather than extrapolating, we
+// try step sizes in turn.
+//
+
+// Rays probed across the width of the view. The height is in
+// proportion.
+const PROBE_RES: usize = 8;
+// Range of step sizes suggested, and the ratio between those tried.
+const MIN_SUGGESTED_STEP: f64 = 0.001;
+const MAX_SUGGESTED_STEP: f64 = 0.1;
+const SUGGESTED_STEP_RATIO: f64 = std::f64::consts::SQRT_2;
+// Step size of the reference trace, relative to the smallest suggested.
+const PROBE_REFERENCE_FRACTION: f64 = 0.5;
+
+impl Tracer {
+    // A fixed step size expected to keep the error in the final
+    // direction (normalised) of rays across the camera's view within
+    // `target_error`. Rays are probed as by `render_ray_stats`, and
+    // those near capture, whose error grows without bound, are
+    // ignored. The result is the largest step size tried for which it
+    // and all the smaller ones meet the target, or else the smallest.
+    pub fn suggest_step_size(
+        &self,
+        conf: &CanvasConfig,
+        camera: &CameraFrame,
+        target_error: f64,
+    ) -> f64 {
+        assert!(target_error > 0.0);
+        let probe_conf = CanvasConfig {
+            width: PROBE_RES,
+            height: (PROBE_RES * conf.height / conf.width).max(1),
+            aspect: conf.aspect,
+            fov_degrees: conf.fov_degrees,
+            fov_v_degrees: conf.fov_v_degrees,
+            antialias: Antialias::None,
+            filter: Filter::Box(1.0),
+            handedness: conf.handedness,
+            pixel_offset: (0.0, 0.0),
+            tone_map: ToneMap::None,
+            gamma: 1.0,
+        };
+        let view = View::from_frame(&probe_conf, camera);
+        let probe = |step_size| {
+            (0..probe_conf.height)
+                .flat_map(|y| (0..probe_conf.width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let dir = view.dir(x as f64, y as f64);
+                    self.trace_ray_stats(view.origin, dir, step_size)
+                })
+                .collect::<Vec<_>>()
+        };
+        let reference = probe(PROBE_REFERENCE_FRACTION * MIN_SUGGESTED_STEP);
+        let probe_error = |step_size| {
+            probe(step_size)
+                .iter()
+                .zip(reference.iter())
+                .filter(|(_, r)| !r.near_capture)
+                .map(|(s, r)| s.step_dir.norm().sub(r.step_dir.norm()).len())
+                .fold(0.0, f64::max)
+        };
+
+        let mut suggested = MIN_SUGGESTED_STEP;
+        let mut step_size = MIN_SUGGESTED_STEP;
+        while step_size <= MAX_SUGGESTED_STEP && probe_error(step_size) <= target_error {
+            suggested = step_size;
+            step_size *= SUGGESTED_STEP_RATIO;
+        }
+        suggested
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////
 // Renderer that returns step-level stats, for further understanding
 // convergence behaviour.
@@ -540,15 +4409,14 @@ const TRACE_STEP_MULT: usize = 10;
 impl Tracer {
     // Render a whole scene by tracing all the rays in the canvas.
     pub fn render_step_stats(&self, conf: &CanvasConfig, step_size: f64) -> Vec<StepStats> {
-        let fov_rad = conf.fov_degrees * std::f64::consts::PI / 180.0;
-        let fov = (fov_rad * 0.5).tan();
+        let (x_scale, y_scale) = conf.view_scale();
 
         // Invariants: start + step * (size - 1)/2 = 0.
-        let x_range = fov * 2.0;
+        let x_range = x_scale * 2.0;
         let x_step = -x_range / conf.width as f64;
         let x_start = -0.5 * x_step * (conf.width - 1) as f64;
 
-        let y_range = x_range * conf.aspect * conf.height as f64 / conf.width as f64;
+        let y_range = y_scale * 2.0;
         let y_step = -y_range / conf.height as f64;
         let y_start = -0.5 * y_step * (conf.height - 1) as f64;
 
@@ -583,21 +4451,19 @@ impl Tracer {
     fn trace_step_stats(&self, p: Point4, dir: Dir4, step_size: f64) -> Vec<StepStats> {
         let mut stats = Vec::new();
 
-        let delta = dir.norm().scale(step_size);
-        let mut p = self.project_vertical(p).unwrap();
-        let mut old_p = self.project_vertical(p.sub(delta)).unwrap();
+        let (mut p, mut old_p, mut dir) = self.start_fixed(p, dir, step_size);
         let mut old_norm = self.normal_at(old_p).scale(EPSILON.recip());
 
         let mut step_num = 0;
         while p.len() < self.infinity {
-            let delta = p.sub(old_p).norm().scale(step_size);
+            let delta = dir.scale(step_size);
             let norm = self.normal_at(p).scale(EPSILON.recip());
             let nnorm = norm.norm();
 
-            let (saved_p, saved_old_p) = (p, old_p);
+            let (saved_p, saved_dir) = (p, dir);
 
-            if let Some(new_p) = self.step(p, delta, nnorm) {
-                (p, old_p) = (new_p, p);
+            if let Some((new_p, new_dir)) = self.advance(p, dir, step_size).0 {
+                (p, old_p, dir) = (new_p, p, new_dir);
             } else {
                 panic!("trace_aux could not extend path");
             }
@@ -606,14 +4472,11 @@ impl Tracer {
 
             // And do a more accurate step to compare with.
             let alt_p = {
-                let (mut p, mut old_p) = (saved_p, saved_old_p);
+                let (mut p, mut dir) = (saved_p, saved_dir);
                 let step_size = step_size / TRACE_STEP_MULT as f64;
                 for _ in 0..TRACE_STEP_MULT {
-                    let delta = p.sub(old_p).norm().scale(step_size);
-                    let nnorm = self.normal_at(p).norm();
-
-                    if let Some(new_p) = self.step(p, delta, nnorm) {
-                        (p, old_p) = (new_p, p);
+                    if let Some((new_p, new_dir)) = self.advance(p, dir, step_size).0 {
+                        (p, dir) = (new_p, new_dir);
                     } else {
                         panic!("trace_step_stats could not extend path");
                     }
@@ -652,3 +4515,341 @@ impl Tracer {
         stats
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_conf(width: usize, height: usize) -> CanvasConfig {
+        CanvasConfig {
+            width,
+            height,
+            aspect: 1.0,
+            fov_degrees: 90.0,
+            fov_v_degrees: None,
+            antialias: Antialias::None,
+            filter: Filter::Box(1.0),
+            handedness: Handedness::Left,
+            pixel_offset: (0.0, 0.0),
+            tone_map: ToneMap::None,
+            gamma: 1.0,
+        }
+    }
+
+    // A cube map with a different gradient on each face, and the
+    // negative side's flipped, so that misplaced rays show.
+    fn gradient_env_map(flip: bool) -> Arc<EnvMap> {
+        let faces = std::array::from_fn(|face| {
+            image::RgbaImage::from_fn(8, 8, |x, y| {
+                let (x, y) = if flip { (7 - x, 7 - y) } else { (x, y) };
+                image::Rgba([(x * 32) as u8, (y * 32) as u8, (face * 40) as u8, 255])
+            })
+        });
+        Arc::new(EnvMap::from_images(faces).unwrap())
+    }
+
+    fn wormhole_tracer() -> Tracer {
+        Tracer::new(
+            gradient_env_map(false),
+            gradient_env_map(true),
+            0.25,
+            0.1,
+            10.0,
+        )
+    }
+
+    fn flat_tracer() -> Tracer {
+        Tracer {
+            dist_expr: Some(Expr::parse("w", &DIST_EXPR_VARS).unwrap()),
+            ..wormhole_tracer()
+        }
+    }
+
+    #[test]
+    fn render_buffer_averages_samples() {
+        let mut buffer = RenderBuffer::new(2, 1);
+        buffer.add_sample(0, 0, [0.2, 0.4, 0.6, 1.0]);
+        buffer.add_sample(0, 0, [0.4, 0.8, 0.0, 0.0]);
+        buffer.add_weighted_sample(1, 0, [1.0, 0.0, 0.0, 1.0], 3.0);
+        buffer.add_weighted_sample(1, 0, [0.0, 1.0, 0.0, 1.0], 1.0);
+
+        let close = |a: [f32; 4], b: [f32; 4]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1.0e-6);
+        assert!(close(buffer.get(0, 0), [0.3, 0.6, 0.3, 0.5]));
+        assert!(close(buffer.get(1, 0), [0.75, 0.25, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn render_buffer_empty_pixels_are_transparent() {
+        let buffer = RenderBuffer::new(1, 1);
+        assert_eq!(buffer.get(0, 0), [0.0; 4]);
+        assert_eq!(buffer.finalize(ToneMap::None, 1.0), vec![0; 4]);
+    }
+
+    #[test]
+    fn render_buffer_finalize_applies_tone_map_then_gamma() {
+        let mut buffer = RenderBuffer::new(1, 1);
+        buffer.add_sample(0, 0, [1.0, 3.0, 0.25, 0.5]);
+
+        // Plain finalize clamps.
+        assert_eq!(buffer.finalize(ToneMap::None, 1.0), vec![255, 255, 64, 128]);
+
+        // Reinhard gives c / (1 + c), then gamma raises to 1 / gamma.
+        // Alpha is left alone.
+        let expected = [1.0f32, 3.0, 0.25].map(|c| {
+            let c = (c / (1.0 + c)).powf(1.0 / 2.2);
+            (c * 255.0).round() as u8
+        });
+        assert_eq!(
+            buffer.finalize(ToneMap::Reinhard, 2.2),
+            vec![expected[0], expected[1], expected[2], 128]
+        );
+    }
+
+    #[test]
+    fn render_buffer_round_trips_pixels() {
+        let pixels = (0..4 * 6).map(|i| (i * 10) as u8).collect::<Vec<_>>();
+        let buffer = RenderBuffer::from_pixels(3, 2, &pixels);
+        assert_eq!(buffer.finalize(ToneMap::None, 1.0), pixels);
+    }
+
+    #[test]
+    fn local_position_puts_forwards_at_centre() {
+        let conf = test_conf(64, 48);
+        let camera = camera_frame(&conf, 0.0, 0.0, 0.0);
+        let z = Dir4 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+            w: 0.0,
+        };
+        assert!(camera.axes[2].sub(z).len() < 1.0e-12);
+
+        let tracer = flat_tracer();
+        let (x, y) = tracer.local_position(&conf, &camera, z).unwrap();
+        assert!((x - 31.5).abs() < 1.0e-6, "x = {x}");
+        assert!((y - 23.5).abs() < 1.0e-6, "y = {y}");
+
+        // Backwards doesn't appear.
+        assert!(tracer
+            .local_position(&conf, &camera, z.scale(-1.0))
+            .is_none());
+    }
+
+    #[test]
+    fn effective_w_scale_is_smooth_across_floor() {
+        // Sweep through the region where the floor blends in, on both
+        // sides of zero.
+        let steps = 1000;
+        let samples = (-steps..=steps)
+            .map(|i| {
+                let w_scale = 4.0 * MIN_W_SCALE * i as f64 / steps as f64;
+                (w_scale, effective_w_scale(w_scale))
+            })
+            .collect::<Vec<_>>();
+        let step = 4.0 * MIN_W_SCALE / steps as f64;
+        for pair in samples.windows(2) {
+            let ((w0, s0), (w1, s1)) = (pair[0], pair[1]);
+            // Continuous: the slope is at most 1.
+            assert!((s1 - s0).abs() <= step + 1.0e-12, "jump at {w0}..{w1}");
+            // Monotonic in the magnitude.
+            if w0 >= 0.0 {
+                assert!(s1 >= s0, "decreasing at {w0}..{w1}");
+            } else if w1 <= 0.0 {
+                assert!(s1 <= s0, "increasing at {w0}..{w1}");
+            }
+        }
+        assert_eq!(effective_w_scale(0.0), MIN_W_SCALE);
+        assert_eq!(effective_w_scale(2.0 * MIN_W_SCALE), 2.0 * MIN_W_SCALE);
+        assert_eq!(effective_w_scale(-0.25), 0.25);
+    }
+
+    #[test]
+    fn negative_w_scale_renders_same_image() {
+        let conf = test_conf(32, 24);
+        let tracer = wormhole_tracer();
+        let mirrored = Tracer {
+            w_scale: -tracer.w_scale,
+            ..tracer.clone()
+        };
+        for step_size in [None, Some(0.01)] {
+            let render = |tracer: &Tracer| tracer.render(&conf, 0.0, 20.0, 0.0, step_size, None);
+            assert_eq!(
+                render(&tracer),
+                render(&mirrored),
+                "step size {step_size:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn camera_side_counts_zero_w_as_positive() {
+        let tracer = wormhole_tracer();
+        let side = |w: f64| {
+            tracer.camera_side(Point4 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+                w,
+            })
+        };
+        assert_eq!(side(0.0), Some(true));
+        assert_eq!(side(-0.0), Some(true));
+        assert_eq!(side(0.5), Some(true));
+        assert_eq!(side(-0.5), Some(false));
+
+        // Inside the throat, there's no surface to project onto.
+        let inside = Point4 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            w: 0.0,
+        };
+        assert_eq!(tracer.camera_side(inside), None);
+    }
+
+    #[test]
+    fn render_scanlines_matches_render() {
+        // Tall enough for more than one batch, with tone mapping and
+        // gamma to apply.
+        let conf = CanvasConfig {
+            tone_map: ToneMap::Reinhard,
+            gamma: 2.2,
+            ..test_conf(16, SCANLINE_BATCH + 6)
+        };
+        let tracer = wormhole_tracer();
+        let mut rows = Vec::new();
+        tracer.render_scanlines(&conf, 0.0, 20.0, 0.0, None, |row| rows.push(row.to_vec()));
+
+        assert_eq!(rows.len(), conf.height);
+        assert!(rows.iter().all(|row| row.len() == conf.width * 4));
+        let expected = tracer
+            .render(&conf, 0.0, 20.0, 0.0, None, None)
+            .complete()
+            .unwrap();
+        assert_eq!(rows.concat(), expected);
+    }
+
+    #[test]
+    fn black_hole_captures_central_ray() {
+        let conf = test_conf(33, 33);
+        let (origin, dir) = camera_ray(&conf, 0.0, 0.0, 0.0, 16.0, 16.0);
+        let black_hole = Tracer {
+            black_hole: Some(0.1),
+            ..wormhole_tracer()
+        };
+        let wormhole = wormhole_tracer();
+        for step_size in [None, Some(0.01)] {
+            let geom = black_hole.trace_geometry(origin, dir, step_size);
+            assert!(geom.captured, "step size {step_size:?}");
+            assert!(geom.steps < MAX_TRACE_STEPS);
+
+            // Through a wormhole, the same ray comes out the other side.
+            let geom = wormhole.trace_geometry(origin, dir, step_size);
+            assert!(geom.escaped(), "step size {step_size:?}");
+            assert!(!geom.positive_w());
+        }
+    }
+
+    #[test]
+    fn quantize_scrubs_non_finite_samples() {
+        let mut buffer = RenderBuffer::new(3, 1);
+        buffer.set(0, 0, [f32::NAN, 0.5, 1.0, 1.0]);
+        buffer.set(1, 0, [f32::INFINITY, f32::NEG_INFINITY, 0.25, f32::NAN]);
+        buffer.set(2, 0, [1.0, 0.0, 0.5, 1.0]);
+
+        for tone_map in [ToneMap::None, ToneMap::Reinhard, ToneMap::Aces] {
+            let (channels, scrubbed) =
+                buffer.quantize_counted(tone_map, 2.2, u8::MAX as f32, Dither::None);
+            assert_eq!(scrubbed, 2);
+            assert!(channels.iter().all(|c| (0.0..=255.0).contains(c)));
+            // Scrubbed channels are treated as zero.
+            assert_eq!(
+                [channels[0], channels[4], channels[5], channels[7]],
+                [0.0; 4]
+            );
+        }
+
+        assert_eq!(
+            buffer.finalize(ToneMap::None, 1.0),
+            vec![0, 128, 255, 255, 0, 0, 64, 0, 255, 0, 128, 255]
+        );
+        let wide = buffer.finalize_u16(ToneMap::None, 1.0);
+        assert_eq!(wide[..8], [0, 32768, 65535, 65535, 0, 0, 16384, 0]);
+    }
+
+    #[test]
+    fn integer_pixel_offset_shifts_flat_image() {
+        let conf = test_conf(32, 24);
+        let tracer = flat_tracer();
+        let render = |pixel_offset| {
+            let conf = CanvasConfig {
+                pixel_offset,
+                ..conf
+            };
+            tracer
+                .render(&conf, 10.0, 30.0, 0.0, None, None)
+                .complete()
+                .unwrap()
+        };
+        let base = render((0.0, 0.0));
+        let pixel = |pixels: &[u8], x: usize, y: usize| {
+            let idx = (y * conf.width + x) * 4;
+            pixels[idx..idx + 4].to_vec()
+        };
+        // Make sure there's something to shift.
+        assert!(base.chunks(4).any(|p| p != &base[..4]));
+
+        for (dx, dy) in [(3, 0), (0, -2), (2, 1)] {
+            let shifted = render((dx as f64, dy as f64));
+            for y in 0..conf.height {
+                for x in 0..conf.width {
+                    let (bx, by) = (x as i64 + dx, y as i64 + dy);
+                    if (0..conf.width as i64).contains(&bx) && (0..conf.height as i64).contains(&by)
+                    {
+                        assert_eq!(
+                            pixel(&shifted, x, y),
+                            pixel(&base, bx as usize, by as usize),
+                            "offset ({dx}, {dy}) at ({x}, {y})"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Poll a future to completion on this thread, returning its output
+    // and the number of times it yielded.
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        let mut yields = 0;
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return (output, yields),
+                Poll::Pending => yields += 1,
+            }
+        }
+    }
+
+    #[test]
+    fn render_async_matches_render() {
+        let tracer = wormhole_tracer();
+        let conf = CanvasConfig {
+            tone_map: ToneMap::Aces,
+            gamma: 2.2,
+            ..test_conf(16, SCANLINE_BATCH + 6)
+        };
+        for antialias in [Antialias::None, Antialias::Supersample(2)] {
+            let conf = CanvasConfig { antialias, ..conf };
+            let (pixels, yields) = block_on(tracer.render_async(&conf, 0.0, 20.0, 0.0, None));
+            let expected = tracer
+                .render(&conf, 0.0, 20.0, 0.0, None, None)
+                .complete()
+                .unwrap();
+            assert_eq!(pixels, expected, "{antialias:?}");
+            // Unless anti-aliased, it yields after each batch of rows.
+            let batches = if antialias == Antialias::None { 2 } else { 0 };
+            assert_eq!(yields, batches, "{antialias:?}");
+        }
+    }
+}
@@ -3,7 +3,9 @@
 // which our 3D space is embedded.
 //
 
-#[derive(Clone, Copy, Debug)]
+use std::ops::{Add, Mul, Neg, Sub};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Vec4 {
     pub x: f64,
     pub y: f64,
@@ -21,15 +23,127 @@ pub type Dir4 = Vec4;
 
 impl Vec4 {
     pub fn scale(&self, m: f64) -> Vec4 {
+        *self * m
+    }
+
+    pub fn add(&self, rhs: Vec4) -> Vec4 {
+        *self + rhs
+    }
+
+    pub fn sub(&self, rhs: Vec4) -> Vec4 {
+        *self - rhs
+    }
+
+    pub fn dot(&self, rhs: Vec4) -> f64 {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z + self.w * rhs.w
+    }
+
+    pub fn len(&self) -> f64 {
+        (self.x.powi(2) + self.y.powi(2) + self.z.powi(2) + self.w.powi(2)).sqrt()
+    }
+
+    // Mirror self in the hyperplane with the given normal. The normal
+    // is trusted to be unit length, and isn't normalised here.
+    pub fn reflect(&self, normal: &Dir4) -> Dir4 {
+        self - normal * (2.0 * self.dot(*normal))
+    }
+
+    // Whether each component is within epsilon of the other's. The
+    // tolerance is absolute, so it stays the same for points far out
+    // near the tracer's infinity, and equal infinite components
+    // compare equal rather than differing by NaN.
+    pub fn approx_eq(&self, other: &Vec4, epsilon: f64) -> bool {
+        let close = |a: f64, b: f64| a == b || (a - b).abs() <= epsilon;
+        close(self.x, other.x)
+            && close(self.y, other.y)
+            && close(self.z, other.z)
+            && close(self.w, other.w)
+    }
+
+    pub fn distance(&self, other: &Vec4) -> f64 {
+        (self - other).len()
+    }
+
+    // Linear interpolation, giving exactly self at t = 0 and other at
+    // t = 1.
+    pub fn lerp(&self, other: &Vec4, t: f64) -> Vec4 {
+        self * (1.0 - t) + other * t
+    }
+
+    pub fn norm(&self) -> Vec4 {
+        self.scale(self.len().recip())
+    }
+
+    pub fn abs(&self) -> Vec4 {
         Vec4 {
-            x: self.x * m,
-            y: self.y * m,
-            z: self.z * m,
-            w: self.w * m,
+            x: self.x.abs(),
+            y: self.y.abs(),
+            z: self.z.abs(),
+            w: self.w.abs(),
         }
     }
 
-    pub fn add(&self, rhs: Vec4) -> Vec4 {
+    pub fn component_min(&self, rhs: Vec4) -> Vec4 {
+        Vec4 {
+            x: self.x.min(rhs.x),
+            y: self.y.min(rhs.y),
+            z: self.z.min(rhs.z),
+            w: self.w.min(rhs.w),
+        }
+    }
+
+    pub fn component_max(&self, rhs: Vec4) -> Vec4 {
+        Vec4 {
+            x: self.x.max(rhs.x),
+            y: self.y.max(rhs.y),
+            z: self.z.max(rhs.z),
+            w: self.w.max(rhs.w),
+        }
+    }
+
+    // Clamp each component into the range given by the corresponding
+    // components of lo and hi.
+    pub fn clamp(&self, lo: Vec4, hi: Vec4) -> Vec4 {
+        self.component_max(lo).component_min(hi)
+    }
+
+    // The generalised cross product: a vector orthogonal to self, b
+    // and c, whose length is the volume of the parallelepiped they
+    // span. Found by expanding the determinant with rows self, b, c
+    // and the axis vectors along its last row, so that
+    // x.cross(y, z) is w. Zero if the vectors are linearly dependent.
+    pub fn cross(&self, b: Vec4, c: Vec4) -> Vec4 {
+        let a = self;
+        let det3 = |u: [f64; 3], v: [f64; 3], t: [f64; 3]| {
+            u[0] * (v[1] * t[2] - v[2] * t[1]) - u[1] * (v[0] * t[2] - v[2] * t[0])
+                + u[2] * (v[0] * t[1] - v[1] * t[0])
+        };
+        // Minor with the given component's column removed.
+        let minor = |f: fn(&Vec4) -> [f64; 3]| det3(f(a), f(&b), f(&c));
+        Vec4 {
+            x: -minor(|v| [v.y, v.z, v.w]),
+            y: minor(|v| [v.x, v.z, v.w]),
+            z: -minor(|v| [v.x, v.y, v.w]),
+            w: minor(|v| [v.x, v.y, v.z]),
+        }
+    }
+
+    // As `cross`, normalised to unit length.
+    pub fn cross_norm(&self, b: Vec4, c: Vec4) -> Vec4 {
+        self.cross(b, c).norm()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Operators: The arithmetic behind the named methods above. Binary
+// operators are implemented for each combination of owned and
+// borrowed operands, so that call sites don't need to copy.
+//
+
+impl Add for Vec4 {
+    type Output = Vec4;
+
+    fn add(self, rhs: Vec4) -> Vec4 {
         Vec4 {
             x: self.x + rhs.x,
             y: self.y + rhs.y,
@@ -37,8 +151,12 @@ impl Vec4 {
             w: self.w + rhs.w,
         }
     }
+}
 
-    pub fn sub(&self, rhs: Vec4) -> Vec4 {
+impl Sub for Vec4 {
+    type Output = Vec4;
+
+    fn sub(self, rhs: Vec4) -> Vec4 {
         Vec4 {
             x: self.x - rhs.x,
             y: self.y - rhs.y,
@@ -46,16 +164,79 @@ impl Vec4 {
             w: self.w - rhs.w,
         }
     }
+}
 
-    pub fn dot(&self, rhs: Vec4) -> f64 {
-        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z + self.w * rhs.w
+impl Neg for Vec4 {
+    type Output = Vec4;
+
+    fn neg(self) -> Vec4 {
+        Vec4 {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+            w: -self.w,
+        }
     }
+}
 
-    pub fn len(&self) -> f64 {
-        (self.x.powi(2) + self.y.powi(2) + self.z.powi(2) + self.w.powi(2)).sqrt()
+impl Neg for &Vec4 {
+    type Output = Vec4;
+
+    fn neg(self) -> Vec4 {
+        -*self
     }
+}
 
-    pub fn norm(&self) -> Vec4 {
-        self.scale(self.len().recip())
+impl Mul<f64> for Vec4 {
+    type Output = Vec4;
+
+    fn mul(self, m: f64) -> Vec4 {
+        Vec4 {
+            x: self.x * m,
+            y: self.y * m,
+            z: self.z * m,
+            w: self.w * m,
+        }
+    }
+}
+
+impl Mul<f64> for &Vec4 {
+    type Output = Vec4;
+
+    fn mul(self, m: f64) -> Vec4 {
+        *self * m
     }
 }
+
+// Implement a binary operator for the borrowed combinations of
+// operands, in terms of the owned one.
+macro_rules! forward_ref_binop {
+    ($imp:ident, $method:ident) => {
+        impl $imp<&Vec4> for Vec4 {
+            type Output = Vec4;
+
+            fn $method(self, rhs: &Vec4) -> Vec4 {
+                $imp::$method(self, *rhs)
+            }
+        }
+
+        impl $imp<Vec4> for &Vec4 {
+            type Output = Vec4;
+
+            fn $method(self, rhs: Vec4) -> Vec4 {
+                $imp::$method(*self, rhs)
+            }
+        }
+
+        impl $imp<&Vec4> for &Vec4 {
+            type Output = Vec4;
+
+            fn $method(self, rhs: &Vec4) -> Vec4 {
+                $imp::$method(*self, *rhs)
+            }
+        }
+    };
+}
+
+forward_ref_binop!(Add, add);
+forward_ref_binop!(Sub, sub);